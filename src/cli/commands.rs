@@ -1,274 +1,1207 @@
-use std::{net::Ipv4Addr, sync::mpsc};
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    path::PathBuf,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
 
-use clap::Args;
+use bytes::Bytes;
+use chrono::Utc;
+use clap::{Args, Subcommand};
 use log::{debug, error, info, trace, warn};
+use tokio::{
+    sync::{mpsc, watch},
+    time::Instant,
+};
 
+use super::{
+    monitor::{Health, LoopControl, MonitorLoop, MonitorLoopMessage, UpdateContext, Updater},
+    plan::{apply_plan, plan_zone, Plan},
+    zone::{
+        account_client, build_available_zones, build_zones, import, managed_records, retry_lookups,
+        rollback, update_ip, Zone, ZoneError,
+    },
+    IpVersionArgument,
+};
 use crate::{
-    cloudflare::{
-        client::CloudFlareClient,
-        models::{CloudFlareClientError, UpdateDNSRecordRequest},
+    cloudflare::{bind, models::CreateDNSRecordRequest},
+    config::{Config, MqttConfig},
+    ip::{Detector, IpFamily},
+    journal::Journal,
+    mqtt::{
+        command::{Command, CommandResultMessage},
+        MqttClient, MqttError, UpdateResult,
     },
-    mqtt::{IpChangeMessage, MqttClient},
+    retry::{CircuitBreaker, RetryPolicy},
+    state::{StateError, StateFile},
 };
 
 #[derive(Debug, Args)]
-pub struct CurrentArguments {}
+pub struct CurrentArguments {
+    #[arg(long, value_enum, help = "IP versions to detect")]
+    ip_version: Option<IpVersionArgument>,
 
-pub async fn current_command(_args: &CurrentArguments) -> i32 {
-    match public_ip::addr_v4().await {
-        Some(ip) => {
-            info!("{}", ip);
-            0
+    #[arg(long, help = "Query every source and print their results")]
+    verbose: bool,
+}
+
+pub async fn current_command(args: &CurrentArguments, mut config: Config) -> i32 {
+    if let Some(ref ip_version) = args.ip_version {
+        config.detection.ip_versions = ip_version.families();
+    }
+
+    if let Err(e) = config.detection.validate("detection") {
+        error!("{}", e);
+        return 1;
+    }
+
+    let detector = match Detector::from_config(&config.detection.sources, config.detection.quorum) {
+        Ok(detector) => detector,
+        Err(e) => {
+            error!("{}", e);
+            return 1;
         }
-        None => {
-            error!("Could not get public IP");
-            1
+    };
+
+    let mut found = false;
+
+    for family in config.detection.ip_versions {
+        let ip = if args.verbose {
+            let results = detector.detect_all(family).await;
+
+            let mut text = format!("{} sources:", family);
+            for result in &results {
+                match &result.result {
+                    Ok(ip) => text.push_str(&format!("\n{:<40} {}", result.source, ip)),
+                    Err(e) => text.push_str(&format!("\n{:<40} error: {}", result.source, e)),
+                }
+            }
+            info!("{}", text);
+
+            detector.decide(family, &results)
+        } else {
+            detector.detect(family).await
+        };
+
+        match ip {
+            Some(ip) => {
+                info!("{}: {}", family, ip);
+                found = true;
+            }
+            None => warn!("Could not get public {}", family),
         }
     }
+
+    if !found {
+        error!("Could not get public IP");
+        return 1;
+    }
+
+    0
 }
 
 #[derive(Debug, Args)]
-pub struct InfoArguments {}
+pub struct InfoArguments {
+    #[arg(long, value_enum, help = "IP versions to detect")]
+    ip_version: Option<IpVersionArgument>,
+}
 
-pub async fn info_command(_args: &InfoArguments) -> i32 {
-    let cloudflare_token = std::env::var("CLOUDFLARE_TOKEN")
-        .expect("Environment variable CLOUDFLARE_TOKEN is not set");
-    let cloudflare_zone_id = std::env::var("CLOUDFLARE_ZONE_ID")
-        .expect("Environment variable CLOUDFLARE_ZONE_ID is not set");
+pub async fn info_command(args: &InfoArguments, mut config: Config) -> i32 {
+    if let Some(ref ip_version) = args.ip_version {
+        config.detection.ip_versions = ip_version.families();
+    }
 
-    let cloudflare_client = CloudFlareClient::new(&cloudflare_token, &cloudflare_zone_id);
+    if let Err(e) = config.validate() {
+        error!("{}", e);
+        return 1;
+    }
 
-    let current_ip = public_ip::addr_v4().await.expect("Could not get public IP");
-    info!("Current IP: {}", current_ip);
+    let state = match open_state(&config) {
+        Ok(state) => state,
+        Err(e) => {
+            error!("{}", e);
+            return 1;
+        }
+    };
 
-    let records = match cloudflare_client
-        .get_dns_records_with_content(&current_ip.to_string())
-        .await
-    {
-        Ok(res) => res.result,
+    let zones = match build_zones(&config, &state).await {
+        Ok(zones) => zones,
+        Err(e) => {
+            error!("{}", e);
+            return 1;
+        }
+    };
+
+    let detector = match Detector::from_config(&config.detection.sources, config.detection.quorum) {
+        Ok(detector) => detector,
         Err(e) => {
-            error!("Failed to get dns records: {:?}", e);
+            error!("{}", e);
             return 1;
         }
     };
 
-    if records.len() == 0 {
-        warn!(
-            "No DNS record is using the current public IP {}",
-            current_ip
+    let mut found = false;
+
+    for family in config.detection.ip_versions {
+        let current_ip = match detector.detect(family).await {
+            Some(ip) => ip,
+            None => {
+                warn!("Could not get public {}", family);
+                continue;
+            }
+        };
+        found = true;
+        info!("Current {}: {}", family, current_ip);
+
+        for zone in &zones {
+            let records = match managed_records(zone, family, current_ip).await {
+                Ok(records) => records,
+                Err(e) => {
+                    error!("Failed to get dns records: {}", e);
+                    return 1;
+                }
+            };
+
+            if records.is_empty() {
+                if zone.selectors.is_empty() {
+                    warn!(
+                        "No DNS record is using the current public {} {}",
+                        family, current_ip
+                    );
+                } else {
+                    warn!("No {} record matches the configured records", family);
+                }
+                continue;
+            }
+
+            let mut text = String::from("Affected records:");
+
+            for record in records {
+                text.push_str(&format!(
+                    "\n{:<6} {} {}",
+                    record.r#type, record.name, record.content
+                ));
+                if record.content != current_ip.to_string() {
+                    text.push_str(" (outdated)");
+                }
+            }
+
+            info!("{}", text);
+        }
+    }
+
+    if let Some(zone) = zones.first() {
+        info!(
+            "Cloudflare API budget: {} requests left",
+            zone.client.remaining_budget()
         );
-        return 0;
     }
 
-    let mut text = String::from("Affected records:");
+    if !found {
+        error!("Could not get public IP");
+        return 1;
+    }
+
+    0
+}
+
+#[derive(Debug, Args)]
+pub struct MonitorArguments {
+    #[arg(long, help = "Delay between IP checks in seconds [default: 300]")]
+    check_delay: Option<u64>,
+
+    #[arg(long, value_enum, help = "IP versions to monitor")]
+    ip_version: Option<IpVersionArgument>,
+}
 
-    for record in records {
-        text.push_str(&format!("\n{:<6} {}", record.r#type, record.name));
+pub async fn monitor_command(args: &MonitorArguments, mut config: Config) -> i32 {
+    if let Some(check_delay) = args.check_delay {
+        config.monitor.check_delay = check_delay;
+    }
+    if let Some(ref ip_version) = args.ip_version {
+        config.detection.ip_versions = ip_version.families();
     }
 
-    info!("{}", text);
+    if let Err(e) = config.validate() {
+        error!("{}", e);
+        return 1;
+    }
+
+    let mqtt_client = match build_mqtt_client(&config.mqtt).await {
+        Ok(mqtt_client) => mqtt_client,
+        Err(e) => {
+            error!("{}", e);
+            return 1;
+        }
+    };
+
+    let detector = match Detector::from_config(&config.detection.sources, config.detection.quorum) {
+        Ok(detector) => detector,
+        Err(e) => {
+            error!("{}", e);
+            return 1;
+        }
+    };
+
+    let state = match open_state(&config) {
+        Ok(state) => state,
+        Err(e) => {
+            error!("{}", e);
+            return 1;
+        }
+    };
+
+    // a zone that could not be looked up must not stop managing the others
+    let (zones, failed_zones) = match build_available_zones(&config, &state).await {
+        Ok((zones, _)) if zones.is_empty() => {
+            error!("No zone could be looked up");
+            return 1;
+        }
+        Ok(built) => built,
+        Err(e) => {
+            error!("{}", e);
+            return 1;
+        }
+    };
+
+    let control = Arc::new(LoopControl::default());
+
+    let mut commands = None;
+    let mut pushed_ips = Vec::new();
+    if let Some(ref mqtt_client) = mqtt_client {
+        match mqtt_client.announce(&config.detection.ip_versions) {
+            Ok(_) => debug!("MQTT message queued"),
+            Err(e) => warn!("Dropped MQTT message: {}", e),
+        }
+
+        match mqtt_client.subscribe_commands().await {
+            Ok(receiver) => commands = Some(receiver),
+            Err(e) => error!("Failed to subscribe to the MQTT commands: {}", e),
+        }
+
+        // check as soon as a source receives an IP
+        for source in detector.mqtt_sources() {
+            let mut receiver = match mqtt_client.subscribe(source.topic()).await {
+                Ok(receiver) => receiver,
+                Err(e) => {
+                    error!("Failed to subscribe to {}: {}", source.topic(), e);
+                    continue;
+                }
+            };
+
+            let source = source.clone();
+            let control = control.clone();
+            pushed_ips.push(tokio::spawn(async move {
+                while let Some(payload) = receiver.recv().await {
+                    match source.receive(&payload) {
+                        Ok(ip) => {
+                            debug!("Received {} on {}", ip, source.topic());
+                            control.check_now();
+                        }
+                        Err(e) => warn!("Ignoring message on {}: {}", source.topic(), e),
+                    }
+                }
+            }));
+        }
+    }
+
+    let mut updater = Updater::new(UpdateContext {
+        zones: RwLock::new(zones.into_iter().map(Arc::new).collect()),
+        mqtt_client,
+        retry: RetryPolicy::from(&config.retry),
+        circuit_breaker: CircuitBreaker::from(&config.retry.circuit_breaker),
+        state,
+        drift_mode: config.reconcile.mode,
+        health: Health::new(),
+    });
+
+    let mut missing_zones = Vec::new();
+    for e in failed_zones {
+        let message = format!("{}, looking it up again later", e);
+        error!("{}", message);
+        updater.health().failed(message);
+        missing_zones.push(String::from(e.zone()));
+    }
+
+    // the zones found later are managed from then on
+    let (found_tx, mut found_zones) = mpsc::channel(1);
+    let lookups = tokio::spawn(retry_lookups(
+        config.clone(),
+        account_client(&config),
+        missing_zones,
+        RetryPolicy::from(&config.retry),
+        found_tx,
+    ));
+
+    let mut current_ips = HashMap::new();
+
+    // repair what changed while not running before watching for changes
+    let mut start_ips = Vec::new();
+    for family in config.detection.ip_versions.iter().copied() {
+        let start_ip = detector.detect(family).await;
+
+        match start_ip {
+            Some(ip) => {
+                info!("Current {} is {}", family, ip);
+                updater.reconcile(ip);
+                current_ips.insert(family, ip);
+            }
+            None => {
+                updater
+                    .health()
+                    .failed(format!("Could not get public {}", family));
+                warn!("Could not get public {} address", family)
+            }
+        }
+
+        start_ips.push((family, start_ip));
+    }
+    updater.health().checked();
+
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+
+    let monitor_loop = MonitorLoop::new(Duration::from_secs(config.monitor.check_delay), start_ips);
+    let (mut messages, detection) = monitor_loop.start(detector, control.clone(), shutdown_rx);
+
+    let drift_interval = Duration::from_secs(config.reconcile.interval.max(1));
+    let mut drift_check = tokio::time::interval_at(Instant::now() + drift_interval, drift_interval);
+
+    let heartbeat_interval = Duration::from_secs(config.mqtt.heartbeat_interval.max(1));
+    let mut heartbeat = tokio::time::interval(heartbeat_interval);
+    let heartbeat_enabled = config.mqtt.enabled && config.mqtt.heartbeat_interval > 0;
+
+    let shutdown = shutdown_signal();
+    tokio::pin!(shutdown);
+
+    loop {
+        tokio::select! {
+            message = messages.recv() => match message {
+                Some(MonitorLoopMessage::IpChanged { old_ip, new_ip }) => {
+                    updater.health().checked();
+                    // the records use the IP set by a command, if any
+                    let previous = current_ips.insert(IpFamily::of(&new_ip), new_ip);
+                    updater.ip_changed(previous.unwrap_or(old_ip), new_ip)
+                }
+                Some(MonitorLoopMessage::IpFound(ip)) => {
+                    updater.health().checked();
+                    current_ips.insert(IpFamily::of(&ip), ip);
+                    updater.reconcile(ip)
+                }
+                Some(MonitorLoopMessage::CouldNotGetIp(family)) => {
+                    updater.health().checked();
+                    updater.health().failed(format!("Could not get public {}", family));
+                    warn!("Could not get public {}", family)
+                }
+                Some(MonitorLoopMessage::NoChange(family)) => {
+                    updater.health().checked();
+                    trace!("No {} change", family)
+                }
+                None => break,
+            },
+            Some(zone) = found_zones.recv() => {
+                updater.add_zone(zone);
+                if !control.is_paused() {
+                    for ip in current_ips.values() {
+                        updater.reconcile(*ip);
+                    }
+                }
+            }
+            _ = heartbeat.tick(), if heartbeat_enabled => updater.publish_heartbeat(),
+            Some(payload) = next_command(&mut commands) => {
+                let command = String::from_utf8_lossy(&payload).trim().to_string();
+                info!("Received command {}", command);
+
+                let outcome = match command.parse::<Command>() {
+                    Ok(parsed) => run_command(
+                        parsed,
+                        &mut updater,
+                        &control,
+                        &mut current_ips,
+                        &config.detection.ip_versions,
+                    ),
+                    Err(e) => Err(e.to_string()),
+                };
+                let (result, message) = match outcome {
+                    Ok(message) => (UpdateResult::Success, message),
+                    Err(message) => {
+                        warn!("Command {} failed: {}", command, message);
+                        (UpdateResult::Failure, message)
+                    }
+                };
+
+                updater
+                    .publish_command_result(CommandResultMessage {
+                        command,
+                        result,
+                        message,
+                        received_on: Utc::now(),
+                    })
+            }
+            _ = drift_check.tick(), if config.reconcile.enabled && !control.is_paused() => {
+                for ip in current_ips.values() {
+                    updater.check_drift(*ip);
+                }
+            }
+            _ = &mut shutdown => {
+                info!("Shutting down");
+                break;
+            }
+        }
+    }
+
+    let _ = shutdown_tx.send(true);
+    let _ = detection.await;
+    for handle in pushed_ips {
+        handle.abort();
+    }
+    lookups.abort();
+    updater.shutdown().await;
 
     0
 }
 
+/// Exit codes of the update command, for scripts
+const EXIT_INVALID_CONFIG: i32 = 1;
+const EXIT_NO_IP: i32 = 2;
+const EXIT_UPDATE_FAILED: i32 = 3;
+
 #[derive(Debug, Args)]
-pub struct MonitorArguments {
+pub struct UpdateArguments {
+    #[arg(long, value_enum, help = "IP versions to update")]
+    ip_version: Option<IpVersionArgument>,
+
     #[arg(
         long,
-        default_value_t = 300,
-        help = "Delay between IP checks in seconds"
+        help = "Use this IP instead of detecting it, such as the local IP given to a PPP ip-up hook"
     )]
-    check_delay: u64,
+    ip: Option<IpAddr>,
 }
 
-pub async fn monitor_command(args: &MonitorArguments) -> i32 {
-    let mqtt_client = build_mqtt_client().await;
+pub async fn update_command(args: &UpdateArguments, mut config: Config) -> i32 {
+    if let Some(ref ip_version) = args.ip_version {
+        config.detection.ip_versions = ip_version.families();
+    }
+    if let Some(ip) = args.ip {
+        config.detection.ip_versions = vec![IpFamily::of(&ip)];
+    }
+
+    if let Err(e) = config.validate() {
+        error!("{}", e);
+        return EXIT_INVALID_CONFIG;
+    }
+
+    let detector = match Detector::from_config(&config.detection.sources, config.detection.quorum) {
+        Ok(detector) => detector,
+        Err(e) => {
+            error!("{}", e);
+            return EXIT_INVALID_CONFIG;
+        }
+    };
+
+    let state = match open_state(&config) {
+        Ok(state) => state,
+        Err(e) => {
+            error!("{}", e);
+            return EXIT_INVALID_CONFIG;
+        }
+    };
+
+    let zones = match build_zones(&config, &state).await {
+        Ok(zones) => zones,
+        Err(e @ ZoneError::NotFound(_)) => {
+            error!("{}", e);
+            return EXIT_INVALID_CONFIG;
+        }
+        Err(e) => {
+            error!("{}", e);
+            return EXIT_UPDATE_FAILED;
+        }
+    };
 
-    let cloudflare_client = build_cloudflare_client();
+    let mut found = false;
+    let mut failed = false;
 
-    let monitor_loop = MonitorLoop::new(std::time::Duration::from_secs(args.check_delay));
+    for family in config.detection.ip_versions {
+        let current_ip = match args.ip {
+            Some(ip) => ip,
+            None => match detector.detect(family).await {
+                Some(ip) => ip,
+                None => {
+                    warn!("Could not get public {}", family);
+                    continue;
+                }
+            },
+        };
+        found = true;
+        info!("Current {}: {}", family, current_ip);
+
+        for zone in &zones {
+            // the records still using the IP applied last time are the ones to update
+            let last_applied = state.last_applied(&zone.id, &zone.selectors, family);
+            let stale_ips: Vec<IpAddr> = last_applied
+                .into_iter()
+                .filter(|ip| *ip != current_ip)
+                .collect();
 
-    monitor_loop.start();
+            // a first run can only update selected records
+            if last_applied.is_none() && zone.selectors.is_empty() {
+                warn!(
+                    "No previous {} known for zone {}, nothing to update until it changes, set records to select the records to update",
+                    family, zone.id
+                );
+            }
 
-    for message in monitor_loop.listen() {
-        match message {
-            MonitorLoopMessage::IpChanged { old_ip, new_ip } => {
-                handle_update_ip_message(old_ip, new_ip, &mqtt_client, &cloudflare_client).await
+            match update_ip(zone, &stale_ips, current_ip).await {
+                Ok(_) => state.record_applied(&zone.id, &zone.selectors, current_ip),
+                Err(e) => {
+                    error!("Failed to update IP: {}", e);
+                    failed = true;
+                }
             }
-            MonitorLoopMessage::CouldNotGetIp => warn!("Could not get public IP"),
-            MonitorLoopMessage::NoChange => trace!("No IP change"),
         }
     }
 
+    if !found {
+        error!("Could not get public IP");
+        return EXIT_NO_IP;
+    }
+
+    if failed {
+        return EXIT_UPDATE_FAILED;
+    }
+
     0
 }
 
-fn build_cloudflare_client() -> CloudFlareClient {
-    trace!("Building CloudFlareClient");
-    let cloudflare_token = std::env::var("CLOUDFLARE_TOKEN")
-        .expect("Environment variable CLOUDFLARE_TOKEN is not set");
-    let cloudflare_zone_id = std::env::var("CLOUDFLARE_ZONE_ID")
-        .expect("Environment variable CLOUDFLARE_ZONE_ID is not set");
+#[derive(Debug, Args)]
+pub struct PlanArguments {
+    #[arg(long, value_enum, help = "IP versions to plan for")]
+    ip_version: Option<IpVersionArgument>,
+
+    #[arg(long, help = "Print the plan as JSON instead of a table")]
+    json: bool,
 
-    CloudFlareClient::new(&cloudflare_token, &cloudflare_zone_id)
+    #[arg(long, help = "Save the plan to this file, to run it with apply")]
+    out: Option<PathBuf>,
 }
 
-async fn build_mqtt_client() -> Option<MqttClient> {
-    let enabled: bool = std::env::var("MQTT_ENABLED")
-        .unwrap_or(String::from("false"))
-        .parse()
-        .expect("Environment variable MQTT_ENABLED must be a boolean");
+pub async fn plan_command(args: &PlanArguments, mut config: Config) -> i32 {
+    if let Some(ref ip_version) = args.ip_version {
+        config.detection.ip_versions = ip_version.families();
+    }
 
-    if !enabled {
-        debug!("MQTT is disabled");
-        return None;
+    if let Err(e) = config.validate() {
+        error!("{}", e);
+        return 1;
     }
 
-    debug!("MQTT is enabled");
+    let detector = match Detector::from_config(&config.detection.sources, config.detection.quorum) {
+        Ok(detector) => detector,
+        Err(e) => {
+            error!("{}", e);
+            return 1;
+        }
+    };
 
-    trace!("Building MqttClient");
+    let state = match open_state(&config) {
+        Ok(state) => state,
+        Err(e) => {
+            error!("{}", e);
+            return 1;
+        }
+    };
+
+    let zones = match build_zones(&config, &state).await {
+        Ok(zones) => zones,
+        Err(e) => {
+            error!("{}", e);
+            return 1;
+        }
+    };
+
+    let mut plan = Plan {
+        created_on: Utc::now(),
+        changes: vec![],
+    };
+    let mut found = false;
+
+    for family in config.detection.ip_versions {
+        let current_ip = match detector.detect(family).await {
+            Some(ip) => ip,
+            None => {
+                warn!("Could not get public {}", family);
+                continue;
+            }
+        };
+        found = true;
+        debug!("Current {}: {}", family, current_ip);
+
+        for zone in &zones {
+            let stale_ips: Vec<IpAddr> = state
+                .last_applied(&zone.id, &zone.selectors, family)
+                .into_iter()
+                .collect();
+
+            match plan_zone(zone, &stale_ips, current_ip).await {
+                Ok(changes) => plan.changes.extend(changes),
+                Err(e) => {
+                    error!("Failed to get dns records: {}", e);
+                    return 1;
+                }
+            }
+        }
+    }
+
+    if !found {
+        error!("Could not get public IP");
+        return 1;
+    }
+
+    if args.json {
+        match plan.to_json() {
+            Ok(json) => println!("{}", json),
+            Err(e) => {
+                error!("Could not serialize the plan: {}", e);
+                return 1;
+            }
+        }
+    } else if plan.changes.is_empty() {
+        info!("No managed record found");
+    } else {
+        info!("{}", plan.table());
+    }
+
+    if !plan.has_changes() && !args.json {
+        info!("The records are up to date");
+    }
+
+    if let Some(ref out) = args.out {
+        if let Err(e) = plan.save(out) {
+            error!("{}", e);
+            return 1;
+        }
+        info!(
+            "Plan saved to {}, run it with `apply {}`",
+            out.display(),
+            out.display()
+        );
+    }
 
-    let mqtt_host = std::env::var("MQTT_HOST").expect("Environment variable MQTT_HOST is not set");
-    let mqtt_port: u16 = std::env::var("MQTT_PORT")
-        .unwrap_or(String::from("1883"))
-        .parse()
-        .expect("Environment variable MQTT_PORT must be a valid number");
-    let mqtt_id = std::env::var("MQTT_ID").unwrap_or(String::from("cfdpip"));
-    let mqtt_base_topic = std::env::var("MQTT_BASE_TOPIC").unwrap_or(String::from("cfdpip"));
+    0
+}
 
-    Some(MqttClient::new(&mqtt_host, mqtt_port, &mqtt_id, &mqtt_base_topic).await)
+#[derive(Debug, Args)]
+pub struct ApplyArguments {
+    #[arg(help = "Plan file saved by `plan --out`")]
+    plan: PathBuf,
 }
 
-async fn handle_update_ip_message(
-    old_ip: Ipv4Addr,
-    new_ip: Ipv4Addr,
-    mqtt_client: &Option<MqttClient>,
-    cloudflare_client: &CloudFlareClient,
-) {
-    info!("IP address change detected from {} to {}", old_ip, new_ip);
+pub async fn apply_command(args: &ApplyArguments, config: Config) -> i32 {
+    if let Err(e) = config.validate_cloudflare() {
+        error!("{}", e);
+        return 1;
+    }
 
-    if let Some(ref mqtt_client) = mqtt_client {
-        match mqtt_client
-            .publish_ip_change(IpChangeMessage {
-                old: old_ip,
-                new: new_ip,
-            })
-            .await
-        {
-            Ok(_) => debug!("MQTT message sent"),
-            Err(_) => error!(" Failed to send MQTT message"),
+    let plan = match Plan::load(&args.plan) {
+        Ok(plan) => plan,
+        Err(e) => {
+            error!("{}", e);
+            return 1;
         }
+    };
+
+    if !plan.has_changes() {
+        info!("Nothing to apply, the plan has no changes");
+        return 0;
     }
 
-    loop {
-        match update_ip(&cloudflare_client, old_ip, new_ip).await {
-            Ok(_) => {
-                info!("Successfully updated IP to {}", new_ip);
-                break;
+    info!("{}", plan.table());
+
+    let state = match open_state(&config) {
+        Ok(state) => state,
+        Err(e) => {
+            error!("{}", e);
+            return 1;
+        }
+    };
+
+    let zones = match build_zones(&config, &state).await {
+        Ok(zones) => zones,
+        Err(e) => {
+            error!("{}", e);
+            return 1;
+        }
+    };
+
+    match apply_plan(&plan, &zones, &state).await {
+        Ok(_) => {
+            info!("Plan applied");
+            0
+        }
+        Err(e) => {
+            error!("{}", e);
+            1
+        }
+    }
+}
+
+#[derive(Debug, Args)]
+pub struct HistoryArguments {
+    #[arg(
+        long,
+        default_value_t = 20,
+        help = "Number of changes to list, the latest ones"
+    )]
+    limit: usize,
+
+    #[arg(long, help = "Print the changes with the saved records as JSON")]
+    json: bool,
+}
+
+pub async fn history_command(args: &HistoryArguments, config: Config) -> i32 {
+    let journal_path = config.journal_path();
+    let journal = Journal::new(&journal_path);
+
+    let entries = match journal.entries() {
+        Ok(entries) => entries,
+        Err(e) => {
+            error!("{}", e);
+            return 1;
+        }
+    };
+    let entries = &entries[entries.len().saturating_sub(args.limit)..];
+
+    if args.json {
+        return match serde_json::to_string_pretty(entries) {
+            Ok(json) => {
+                println!("{}", json);
+                0
             }
             Err(e) => {
-                error!("Failed to update IP: {:?}", e);
+                error!("Could not serialize the changes: {}", e);
+                1
+            }
+        };
+    }
 
-                let delay = std::time::Duration::from_secs(120);
-                warn!("Retrying in {:?}", delay);
+    if entries.is_empty() {
+        info!("No change saved in {}", journal_path.display());
+        return 0;
+    }
 
-                tokio::time::sleep(delay).await;
-            }
+    let mut text = format!(
+        "{:<20} {:<25} {:<32} {:<24} {}",
+        "CHANGE", "DATE", "ZONE", "DESCRIPTION", "RECORDS"
+    );
+    for entry in entries {
+        let names: Vec<&str> = entry.records.iter().map(|r| r.name.as_str()).collect();
+        text.push_str(&format!(
+            "\n{:<20} {:<25} {:<32} {:<24} {}",
+            entry.change_id,
+            entry
+                .created_on
+                .to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+            entry.zone_id,
+            entry.description,
+            names.join(", ")
+        ));
+    }
+    info!("{}", text);
+
+    0
+}
+
+#[derive(Debug, Args)]
+pub struct RollbackArguments {
+    #[arg(help = "Id of the change to undo, as listed by `history`")]
+    change_id: String,
+}
+
+pub async fn rollback_command(args: &RollbackArguments, config: Config) -> i32 {
+    if let Err(e) = config.validate_cloudflare() {
+        error!("{}", e);
+        return 1;
+    }
+
+    let journal_path = config.journal_path();
+    let journal = Journal::new(&journal_path);
+
+    let entry = match journal.find(&args.change_id) {
+        Ok(Some(entry)) => entry,
+        Ok(None) => {
+            error!(
+                "Change {} not found in {}",
+                args.change_id,
+                journal_path.display()
+            );
+            return 1;
+        }
+        Err(e) => {
+            error!("{}", e);
+            return 1;
+        }
+    };
+
+    let state = match open_state(&config) {
+        Ok(state) => state,
+        Err(e) => {
+            error!("{}", e);
+            return 1;
+        }
+    };
+
+    let zones = match build_zones(&config, &state).await {
+        Ok(zones) => zones,
+        Err(e) => {
+            error!("{}", e);
+            return 1;
+        }
+    };
+
+    let zone = match zones.iter().find(|z| z.id == entry.zone_id) {
+        Some(zone) => zone,
+        None => {
+            error!("Zone {} is not configured", entry.zone_id);
+            return 1;
+        }
+    };
+
+    match rollback(zone, &entry).await {
+        Ok(count) => {
+            info!("Restored {} records of change {}", count, entry.change_id);
+            0
+        }
+        Err(e) => {
+            error!("Failed to roll back change {}: {}", entry.change_id, e);
+            1
         }
     }
 }
 
-async fn update_ip(
-    client: &CloudFlareClient,
-    old_ip: Ipv4Addr,
-    new_ip: Ipv4Addr,
-) -> Result<(), CloudFlareClientError> {
-    let records = match client
-        .get_dns_records_with_content(&old_ip.to_string())
-        .await
-    {
+#[derive(clap::ValueEnum, Debug, Clone)]
+pub enum ExportFormat {
+    /// RFC 1035 zone file
+    Bind,
+}
+
+#[derive(Debug, Args)]
+pub struct ExportArguments {
+    #[arg(long, value_enum, default_value_t = ExportFormat::Bind, help = "Format of the export")]
+    format: ExportFormat,
+
+    #[arg(
+        long,
+        help = "Id or name of the zone to export, required with several zones"
+    )]
+    zone: Option<String>,
+
+    #[arg(short, long, help = "File to write, the standard output by default")]
+    output: Option<PathBuf>,
+}
+
+pub async fn export_command(args: &ExportArguments, config: Config) -> i32 {
+    if let Err(e) = config.validate_cloudflare() {
+        error!("{}", e);
+        return 1;
+    }
+
+    let state = match open_state(&config) {
+        Ok(state) => state,
+        Err(e) => {
+            error!("{}", e);
+            return 1;
+        }
+    };
+
+    let zones = match build_zones(&config, &state).await {
+        Ok(zones) => zones,
+        Err(e) => {
+            error!("{}", e);
+            return 1;
+        }
+    };
+
+    let zone = match select_zone(&zones, args.zone.as_deref()) {
+        Ok(zone) => zone,
+        Err(e) => {
+            error!("{}", e);
+            return 1;
+        }
+    };
+
+    let records = match zone.client.get_dns_records().await {
         Ok(r) => r.result,
-        Err(e) => return Err(e),
+        Err(e) => {
+            error!("Failed to get the records of zone {}: {}", zone.id, e);
+            return 1;
+        }
     };
 
-    debug!("Found {} records to update", records.len());
+    let text = match args.format {
+        ExportFormat::Bind => bind::render(&records),
+    };
 
-    for record in records {
-        let record_name = record.name.clone();
-        debug!("Updating record {}", record_name);
+    match &args.output {
+        Some(path) => match std::fs::write(path, text) {
+            Ok(_) => {
+                info!(
+                    "Exported {} records of zone {} to {}",
+                    records.len(),
+                    zone.id,
+                    path.display()
+                );
+                0
+            }
+            Err(e) => {
+                error!("Could not write {}: {}", path.display(), e);
+                1
+            }
+        },
+        None => {
+            print!("{}", text);
+            0
+        }
+    }
+}
+
+#[derive(Debug, Args)]
+pub struct ImportArguments {
+    #[arg(help = "Zone file to import, in the BIND format")]
+    file: PathBuf,
+
+    #[arg(
+        long,
+        help = "Id or name of the zone to import into, required with several zones"
+    )]
+    zone: Option<String>,
+
+    #[arg(
+        long,
+        help = "Domain completing the relative names, until a $ORIGIN in the file"
+    )]
+    origin: Option<String>,
+}
+
+pub async fn import_command(args: &ImportArguments, config: Config) -> i32 {
+    if let Err(e) = config.validate_cloudflare() {
+        error!("{}", e);
+        return 1;
+    }
+
+    let text = match std::fs::read_to_string(&args.file) {
+        Ok(text) => text,
+        Err(e) => {
+            error!("Could not read {}: {}", args.file.display(), e);
+            return 1;
+        }
+    };
+
+    let records: Vec<CreateDNSRecordRequest> = match bind::parse(&text, args.origin.as_deref()) {
+        Ok(parsed) => parsed.into_iter().map(|p| p.record).collect(),
+        Err(e) => {
+            error!("Could not parse {}: {}", args.file.display(), e);
+            return 1;
+        }
+    };
+
+    let state = match open_state(&config) {
+        Ok(state) => state,
+        Err(e) => {
+            error!("{}", e);
+            return 1;
+        }
+    };
 
-        let mut new_record = UpdateDNSRecordRequest::from(record);
-        new_record.content = new_ip.to_string();
+    let zones = match build_zones(&config, &state).await {
+        Ok(zones) => zones,
+        Err(e) => {
+            error!("{}", e);
+            return 1;
+        }
+    };
 
-        if let Err(e) = client.set_dns_record(new_record).await {
-            error!("Failed to update record {}", record_name);
-            return Err(e);
+    let zone = match select_zone(&zones, args.zone.as_deref()) {
+        Ok(zone) => zone,
+        Err(e) => {
+            error!("{}", e);
+            return 1;
         }
+    };
 
-        info!("Successfully updated record {}", record_name);
+    match import(zone, &records).await {
+        Ok((created, updated)) => {
+            info!(
+                "Imported {} records into zone {}: {} created, {} updated",
+                records.len(),
+                zone.id,
+                created,
+                updated
+            );
+            0
+        }
+        Err(e) => {
+            error!("Failed to import {}: {}", args.file.display(), e);
+            1
+        }
     }
+}
 
-    Ok(())
+/// The zone with the given id or name, or the only one configured
+fn select_zone<'a>(zones: &'a [Zone], zone: Option<&str>) -> Result<&'a Zone, String> {
+    match (zone, zones) {
+        (Some(zone), _) => zones
+            .iter()
+            .find(|z| z.id == zone || z.name.as_deref() == Some(zone))
+            .ok_or(format!("Zone {} is not configured", zone)),
+        (None, [zone]) => Ok(zone),
+        (None, _) => Err(String::from(
+            "Several zones are configured, select one with --zone",
+        )),
+    }
 }
 
-#[derive(Debug)]
-enum MonitorLoopMessage {
-    IpChanged { old_ip: Ipv4Addr, new_ip: Ipv4Addr },
-    CouldNotGetIp,
-    NoChange,
+/// The state is not saved in dry run mode, nothing was applied
+fn open_state(config: &Config) -> Result<StateFile, StateError> {
+    let state = StateFile::open(&config.state.path)?;
+
+    match config.dry_run {
+        true => Ok(state.read_only()),
+        false => Ok(state),
+    }
 }
 
-struct MonitorLoop {
-    wait_time: std::time::Duration,
-    tx: mpsc::Sender<MonitorLoopMessage>,
-    rx: mpsc::Receiver<MonitorLoopMessage>,
+/// Apply a command received over MQTT, returns what was done or why not
+fn run_command(
+    command: Command,
+    updater: &mut Updater,
+    control: &LoopControl,
+    current_ips: &mut HashMap<IpFamily, IpAddr>,
+    families: &[IpFamily],
+) -> Result<String, String> {
+    match command {
+        Command::CheckNow => match control.is_paused() {
+            true => Err(String::from("monitoring is paused")),
+            false => {
+                control.check_now();
+                Ok(String::from("checking the public IP"))
+            }
+        },
+        Command::Reconcile => {
+            if control.is_paused() {
+                return Err(String::from("monitoring is paused"));
+            }
+            if current_ips.is_empty() {
+                return Err(String::from("no public IP known yet"));
+            }
+
+            for ip in current_ips.values() {
+                updater.reconcile(*ip);
+            }
+            Ok(String::from(
+                "updating the records not using the current IP",
+            ))
+        }
+        Command::Pause => match control.pause() {
+            true => Ok(String::from("monitoring paused")),
+            false => Ok(String::from("monitoring already paused")),
+        },
+        Command::Resume => match control.resume() {
+            true => Ok(String::from("monitoring resumed")),
+            false => Ok(String::from(
+                "monitoring not paused, checking the public IP",
+            )),
+        },
+        // applied while paused too, pausing is how the IP is kept
+        Command::SetIp(ip) => {
+            let family = IpFamily::of(&ip);
+            if !families.contains(&family) {
+                return Err(format!("{} is not monitored", family));
+            }
+
+            match current_ips.insert(family, ip) {
+                Some(old_ip) if old_ip != ip => updater.ip_changed(old_ip, ip),
+                _ => updater.reconcile(ip),
+            }
+            Ok(format!("{} set to {}", family, ip))
+        }
+    }
 }
 
-impl MonitorLoop {
-    fn new(wait_time: std::time::Duration) -> Self {
-        let (tx, rx) = mpsc::channel();
-        Self { wait_time, tx, rx }
+/// Next message of an optional subscription, never resolves without one
+async fn next_command(receiver: &mut Option<mpsc::Receiver<Bytes>>) -> Option<Bytes> {
+    match receiver {
+        Some(receiver) => receiver.recv().await,
+        None => std::future::pending().await,
     }
+}
 
-    fn start(&self) {
-        let wait_time = self.wait_time;
-        debug!("Loop wait time: {}ms", wait_time.as_millis());
-        let tx = self.tx.clone();
+/// Resolves on Ctrl+C, or SIGTERM as sent by `docker stop`
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
 
-        tokio::spawn(async move {
-            let start_ip = public_ip::addr_v4()
-                .await
-                .expect("Could not get public IP address");
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => {}
+                    _ = terminate.recv() => {}
+                }
+            }
+            Err(e) => {
+                warn!("Could not listen for SIGTERM: {}", e);
+                let _ = tokio::signal::ctrl_c().await;
+            }
+        }
+    }
 
-            info!("Current IP is {}", start_ip);
+    #[cfg(not(unix))]
+    let _ = tokio::signal::ctrl_c().await;
+}
 
-            let mut old_ip = start_ip;
+#[derive(Debug, Args)]
+pub struct ConfigArguments {
+    #[command(subcommand)]
+    command: ConfigCommands,
+}
 
-            trace!("Starting IP monitoring loop");
+#[derive(Debug, Subcommand)]
+enum ConfigCommands {
+    #[command(about = "Validate the configuration and print the resulting values")]
+    Check,
+}
 
-            loop {
-                if let Some(current_ip) = public_ip::addr_v4().await {
-                    if old_ip != current_ip {
-                        tx.send(MonitorLoopMessage::IpChanged {
-                            old_ip,
-                            new_ip: current_ip,
-                        })
-                        .unwrap();
+pub async fn config_command(args: &ConfigArguments, config: Config) -> i32 {
+    match args.command {
+        ConfigCommands::Check => {
+            if let Err(e) = config.validate() {
+                error!("{}", e);
+                return 1;
+            }
 
-                        old_ip = current_ip;
-                    } else {
-                        tx.send(MonitorLoopMessage::NoChange).unwrap();
-                    }
-                } else {
-                    tx.send(MonitorLoopMessage::CouldNotGetIp).unwrap();
+            match toml::to_string_pretty(&config.redacted()) {
+                Ok(text) => info!("Configuration is valid\n{}", text),
+                Err(e) => {
+                    error!("Could not serialize configuration: {}", e);
+                    return 1;
                 }
-
-                tokio::time::sleep(wait_time).await;
             }
-        });
+
+            0
+        }
     }
+}
 
-    fn listen(&self) -> &mpsc::Receiver<MonitorLoopMessage> {
-        &self.rx
+async fn build_mqtt_client(config: &MqttConfig) -> Result<Option<MqttClient>, MqttError> {
+    if !config.enabled {
+        debug!("MQTT is disabled");
+        return Ok(None);
     }
+
+    debug!("MQTT is enabled");
+
+    trace!("Building MqttClient");
+
+    Ok(Some(MqttClient::new(config).await?))
 }
@@ -1,5 +1,13 @@
+use std::path::PathBuf;
+
 use clap::{Parser, Subcommand};
+use log::error;
 mod commands;
+mod monitor;
+mod plan;
+mod zone;
+
+use crate::{config::Config, ip::IpFamily};
 
 #[derive(Debug, Parser)]
 #[command(name = "Cloudflare Dynamic Public IP", bin_name = "cfdpip", author="Apollo-Roboto", version, about="Automatically update public ip address in cloudflare dns records", long_about = None)]
@@ -7,6 +15,20 @@ pub struct Cli {
     #[arg(short, long, value_enum, default_value_t = LevelFilterArgument::Info, help = "Set verbosity level")]
     pub verbose: LevelFilterArgument,
 
+    #[arg(
+        short,
+        long,
+        help = "Path to a TOML or YAML config file, environment variables and flags override its values"
+    )]
+    pub config: Option<PathBuf>,
+
+    #[arg(
+        long,
+        global = true,
+        help = "Log the changes to the DNS records instead of applying them"
+    )]
+    pub dry_run: bool,
+
     #[command(subcommand)]
     pub command: Commands,
 }
@@ -34,6 +56,23 @@ impl LevelFilterArgument {
     }
 }
 
+#[derive(clap::ValueEnum, Debug, Clone)]
+pub enum IpVersionArgument {
+    Ipv4,
+    Ipv6,
+    Both,
+}
+
+impl IpVersionArgument {
+    pub fn families(&self) -> Vec<IpFamily> {
+        match self {
+            IpVersionArgument::Ipv4 => vec![IpFamily::Ipv4],
+            IpVersionArgument::Ipv6 => vec![IpFamily::Ipv6],
+            IpVersionArgument::Both => vec![IpFamily::Ipv4, IpFamily::Ipv6],
+        }
+    }
+}
+
 #[derive(Debug, Subcommand)]
 pub enum Commands {
     #[command(about = "Print the current public IP")]
@@ -42,14 +81,43 @@ pub enum Commands {
     Info(commands::InfoArguments),
     #[command(about = "Monitor and update DNS records on cloudflare when the public IP changes")]
     Monitor(commands::MonitorArguments),
+    #[command(
+        about = "Update the DNS records once and exit, for cron jobs and PPP ip-up hooks",
+        after_help = "Exit codes: 0 records up to date, 1 invalid configuration, 2 no public IP found, 3 update failed"
+    )]
+    Update(commands::UpdateArguments),
+    #[command(
+        about = "Show the changes the current IP requires, optionally saving them to a plan file"
+    )]
+    Plan(commands::PlanArguments),
+    #[command(about = "Apply a saved plan, unless the planned records changed since")]
+    Apply(commands::ApplyArguments),
+    #[command(about = "List the changes made to the DNS records, saved in the journal")]
+    History(commands::HistoryArguments),
+    #[command(about = "Restore the DNS records as they were before a change")]
+    Rollback(commands::RollbackArguments),
+    #[command(about = "Write the DNS records of a zone to a zone file")]
+    Export(commands::ExportArguments),
+    #[command(about = "Create and update DNS records from a zone file")]
+    Import(commands::ImportArguments),
+    #[command(about = "Inspect the configuration")]
+    Config(commands::ConfigArguments),
 }
 
 impl Commands {
-    pub async fn run(&self) -> i32 {
+    pub async fn run(&self, config: Config) -> i32 {
         match self {
-            Commands::Monitor(args) => commands::monitor_command(args).await,
-            Commands::Info(args) => commands::info_command(args).await,
-            Commands::Current(args) => commands::current_command(args).await,
+            Commands::Monitor(args) => commands::monitor_command(args, config).await,
+            Commands::Update(args) => commands::update_command(args, config).await,
+            Commands::Plan(args) => commands::plan_command(args, config).await,
+            Commands::Apply(args) => commands::apply_command(args, config).await,
+            Commands::History(args) => commands::history_command(args, config).await,
+            Commands::Rollback(args) => commands::rollback_command(args, config).await,
+            Commands::Export(args) => commands::export_command(args, config).await,
+            Commands::Import(args) => commands::import_command(args, config).await,
+            Commands::Info(args) => commands::info_command(args, config).await,
+            Commands::Current(args) => commands::current_command(args, config).await,
+            Commands::Config(args) => commands::config_command(args, config).await,
         }
     }
 }
@@ -59,5 +127,17 @@ pub async fn run() -> i32 {
 
     log::set_max_level(parsed_cli.verbose.level_filter());
 
-    parsed_cli.command.run().await
+    let mut config = match Config::load(parsed_cli.config.as_deref()) {
+        Ok(config) => config,
+        Err(e) => {
+            error!("{}", e);
+            return 1;
+        }
+    };
+
+    if parsed_cli.dry_run {
+        config.dry_run = true;
+    }
+
+    parsed_cli.command.run(config).await
 }
@@ -0,0 +1,1088 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, RwLock,
+    },
+    time::Duration,
+};
+
+use chrono::{DateTime, Utc};
+
+use futures::future::join_all;
+use log::{debug, error, info, trace, warn};
+use rumqttc::ClientError;
+use tokio::{
+    sync::{mpsc, watch, Notify},
+    task::JoinHandle,
+    time::Instant,
+};
+
+use super::zone::{find_drift, update_ip, Zone};
+use crate::{
+    cloudflare::models::{CloudFlareClientError, DNSRecord},
+    config::DriftMode,
+    ip::{Detector, IpFamily},
+    mqtt::{
+        command::CommandResultMessage, CircuitMessage, DriftMessage, HeartbeatMessage,
+        IpChangeMessage, MqttClient, RecordStatus, RecordStatusMessage, UpdateResult,
+        UpdateResultMessage,
+    },
+    retry::{CircuitBreaker, CircuitState, RetryPolicy},
+    state::StateFile,
+};
+
+#[derive(Debug)]
+pub(super) enum MonitorLoopMessage {
+    IpChanged {
+        old_ip: IpAddr,
+        new_ip: IpAddr,
+    },
+    /// First IP of a family that could not be detected until now
+    IpFound(IpAddr),
+    CouldNotGetIp(IpFamily),
+    NoChange(IpFamily),
+}
+
+/// Requests to the monitor loop while it runs, from the MQTT commands
+#[derive(Default)]
+pub(super) struct LoopControl {
+    check_now: Notify,
+    paused: AtomicBool,
+    forget_ips: AtomicBool,
+}
+
+impl LoopControl {
+    /// Check without waiting for the end of the delay, unless paused
+    pub(super) fn check_now(&self) {
+        self.check_now.notify_one();
+    }
+
+    pub(super) fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::SeqCst)
+    }
+
+    /// Skip the checks until resumed, returns false when already paused
+    pub(super) fn pause(&self) -> bool {
+        !self.paused.swap(true, Ordering::SeqCst)
+    }
+
+    /// Check right away and report the detected IPs as found so that they
+    /// replace the ones set meanwhile, returns false when not paused
+    pub(super) fn resume(&self) -> bool {
+        let was_paused = self.paused.swap(false, Ordering::SeqCst);
+        self.forget_ips.store(true, Ordering::SeqCst);
+        self.check_now();
+        was_paused
+    }
+}
+
+/// Periodically detects the public IPs and reports what changed
+pub(super) struct MonitorLoop {
+    wait_time: Duration,
+    /// Each address family is tracked independently, from the IP found on start
+    old_ips: Vec<(IpFamily, Option<IpAddr>)>,
+}
+
+impl MonitorLoop {
+    pub(super) fn new(wait_time: Duration, start_ips: Vec<(IpFamily, Option<IpAddr>)>) -> Self {
+        Self {
+            wait_time,
+            old_ips: start_ips,
+        }
+    }
+
+    /// Run the detection in a task until `shutdown` turns true or the
+    /// receiver of the messages is dropped
+    pub(super) fn start(
+        self,
+        detector: Detector,
+        control: Arc<LoopControl>,
+        mut shutdown: watch::Receiver<bool>,
+    ) -> (mpsc::Receiver<MonitorLoopMessage>, JoinHandle<()>) {
+        debug!("Loop wait time: {}ms", self.wait_time.as_millis());
+        let (tx, rx) = mpsc::channel(16);
+
+        let mut old_ips = self.old_ips;
+
+        let handle = tokio::spawn(async move {
+            trace!("Starting IP monitoring loop");
+
+            loop {
+                tokio::select! {
+                    _ = tokio::time::sleep(self.wait_time) => {}
+                    _ = control.check_now.notified() => debug!("Checking IP on request"),
+                    _ = shutdown.changed() => break,
+                }
+
+                if control.paused.load(Ordering::SeqCst) {
+                    trace!("Monitoring paused, skipping the check");
+                    continue;
+                }
+
+                // report the detected IPs as found, replacing the ones set by hand
+                if control.forget_ips.swap(false, Ordering::SeqCst) {
+                    for (_, old_ip) in old_ips.iter_mut() {
+                        *old_ip = None;
+                    }
+                }
+
+                for (family, old_ip) in old_ips.iter_mut() {
+                    let message = match (*old_ip, detector.detect(*family).await) {
+                        (Some(old), Some(current_ip)) if old != current_ip => {
+                            *old_ip = Some(current_ip);
+                            MonitorLoopMessage::IpChanged {
+                                old_ip: old,
+                                new_ip: current_ip,
+                            }
+                        }
+                        (Some(_), Some(_)) => MonitorLoopMessage::NoChange(*family),
+                        (None, Some(current_ip)) => {
+                            info!("Current {} is {}", family, current_ip);
+                            *old_ip = Some(current_ip);
+                            MonitorLoopMessage::IpFound(current_ip)
+                        }
+                        (_, None) => MonitorLoopMessage::CouldNotGetIp(*family),
+                    };
+
+                    if tx.send(message).await.is_err() {
+                        return;
+                    }
+                }
+            }
+
+            trace!("IP monitoring loop stopped");
+        });
+
+        (rx, handle)
+    }
+}
+
+/// What the heartbeats report about the monitor
+pub(super) struct Health {
+    started: Instant,
+    last_check: Mutex<Option<DateTime<Utc>>>,
+    last_error: Mutex<Option<(DateTime<Utc>, String)>>,
+}
+
+impl Health {
+    pub(super) fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            last_check: Mutex::new(None),
+            last_error: Mutex::new(None),
+        }
+    }
+
+    pub(super) fn checked(&self) {
+        *self.last_check.lock().unwrap() = Some(Utc::now());
+    }
+
+    /// Kept until the next error, the time tells whether it is still relevant
+    pub(super) fn failed(&self, error: String) {
+        *self.last_error.lock().unwrap() = Some((Utc::now(), error));
+    }
+
+    pub(super) fn heartbeat(&self) -> HeartbeatMessage {
+        let last_error = self.last_error.lock().unwrap().clone();
+
+        HeartbeatMessage {
+            uptime: self.started.elapsed().as_secs(),
+            last_check: *self.last_check.lock().unwrap(),
+            last_error_on: last_error.as_ref().map(|(on, _)| *on),
+            last_error: last_error.map(|(_, error)| error),
+            cloudflare_budget: None,
+        }
+    }
+}
+
+/// Everything an update needs, shared with the update tasks
+pub(super) struct UpdateContext {
+    /// Grows when a zone that could not be looked up on start is found
+    pub(super) zones: RwLock<Vec<Arc<Zone>>>,
+    pub(super) mqtt_client: Option<MqttClient>,
+    pub(super) retry: RetryPolicy,
+    pub(super) circuit_breaker: CircuitBreaker,
+    pub(super) state: StateFile,
+    pub(super) drift_mode: DriftMode,
+    pub(super) health: Health,
+}
+
+impl UpdateContext {
+    /// The zones known now, the running updates keep the ones they started with
+    fn zones(&self) -> Vec<Arc<Zone>> {
+        self.zones.read().unwrap().clone()
+    }
+}
+
+/// Applies the IP changes in the background, one task per address family.
+/// A newer change aborts the update of the same family still being retried
+/// and takes over its stale addresses, as well as those of an update that
+/// gave up, so an outdated IP is never applied after a newer one.
+pub(super) struct Updater {
+    context: Arc<UpdateContext>,
+    in_flight: HashMap<IpFamily, InFlight>,
+    /// Drift checks are not updates, a change aborts them without a warning
+    /// and there is nothing to take over from them
+    drift_checks: HashMap<IpFamily, JoinHandle<()>>,
+}
+
+struct InFlight {
+    /// Addresses the managed records may still point to
+    stale_ips: Vec<IpAddr>,
+    new_ip: IpAddr,
+    /// Set once every zone uses `new_ip`
+    applied: Arc<AtomicBool>,
+    handle: JoinHandle<()>,
+}
+
+impl Updater {
+    pub(super) fn new(context: UpdateContext) -> Self {
+        Self {
+            context: Arc::new(context),
+            in_flight: HashMap::new(),
+            drift_checks: HashMap::new(),
+        }
+    }
+
+    pub(super) fn ip_changed(&mut self, old_ip: IpAddr, new_ip: IpAddr) {
+        self.apply(vec![old_ip], new_ip);
+    }
+
+    /// Repair what changed while cfdpip was not running: the records still
+    /// using the last applied IP, and the selected records not using `current_ip`
+    pub(super) fn reconcile(&mut self, current_ip: IpAddr) {
+        let family = IpFamily::of(&current_ip);
+        let mut stale_ips = Vec::new();
+
+        for zone in self.context.zones() {
+            let last_applied = self
+                .context
+                .state
+                .last_applied(&zone.id, &zone.selectors, family);
+
+            if let Some(ip) = last_applied {
+                if ip != current_ip && !stale_ips.contains(&ip) {
+                    stale_ips.push(ip);
+                }
+            }
+        }
+
+        self.apply(stale_ips, current_ip);
+    }
+
+    /// Compare the managed records of the family of `ip` to their desired
+    /// state, unless an update or a check of the family is still running
+    pub(super) fn check_drift(&mut self, ip: IpAddr) {
+        let family = IpFamily::of(&ip);
+
+        if let Some(in_flight) = self.in_flight.get(&family) {
+            if !in_flight.handle.is_finished() {
+                debug!("{} update in progress, skipping the drift check", family);
+                return;
+            }
+        }
+
+        if let Some(check) = self.drift_checks.get(&family) {
+            if !check.is_finished() {
+                debug!("{} drift check still running, skipping it", family);
+                return;
+            }
+        }
+
+        let context = self.context.clone();
+        let handle = tokio::spawn(async move { handle_drift_check(ip, &context).await });
+
+        self.drift_checks.insert(family, handle);
+    }
+
+    fn apply(&mut self, mut stale_ips: Vec<IpAddr>, new_ip: IpAddr) {
+        let family = IpFamily::of(&new_ip);
+
+        // the records are checked again once updated
+        if let Some(check) = self.drift_checks.remove(&family) {
+            if !check.is_finished() {
+                debug!("Cancelling the {} drift check for the update", family);
+                check.abort();
+            }
+        }
+
+        if let Some(previous) = self.in_flight.remove(&family) {
+            if !previous.handle.is_finished() {
+                warn!(
+                    "{} changed to {} before {} was applied, dropping the pending update",
+                    family, new_ip, previous.new_ip
+                );
+                previous.handle.abort();
+            }
+
+            // the records of the zones that failed may use any of them
+            if !previous.applied.load(Ordering::SeqCst) {
+                for ip in previous.stale_ips.into_iter().chain([previous.new_ip]) {
+                    if ip != new_ip && !stale_ips.contains(&ip) {
+                        stale_ips.push(ip);
+                    }
+                }
+            }
+        }
+
+        let context = self.context.clone();
+        let task_stale_ips = stale_ips.clone();
+        let applied = Arc::new(AtomicBool::new(false));
+        let task_applied = applied.clone();
+        let handle = tokio::spawn(async move {
+            if handle_update_ip_message(&task_stale_ips, new_ip, &context).await {
+                task_applied.store(true, Ordering::SeqCst);
+            }
+        });
+
+        self.in_flight.insert(
+            family,
+            InFlight {
+                stale_ips,
+                new_ip,
+                applied,
+                handle,
+            },
+        );
+    }
+
+    /// Manage a zone found after starting, its id is kept in the state file
+    pub(super) fn add_zone(&self, zone: Zone) {
+        if let Some(ref name) = zone.name {
+            self.context.state.record_zone_id(name, &zone.id);
+        }
+
+        self.context.zones.write().unwrap().push(Arc::new(zone));
+    }
+
+    pub(super) fn health(&self) -> &Health {
+        &self.context.health
+    }
+
+    pub(super) fn publish_command_result(&self, payload: CommandResultMessage) {
+        if let Some(ref mqtt_client) = self.context.mqtt_client {
+            match mqtt_client.publish_command_result(payload) {
+                Ok(_) => debug!("MQTT message queued"),
+                Err(e) => warn!("Dropped MQTT command result: {}", e),
+            }
+        }
+    }
+
+    pub(super) fn publish_heartbeat(&self) {
+        if let Some(ref mqtt_client) = self.context.mqtt_client {
+            // every zone uses the same token, hence the same budget
+            let heartbeat = HeartbeatMessage {
+                cloudflare_budget: self
+                    .context
+                    .zones()
+                    .first()
+                    .map(|zone| zone.client.remaining_budget()),
+                ..self.context.health.heartbeat()
+            };
+
+            match mqtt_client.publish_heartbeat(heartbeat) {
+                Ok(_) => debug!("MQTT message queued"),
+                Err(e) => warn!("Dropped MQTT heartbeat: {}", e),
+            }
+        }
+    }
+
+    /// Cancel the updates still running and wait for them to stop, then
+    /// leave the MQTT broker
+    pub(super) async fn shutdown(self) {
+        for (family, in_flight) in self.in_flight {
+            if !in_flight.handle.is_finished() {
+                info!(
+                    "Cancelling the pending {} update to {}",
+                    family, in_flight.new_ip
+                );
+                in_flight.handle.abort();
+            }
+
+            let _ = in_flight.handle.await;
+        }
+
+        for (_, check) in self.drift_checks {
+            check.abort();
+            let _ = check.await;
+        }
+
+        if let Some(ref mqtt_client) = self.context.mqtt_client {
+            mqtt_client.disconnect().await;
+        }
+    }
+}
+
+/// A zone id kept in the state file is dropped once Cloudflare no longer
+/// knows it, such as after the zone was removed and added again
+async fn forget_unknown_zone(context: &UpdateContext, zone: &Zone) {
+    let name = match zone.name {
+        Some(ref name) if context.state.zone_id(name).as_ref() == Some(&zone.id) => name,
+        _ => return,
+    };
+
+    if let Ok(false) = zone.client.zone_exists().await {
+        error!(
+            "Zone {} no longer has id {}, looking it up again on the next start",
+            name, zone.id
+        );
+        context.state.forget_zone_id(name);
+    }
+}
+
+/// Update the records of every zone, returns whether they all use `new_ip`
+async fn handle_update_ip_message(
+    stale_ips: &[IpAddr],
+    new_ip: IpAddr,
+    context: &UpdateContext,
+) -> bool {
+    let family = IpFamily::of(&new_ip);
+
+    let old_ip = match stale_ips.first() {
+        Some(old_ip) => *old_ip,
+        None => {
+            debug!("Checking the managed {} records use {}", family, new_ip);
+            new_ip
+        }
+    };
+
+    let changed_on = Utc::now();
+    if old_ip != new_ip {
+        info!(
+            "{} address change detected from {} to {}",
+            family, old_ip, new_ip
+        );
+    }
+
+    let circuit_breaker = &context.circuit_breaker;
+    let mut attempts = 0;
+    // only the zones that failed are retried
+    let zones = context.zones();
+    let mut pending: Vec<&Zone> = zones.iter().map(|zone| zone.as_ref()).collect();
+
+    let applied = loop {
+        if circuit_breaker.state() == CircuitState::Open {
+            let wait = circuit_breaker.remaining_open();
+            info!(
+                "Circuit breaker open, calling Cloudflare again in {:?}",
+                wait
+            );
+            tokio::time::sleep(wait).await;
+        }
+
+        attempts += 1;
+
+        let results = join_all(
+            pending
+                .iter()
+                .map(|zone| update_ip(zone, stale_ips, new_ip)),
+        )
+        .await;
+
+        let mut failed = Vec::new();
+        let mut last_error = None;
+        for (zone, result) in pending.into_iter().zip(results) {
+            match result {
+                Ok(records) => {
+                    context
+                        .state
+                        .record_applied(&zone.id, &zone.selectors, new_ip);
+
+                    for record in records {
+                        publish_record_status(context, zone, record, new_ip, RecordStatus::InSync);
+                    }
+                }
+                Err(e) => {
+                    if matches!(
+                        e,
+                        CloudFlareClientError::Api(_) | CloudFlareClientError::Status(_)
+                    ) {
+                        forget_unknown_zone(context, zone).await;
+                    }
+
+                    let message = format!("Failed to update IP in zone {}: {}", zone.id, e);
+                    error!("{}", message);
+                    context.health.failed(message.clone());
+                    last_error = Some(message);
+                    failed.push(zone);
+                }
+            }
+        }
+        pending = failed;
+
+        match pending.is_empty() {
+            true => {
+                if let Some(state) = circuit_breaker.record_success() {
+                    report_circuit_state(state, circuit_breaker, &context.mqtt_client);
+                }
+
+                match old_ip != new_ip {
+                    true => info!("Successfully updated IP to {}", new_ip),
+                    false => debug!("Managed {} records are up to date", family),
+                }
+                publish_update_result(context, None);
+                break true;
+            }
+            false => {
+                if let Some(state) = circuit_breaker.record_failure() {
+                    report_circuit_state(state, circuit_breaker, &context.mqtt_client);
+                }
+
+                if !context.retry.can_retry(attempts) {
+                    error!(
+                        "Giving up updating IP in {} zones after {} attempts",
+                        pending.len(),
+                        attempts
+                    );
+                    publish_update_result(context, last_error);
+                    break false;
+                }
+
+                let delay = context.retry.delay(attempts);
+                warn!("Retrying in {:?}", delay);
+
+                tokio::time::sleep(delay).await;
+            }
+        }
+    };
+
+    // reported once the records are updated, never holding them back
+    if let Some(ref mqtt_client) = context.mqtt_client {
+        if old_ip != new_ip {
+            report(mqtt_client.publish_ip_change(IpChangeMessage {
+                family,
+                old: old_ip,
+                new: new_ip,
+            }));
+            report(mqtt_client.publish_last_change(changed_on));
+        }
+
+        report(mqtt_client.publish_ip(family, new_ip));
+    }
+    applied
+}
+
+/// The MQTT messages are queued without waiting, a full queue while the
+/// broker is unreachable drops them
+fn report(result: Result<(), ClientError>) {
+    match result {
+        Ok(_) => debug!("MQTT message queued"),
+        Err(e) => warn!("Dropped MQTT message: {}", e),
+    }
+}
+
+async fn handle_drift_check(ip: IpAddr, context: &UpdateContext) {
+    let family = IpFamily::of(&ip);
+
+    if context.circuit_breaker.state() == CircuitState::Open {
+        debug!("Circuit breaker open, skipping the {} drift check", family);
+        return;
+    }
+
+    debug!("Checking the managed {} records for drift", family);
+
+    join_all(
+        context
+            .zones()
+            .iter()
+            .map(|zone| check_zone_drift(zone, ip, context)),
+    )
+    .await;
+}
+
+async fn check_zone_drift(zone: &Zone, ip: IpAddr, context: &UpdateContext) {
+    let family = IpFamily::of(&ip);
+
+    let drifted = match find_drift(zone, ip).await {
+        Ok(drifted) => drifted,
+        Err(e) => {
+            let message = format!(
+                "Failed to check the {} records of zone {} for drift: {}",
+                family, zone.id, e
+            );
+            error!("{}", message);
+            context.health.failed(message);
+            return;
+        }
+    };
+
+    for (record, drift) in drifted {
+        let record_name = record.name.clone();
+
+        let status = match context.drift_mode {
+            DriftMode::Alert => RecordStatus::Drifted,
+            DriftMode::Fix => {
+                zone.backup("Drift fix", std::slice::from_ref(&record));
+
+                match zone
+                    .client
+                    .set_dns_record(zone.desired(ip).fix(record.clone()))
+                    .await
+                {
+                    Ok(_) => RecordStatus::InSync,
+                    Err(e) => {
+                        error!("Failed to fix record {}: {}", record_name, e);
+                        RecordStatus::Failed
+                    }
+                }
+            }
+        };
+        let fixed = status == RecordStatus::InSync;
+
+        for drift in drift {
+            warn!(
+                "Record {} drifted, {} is {} instead of {}{}",
+                record_name,
+                drift.field,
+                drift.actual,
+                drift.expected,
+                if fixed { ", fixed" } else { "" }
+            );
+
+            if let Some(ref mqtt_client) = context.mqtt_client {
+                report(mqtt_client.publish_drift(DriftMessage {
+                    zone_id: zone.id.clone(),
+                    record: record_name.clone(),
+                    field: drift.field,
+                    expected: drift.expected,
+                    actual: drift.actual,
+                    fixed,
+                }));
+            }
+        }
+
+        publish_record_status(context, zone, record, ip, status);
+    }
+}
+
+fn publish_record_status(
+    context: &UpdateContext,
+    zone: &Zone,
+    record: DNSRecord,
+    ip: IpAddr,
+    status: RecordStatus,
+) {
+    if let Some(ref mqtt_client) = context.mqtt_client {
+        let content = match status {
+            RecordStatus::InSync => ip.to_string(),
+            _ => record.content,
+        };
+
+        report(mqtt_client.publish_record_status(RecordStatusMessage {
+            zone_id: zone.id.clone(),
+            record_id: record.id,
+            name: record.name,
+            r#type: record.r#type,
+            content,
+            status,
+        }));
+    }
+}
+
+fn publish_update_result(context: &UpdateContext, error: Option<String>) {
+    if let Some(ref mqtt_client) = context.mqtt_client {
+        let result = match error {
+            Some(_) => UpdateResult::Failure,
+            None => UpdateResult::Success,
+        };
+
+        report(mqtt_client.publish_update_result(UpdateResultMessage {
+            result,
+            error,
+            finished_on: Utc::now(),
+        }));
+    }
+}
+
+fn report_circuit_state(
+    state: CircuitState,
+    circuit_breaker: &CircuitBreaker,
+    mqtt_client: &Option<MqttClient>,
+) {
+    let retry_in = match state {
+        CircuitState::Open => {
+            let remaining = circuit_breaker.remaining_open();
+            warn!(
+                "Circuit breaker open after repeated failures, leaving Cloudflare alone for {:?}",
+                remaining
+            );
+            Some(remaining.as_secs())
+        }
+        _ => {
+            info!("Circuit breaker {}, Cloudflare calls resumed", state);
+            None
+        }
+    };
+
+    if let Some(ref mqtt_client) = mqtt_client {
+        report(mqtt_client.publish_circuit_state(CircuitMessage { state, retry_in }));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::Ipv4Addr,
+        path::{Path, PathBuf},
+    };
+
+    use httpmock::prelude::*;
+
+    use super::*;
+    use crate::{
+        cloudflare::{
+            client::CloudFlareClient,
+            models::{DNSRecord, ResultInfo, SuccessResponseList},
+        },
+        ip::fixed::{FileSource, StaticSource},
+    };
+
+    fn ip(last: u8) -> IpAddr {
+        IpAddr::V4(Ipv4Addr::new(1, 2, 3, last))
+    }
+
+    fn zone(server: &MockServer, id: &str) -> Zone {
+        Zone {
+            id: String::from(id),
+            name: None,
+            client: CloudFlareClient::new_with_url("", id, &server.url("")),
+            selectors: vec![],
+            proxied: None,
+            ttl: None,
+            journal: None,
+        }
+    }
+
+    fn state_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("cfdpip-{}-state-{}.json", name, std::process::id()))
+    }
+
+    /// Without MQTT, fixing drift, the state file is left to the test to remove
+    fn context(zones: Vec<Zone>, retry: RetryPolicy, state_path: &Path) -> UpdateContext {
+        UpdateContext {
+            zones: RwLock::new(zones.into_iter().map(Arc::new).collect()),
+            mqtt_client: None,
+            retry,
+            circuit_breaker: CircuitBreaker::new(1000, Duration::ZERO),
+            state: StateFile::open(state_path).unwrap(),
+            drift_mode: DriftMode::Fix,
+            health: Health::new(),
+        }
+    }
+
+    #[tokio::test]
+    async fn monitor_loop_reports_changes_and_stops() {
+        let path = std::env::temp_dir().join(format!("cfdpip-monitor-loop-{}", std::process::id()));
+        std::fs::write(&path, "1.2.3.4\n").unwrap();
+
+        let detector = Detector::new(vec![Box::new(FileSource::new(&path))], None);
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+
+        let monitor_loop = MonitorLoop::new(
+            Duration::from_millis(20),
+            vec![(IpFamily::Ipv4, Some(ip(4)))],
+        );
+        let (mut messages, handle) =
+            monitor_loop.start(detector, Arc::new(LoopControl::default()), shutdown_rx);
+
+        assert!(matches!(
+            messages.recv().await,
+            Some(MonitorLoopMessage::NoChange(IpFamily::Ipv4))
+        ));
+
+        std::fs::write(&path, "1.2.3.5\n").unwrap();
+
+        let changed = loop {
+            match messages.recv().await {
+                Some(MonitorLoopMessage::NoChange(_)) => continue,
+                other => break other,
+            }
+        };
+
+        shutdown_tx.send(true).unwrap();
+        tokio::time::timeout(Duration::from_secs(5), handle)
+            .await
+            .unwrap()
+            .unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        match changed {
+            Some(MonitorLoopMessage::IpChanged { old_ip, new_ip }) => {
+                assert_eq!(old_ip, ip(4));
+                assert_eq!(new_ip, ip(5));
+            }
+            other => panic!("unexpected message {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn paused_loop_reports_the_detected_ip_on_resume() {
+        let detector = Detector::new(vec![Box::new(StaticSource::new(vec![ip(4)]))], None);
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+
+        let control = Arc::new(LoopControl::default());
+        assert!(control.pause());
+        assert!(!control.pause());
+
+        let monitor_loop = MonitorLoop::new(
+            Duration::from_millis(20),
+            vec![(IpFamily::Ipv4, Some(ip(5)))],
+        );
+        let (mut messages, handle) = monitor_loop.start(detector, control.clone(), shutdown_rx);
+
+        // nothing is checked while paused
+        control.check_now();
+        assert!(
+            tokio::time::timeout(Duration::from_millis(100), messages.recv())
+                .await
+                .is_err()
+        );
+
+        assert!(control.resume());
+        let message = tokio::time::timeout(Duration::from_secs(5), messages.recv())
+            .await
+            .unwrap();
+
+        shutdown_tx.send(true).unwrap();
+        tokio::time::timeout(Duration::from_secs(5), handle)
+            .await
+            .unwrap()
+            .unwrap();
+
+        // found rather than changed, the records may use an IP set by hand
+        assert!(matches!(message, Some(MonitorLoopMessage::IpFound(found)) if found == ip(4)));
+    }
+
+    async fn wait_for_update(updater: &Updater) {
+        while !updater.in_flight[&IpFamily::Ipv4].handle.is_finished() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn newer_change_supersedes_pending_update() {
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.method(GET).path_contains("/dns_records");
+            then.status(400).body("{}");
+        });
+
+        let fast = RetryPolicy {
+            max_attempts: None,
+            base_delay: Duration::from_millis(10),
+            max_delay: Duration::from_millis(10),
+            jitter: false,
+        };
+
+        let state_path = state_path("supersede");
+        let mut updater = Updater::new(context(vec![zone(&server, "1234")], fast, &state_path));
+
+        updater.ip_changed(ip(1), ip(2));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        updater.ip_changed(ip(2), ip(3));
+
+        let in_flight = &updater.in_flight[&IpFamily::Ipv4];
+        assert_eq!(in_flight.new_ip, ip(3));
+        assert_eq!(in_flight.stale_ips, vec![ip(2), ip(1)]);
+
+        tokio::time::timeout(Duration::from_secs(5), updater.shutdown())
+            .await
+            .unwrap();
+        let _ = std::fs::remove_file(&state_path);
+    }
+
+    #[tokio::test]
+    async fn change_cancels_the_drift_check() {
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.method(GET).path_contains("/dns_records");
+            then.status(400).delay(Duration::from_secs(1)).body("{}");
+        });
+
+        let state_path = state_path("drift-cancel");
+        let mut updater = Updater::new(context(
+            vec![zone(&server, "1234")],
+            RetryPolicy {
+                max_attempts: Some(1),
+                ..RetryPolicy::requests()
+            },
+            &state_path,
+        ));
+
+        updater.check_drift(ip(1));
+        assert!(!updater.in_flight.contains_key(&IpFamily::Ipv4));
+
+        // nothing is taken over from the check
+        updater.ip_changed(ip(1), ip(2));
+        assert!(updater.drift_checks.is_empty());
+        assert_eq!(updater.in_flight[&IpFamily::Ipv4].stale_ips, vec![ip(1)]);
+
+        tokio::time::timeout(Duration::from_secs(5), updater.shutdown())
+            .await
+            .unwrap();
+        let _ = std::fs::remove_file(&state_path);
+    }
+
+    #[tokio::test]
+    async fn reconcile_starts_from_the_last_applied_ip() {
+        let server = MockServer::start();
+        let mut failing = server.mock(|when, then| {
+            when.method(GET).path_contains("/dns_records");
+            then.status(400).body("{}");
+        });
+
+        let state_path = state_path("reconcile");
+        let context = context(
+            vec![zone(&server, "1234")],
+            RetryPolicy {
+                max_attempts: Some(1),
+                ..RetryPolicy::requests()
+            },
+            &state_path,
+        );
+        context.state.record_applied("1234", &[], ip(1));
+
+        let mut updater = Updater::new(context);
+
+        updater.reconcile(ip(2));
+        assert_eq!(updater.in_flight[&IpFamily::Ipv4].stale_ips, vec![ip(1)]);
+        wait_for_update(&updater).await;
+
+        // the update gave up, some records may still use either IP
+        updater.reconcile(ip(1));
+        assert_eq!(updater.in_flight[&IpFamily::Ipv4].stale_ips, vec![ip(2)]);
+        wait_for_update(&updater).await;
+
+        failing.delete();
+        server.mock(|when, then| {
+            when.method(GET).path_contains("/dns_records");
+            then.status(200)
+                .header("content-type", "application/json")
+                .body(r#"{ "success": true, "errors": [], "messages": [], "result_info": { "count": 0, "page": 1, "per_page": 500, "total_count": 0 }, "result": [] }"#);
+        });
+        updater.reconcile(ip(1));
+        wait_for_update(&updater).await;
+
+        // nothing is stale when the IP did not change
+        updater.reconcile(ip(1));
+        assert!(updater.in_flight[&IpFamily::Ipv4].stale_ips.is_empty());
+
+        updater.shutdown().await;
+        std::fs::remove_file(&state_path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn drift_check_fixes_records() {
+        let records = SuccessResponseList {
+            success: true,
+            result_info: ResultInfo {
+                count: 1,
+                page: 1,
+                per_page: 100,
+                total_count: 1,
+            },
+            result: vec![DNSRecord {
+                id: String::from("abc"),
+                name: String::from("home.example.com"),
+                content: String::from("1.2.3.1"),
+                proxied: Some(true),
+                proxiable: true,
+                ttl: Some(1),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.method(GET).path_contains("/dns_records");
+            then.status(200)
+                .header("content-type", "application/json")
+                .body(serde_json::to_string(&records).unwrap());
+        });
+        let fix_mock = server.mock(|when, then| {
+            when.method(httpmock::Method::PATCH)
+                .path_contains("/dns_records/abc")
+                .json_body_partial(r#"{ "proxied": false, "ttl": 300 }"#);
+            then.status(200)
+                .header("content-type", "application/json")
+                .body(r#"{ "success": true, "errors": [], "messages": [], "result": {} }"#);
+        });
+
+        let state_path = state_path("drift");
+        let mut context = context(
+            vec![Zone {
+                proxied: Some(false),
+                ttl: Some(300),
+                ..zone(&server, "1234")
+            }],
+            RetryPolicy::requests(),
+            &state_path,
+        );
+        context.drift_mode = DriftMode::Alert;
+
+        handle_drift_check(ip(1), &context).await;
+        fix_mock.assert_hits(0);
+
+        context.drift_mode = DriftMode::Fix;
+        handle_drift_check(ip(1), &context).await;
+        let _ = std::fs::remove_file(&state_path);
+        fix_mock.assert_hits(1);
+    }
+
+    #[tokio::test]
+    async fn failing_zone_does_not_block_the_others() {
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.method(GET).path_contains("/zones/bad/dns_records");
+            then.status(400).body("{}");
+        });
+        server.mock(|when, then| {
+            when.method(GET).path_contains("/zones/good/dns_records");
+            then.status(200)
+                .header("content-type", "application/json")
+                .body(r#"{ "success": true, "errors": [], "messages": [], "result_info": { "count": 0, "page": 1, "per_page": 500, "total_count": 0 }, "result": [] }"#);
+        });
+
+        let state_path = state_path("isolation");
+        let context = context(
+            vec![zone(&server, "bad"), zone(&server, "good")],
+            RetryPolicy {
+                max_attempts: Some(1),
+                ..RetryPolicy::requests()
+            },
+            &state_path,
+        );
+
+        handle_update_ip_message(&[ip(1)], ip(2), &context).await;
+        let _ = std::fs::remove_file(&state_path);
+
+        assert_eq!(
+            context.state.last_applied("good", &[], IpFamily::Ipv4),
+            Some(ip(2))
+        );
+        assert_eq!(context.state.last_applied("bad", &[], IpFamily::Ipv4), None);
+    }
+
+    #[test]
+    fn heartbeat_reports_the_last_check_and_error() {
+        let health = Health::new();
+
+        let heartbeat = health.heartbeat();
+        assert_eq!(heartbeat.last_check, None);
+        assert_eq!(heartbeat.last_error, None);
+
+        health.checked();
+        health.failed(String::from("Could not get public IPv4"));
+
+        let heartbeat = health.heartbeat();
+        assert!(heartbeat.last_check.is_some());
+        assert_eq!(
+            heartbeat.last_error.as_deref(),
+            Some("Could not get public IPv4")
+        );
+        assert!(heartbeat.last_error_on.is_some());
+    }
+}
@@ -0,0 +1,524 @@
+use core::fmt;
+use std::{
+    net::IpAddr,
+    path::{Path, PathBuf},
+};
+
+use chrono::{DateTime, Utc};
+use log::{debug, info};
+use serde::{Deserialize, Serialize};
+
+use super::zone::{stale_records, Zone};
+use crate::{
+    cloudflare::{
+        drift::{DesiredRecord, Drift, DriftField},
+        models::{BatchDNSRecordsRequest, CloudFlareClientError, CreateDNSRecordRequest, DNSType},
+        selector::RecordSelector,
+    },
+    ip::IpFamily,
+    state::StateFile,
+};
+
+#[derive(Debug)]
+pub(super) enum PlanError {
+    Io(PathBuf, std::io::Error),
+    Parse(PathBuf, serde_json::Error),
+    /// The plan changes a zone that is not in the configuration
+    UnknownZone(String),
+    /// The record is not as it was when the plan was made
+    Changed(String),
+    Cloudflare(CloudFlareClientError),
+}
+
+impl fmt::Display for PlanError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PlanError::Io(path, e) => write!(f, "Could not access {}: {}", path.display(), e),
+            PlanError::Parse(path, e) => write!(f, "Could not parse {}: {}", path.display(), e),
+            PlanError::UnknownZone(id) => write!(f, "Zone {} is not configured", id),
+            PlanError::Changed(name) => write!(
+                f,
+                "Record {} changed since the plan was made, make a new plan",
+                name
+            ),
+            PlanError::Cloudflare(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for PlanError {}
+
+impl From<CloudFlareClientError> for PlanError {
+    fn from(value: CloudFlareClientError) -> Self {
+        PlanError::Cloudflare(value)
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub(super) enum PlanAction {
+    Create,
+    Update,
+    NoOp,
+}
+
+impl fmt::Display for PlanAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PlanAction::Create => write!(f, "create"),
+            PlanAction::Update => write!(f, "update"),
+            PlanAction::NoOp => write!(f, "no-op"),
+        }
+    }
+}
+
+/// Changes to the managed records for the current IP, saved to be applied later
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub(super) struct Plan {
+    pub(super) created_on: DateTime<Utc>,
+    pub(super) changes: Vec<PlannedChange>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub(super) struct PlannedChange {
+    pub(super) zone_id: String,
+    pub(super) action: PlanAction,
+    pub(super) r#type: DNSType,
+    pub(super) name: String,
+    /// Record to update, not set for a creation
+    pub(super) record_id: Option<String>,
+    /// Last modification of the record when the plan was made
+    pub(super) modified_on: Option<DateTime<Utc>>,
+    pub(super) desired: DesiredRecord,
+    pub(super) drift: Vec<Drift>,
+}
+
+impl PlannedChange {
+    /// Short description of what changes, for the table
+    fn summary(&self) -> String {
+        let changes: Vec<String> = match self.action {
+            PlanAction::Create => vec![format!("content {}", self.desired.content)],
+            _ => self
+                .drift
+                .iter()
+                .map(|d| format!("{} {} -> {}", d.field, d.actual, d.expected))
+                .collect(),
+        };
+
+        changes.join(", ")
+    }
+}
+
+impl Plan {
+    pub(super) fn load(path: &Path) -> Result<Self, PlanError> {
+        let text =
+            std::fs::read_to_string(path).map_err(|e| PlanError::Io(path.to_path_buf(), e))?;
+
+        serde_json::from_str(&text).map_err(|e| PlanError::Parse(path.to_path_buf(), e))
+    }
+
+    pub(super) fn save(&self, path: &Path) -> Result<(), PlanError> {
+        let json = self
+            .to_json()
+            .map_err(|e| PlanError::Parse(path.to_path_buf(), e))?;
+
+        std::fs::write(path, json).map_err(|e| PlanError::Io(path.to_path_buf(), e))
+    }
+
+    pub(super) fn to_json(&self) -> Result<String, serde_json::Error> {
+        serde_json::to_string_pretty(self)
+    }
+
+    pub(super) fn has_changes(&self) -> bool {
+        self.changes.iter().any(|c| c.action != PlanAction::NoOp)
+    }
+
+    pub(super) fn table(&self) -> String {
+        let mut text = format!("{:<8} {:<6} {:<40} {}", "ACTION", "TYPE", "NAME", "CHANGES");
+
+        for change in &self.changes {
+            text.push_str(&format!(
+                "\n{:<8} {:<6} {:<40} {}",
+                change.action,
+                change.r#type,
+                change.name,
+                change.summary()
+            ));
+        }
+
+        text
+    }
+}
+
+/// Changes bringing the managed records of the zone to `ip`. Exact record
+/// names that do not exist yet are created.
+pub(super) async fn plan_zone(
+    zone: &Zone,
+    stale_ips: &[IpAddr],
+    ip: IpAddr,
+) -> Result<Vec<PlannedChange>, CloudFlareClientError> {
+    let record_type = IpFamily::of(&ip).record_type();
+    let desired = zone.desired(ip);
+
+    // the records already using the IP are listed too, as no-ops
+    let mut ips = stale_ips.to_vec();
+    ips.push(ip);
+    let records = stale_records(zone, &ips, ip).await?;
+
+    let mut changes: Vec<PlannedChange> = records
+        .iter()
+        .map(|record| {
+            let drift = desired.drift(record);
+
+            PlannedChange {
+                zone_id: zone.id.clone(),
+                action: match drift.is_empty() {
+                    true => PlanAction::NoOp,
+                    false => PlanAction::Update,
+                },
+                r#type: record.r#type.clone(),
+                name: record.name.clone(),
+                record_id: Some(record.id.clone()),
+                modified_on: Some(record.modified_on),
+                desired: desired.clone(),
+                drift,
+            }
+        })
+        .collect();
+
+    for selector in &zone.selectors {
+        let name = match selector {
+            RecordSelector::Name(name) if !name.contains(['*', '?']) => name,
+            _ => continue,
+        };
+
+        if records.iter().any(|r| r.name.eq_ignore_ascii_case(name)) {
+            continue;
+        }
+
+        changes.push(PlannedChange {
+            zone_id: zone.id.clone(),
+            action: PlanAction::Create,
+            r#type: record_type.clone(),
+            name: name.clone(),
+            record_id: None,
+            modified_on: None,
+            desired: desired.clone(),
+            drift: vec![Drift {
+                field: DriftField::Content,
+                expected: desired.content.clone(),
+                actual: String::new(),
+            }],
+        });
+    }
+
+    Ok(changes)
+}
+
+/// Apply the plan with one batch per zone, after checking that none of the
+/// planned records changed in any zone
+pub(super) async fn apply_plan(
+    plan: &Plan,
+    zones: &[Zone],
+    state: &StateFile,
+) -> Result<(), PlanError> {
+    if let Some(change) = plan
+        .changes
+        .iter()
+        .find(|c| !zones.iter().any(|z| z.id == c.zone_id))
+    {
+        return Err(PlanError::UnknownZone(change.zone_id.clone()));
+    }
+
+    let mut batches = Vec::new();
+
+    for zone in zones {
+        let changes: Vec<&PlannedChange> = plan
+            .changes
+            .iter()
+            .filter(|c| c.zone_id == zone.id)
+            .collect();
+
+        if changes.is_empty() {
+            continue;
+        }
+
+        let records = zone.client.get_dns_records().await?.result;
+        let mut batch = BatchDNSRecordsRequest::default();
+        let mut touched = Vec::new();
+
+        for change in &changes {
+            match change.action {
+                PlanAction::Create => {
+                    if records.iter().any(|r| {
+                        r.r#type == change.r#type && r.name.eq_ignore_ascii_case(&change.name)
+                    }) {
+                        return Err(PlanError::Changed(change.name.clone()));
+                    }
+
+                    batch.posts.push(CreateDNSRecordRequest {
+                        content: change.desired.content.clone(),
+                        name: change.name.clone(),
+                        proxied: change.desired.proxied,
+                        r#type: change.r#type.clone(),
+                        comment: None,
+                        tags: None,
+                        ttl: change.desired.ttl,
+                        priority: None,
+                    });
+                }
+                PlanAction::Update | PlanAction::NoOp => {
+                    let record = records
+                        .iter()
+                        .find(|r| Some(&r.id) == change.record_id.as_ref())
+                        .filter(|r| Some(r.modified_on) == change.modified_on);
+
+                    let record = match record {
+                        Some(record) => record,
+                        None => return Err(PlanError::Changed(change.name.clone())),
+                    };
+
+                    if change.action == PlanAction::Update {
+                        touched.push(record.clone());
+                        batch.patches.push(change.desired.fix(record.clone()));
+                    }
+                }
+            }
+        }
+
+        batches.push((zone, changes, batch, touched));
+    }
+
+    for (zone, changes, batch, touched) in batches {
+        if batch.is_empty() {
+            debug!("Nothing to change in zone {}", zone.id);
+        } else {
+            zone.backup(
+                &format!("Plan of {}", plan.created_on.to_rfc3339()),
+                &touched,
+            );
+            zone.client.batch_dns_records(batch).await?;
+            info!("Applied the changes to zone {}", zone.id);
+        }
+
+        let mut applied: Vec<IpAddr> = Vec::new();
+        for change in changes {
+            if let Ok(ip) = change.desired.content.parse::<IpAddr>() {
+                if !applied.contains(&ip) {
+                    state.record_applied(&zone.id, &zone.selectors, ip);
+                    applied.push(ip);
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use httpmock::prelude::*;
+
+    use super::*;
+    use crate::cloudflare::{
+        client::CloudFlareClient,
+        models::{DNSRecord, ResultInfo, SuccessResponseList},
+    };
+
+    fn ip(last: u8) -> IpAddr {
+        IpAddr::V4(Ipv4Addr::new(1, 2, 3, last))
+    }
+
+    fn records_response(records: Vec<DNSRecord>) -> String {
+        let response = SuccessResponseList {
+            success: true,
+            result_info: ResultInfo {
+                count: records.len() as i32,
+                page: 1,
+                per_page: 500,
+                total_count: records.len() as i32,
+            },
+            result: records,
+            ..Default::default()
+        };
+
+        serde_json::to_string(&response).unwrap()
+    }
+
+    fn record(id: &str, name: &str, content: &str) -> DNSRecord {
+        DNSRecord {
+            id: String::from(id),
+            name: String::from(name),
+            content: String::from(content),
+            ..Default::default()
+        }
+    }
+
+    fn zone(server: &MockServer, selectors: Vec<RecordSelector>) -> Zone {
+        Zone {
+            id: String::from("1234"),
+            name: None,
+            client: CloudFlareClient::new_with_url("", "1234", &server.url("")),
+            selectors,
+            proxied: None,
+            ttl: None,
+            journal: None,
+        }
+    }
+
+    fn name(name: &str) -> RecordSelector {
+        RecordSelector::Name(String::from(name))
+    }
+
+    #[tokio::test]
+    async fn plan_lists_every_action() {
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.method(GET).path_contains("/dns_records");
+            then.status(200)
+                .header("content-type", "application/json")
+                .body(records_response(vec![
+                    record("a", "home.example.com", "1.2.3.1"),
+                    record("b", "lab.example.com", "1.2.3.4"),
+                ]));
+        });
+
+        let zone = zone(
+            &server,
+            vec![
+                name("home.example.com"),
+                name("lab.example.com"),
+                name("new.example.com"),
+            ],
+        );
+
+        let changes = plan_zone(&zone, &[], ip(4)).await.unwrap();
+        let actions: Vec<(PlanAction, &str)> = changes
+            .iter()
+            .map(|c| (c.action, c.name.as_str()))
+            .collect();
+
+        assert_eq!(
+            actions,
+            vec![
+                (PlanAction::Update, "home.example.com"),
+                (PlanAction::NoOp, "lab.example.com"),
+                (PlanAction::Create, "new.example.com"),
+            ]
+        );
+        assert_eq!(changes[0].summary(), "content 1.2.3.1 -> 1.2.3.4");
+    }
+
+    #[tokio::test]
+    async fn apply_refuses_a_changed_record() {
+        let server = MockServer::start();
+        let mut changed = record("a", "home.example.com", "1.2.3.1");
+        changed.modified_on = DateTime::from_timestamp(1000, 0).unwrap();
+        server.mock(|when, then| {
+            when.method(GET).path_contains("/dns_records");
+            then.status(200)
+                .header("content-type", "application/json")
+                .body(records_response(vec![changed]));
+        });
+        let batch_mock = server.mock(|when, then| {
+            when.method(POST).path_contains("/batch");
+            then.status(200);
+        });
+
+        let zone = zone(&server, vec![name("home.example.com")]);
+        let plan = Plan {
+            created_on: Utc::now(),
+            changes: vec![PlannedChange {
+                zone_id: String::from("1234"),
+                action: PlanAction::Update,
+                r#type: DNSType::A,
+                name: String::from("home.example.com"),
+                record_id: Some(String::from("a")),
+                modified_on: Some(DateTime::from_timestamp(0, 0).unwrap()),
+                desired: zone.desired(ip(4)),
+                drift: vec![],
+            }],
+        };
+
+        let state_path =
+            std::env::temp_dir().join(format!("cfdpip-plan-state-{}.json", std::process::id()));
+        let state = StateFile::open(&state_path).unwrap();
+
+        let result = apply_plan(&plan, &[zone], &state).await;
+        let _ = std::fs::remove_file(&state_path);
+
+        assert!(matches!(result, Err(PlanError::Changed(_))));
+        batch_mock.assert_hits(0);
+    }
+
+    #[tokio::test]
+    async fn apply_sends_one_batch() {
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.method(GET).path_contains("/dns_records");
+            then.status(200)
+                .header("content-type", "application/json")
+                .body(records_response(vec![record(
+                    "a",
+                    "home.example.com",
+                    "1.2.3.1",
+                )]));
+        });
+        let batch_mock = server.mock(|when, then| {
+            when.method(POST)
+                .path("/client/v4/zones/1234/dns_records/batch")
+                .json_body_partial(
+                    r#"{
+                        "patches": [{ "id": "a", "content": "1.2.3.4" }],
+                        "posts": [{ "name": "new.example.com", "content": "1.2.3.4" }]
+                    }"#,
+                );
+            then.status(200)
+                .header("content-type", "application/json")
+                .body(r#"{ "success": true, "errors": [], "messages": [], "result": {} }"#);
+        });
+
+        let zone = zone(
+            &server,
+            vec![name("home.example.com"), name("new.example.com")],
+        );
+        let plan = Plan {
+            created_on: Utc::now(),
+            changes: plan_zone(&zone, &[], ip(4)).await.unwrap(),
+        };
+
+        let state_path =
+            std::env::temp_dir().join(format!("cfdpip-apply-state-{}.json", std::process::id()));
+        let state = StateFile::open(&state_path).unwrap();
+
+        apply_plan(&plan, &[zone], &state).await.unwrap();
+        std::fs::remove_file(&state_path).unwrap();
+
+        batch_mock.assert();
+        assert_eq!(
+            state.last_applied(
+                "1234",
+                &[name("home.example.com"), name("new.example.com")],
+                IpFamily::Ipv4
+            ),
+            Some(ip(4))
+        );
+    }
+
+    #[test]
+    fn plan_file_round_trip() {
+        let plan = Plan {
+            created_on: Utc::now(),
+            changes: vec![],
+        };
+
+        let path = std::env::temp_dir().join(format!("cfdpip-plan-{}.json", std::process::id()));
+        plan.save(&path).unwrap();
+        let loaded = Plan::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(loaded, plan);
+    }
+}
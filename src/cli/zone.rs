@@ -0,0 +1,650 @@
+use core::fmt;
+use std::net::IpAddr;
+
+use log::{debug, error, info, trace, warn};
+use tokio::sync::mpsc;
+
+use crate::{
+    cloudflare::{
+        bind,
+        client::CloudFlareClient,
+        drift::{DesiredRecord, Drift},
+        models::{
+            BatchDNSRecordsRequest, CloudFlareClientError, CreateDNSRecordRequest, DNSRecord,
+            UpdateDNSRecordRequest,
+        },
+        selector::{self, RecordSelector},
+    },
+    config::{Config, ZoneConfig},
+    ip::IpFamily,
+    journal::{Journal, JournalEntry},
+    retry::RetryPolicy,
+    state::StateFile,
+};
+
+/// A Cloudflare zone and the selectors of the records managed in it
+pub(super) struct Zone {
+    pub(super) id: String,
+    /// Domain name of the zones configured by name
+    pub(super) name: Option<String>,
+    pub(super) client: CloudFlareClient,
+    pub(super) selectors: Vec<RecordSelector>,
+    pub(super) proxied: Option<bool>,
+    pub(super) ttl: Option<i32>,
+    /// Where the records are saved before being changed, none in dry run mode
+    pub(super) journal: Option<Journal>,
+}
+
+impl Zone {
+    /// What the managed records should look like when using `ip`
+    pub(super) fn desired(&self, ip: IpAddr) -> DesiredRecord {
+        DesiredRecord {
+            content: ip.to_string(),
+            proxied: self.proxied,
+            ttl: self.ttl,
+        }
+    }
+
+    /// Save the records about to change to the journal. A failure is only
+    /// logged, keeping the records up to date matters more.
+    pub(super) fn backup(&self, description: &str, records: &[DNSRecord]) {
+        let journal = match (&self.journal, records.is_empty()) {
+            (Some(journal), false) => journal,
+            _ => return,
+        };
+
+        match journal.record(&self.id, description, records) {
+            Ok(change_id) => info!(
+                "Saved {} records of zone {} as change {}",
+                records.len(),
+                self.id,
+                change_id
+            ),
+            Err(e) => error!("Could not save the records before changing them: {}", e),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(super) enum ZoneError {
+    /// No zone of the account has the configured name
+    NotFound(String),
+    Lookup(String, CloudFlareClientError),
+}
+
+impl fmt::Display for ZoneError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ZoneError::NotFound(name) => write!(f, "Zone {} not found in the account", name),
+            ZoneError::Lookup(name, e) => write!(f, "Could not look up zone {}: {}", name, e),
+        }
+    }
+}
+
+impl std::error::Error for ZoneError {}
+
+impl ZoneError {
+    /// Name of the zone that could not be looked up
+    pub(super) fn zone(&self) -> &str {
+        match self {
+            ZoneError::NotFound(name) | ZoneError::Lookup(name, _) => name,
+        }
+    }
+}
+
+/// Expects a validated config. The zones configured by name are looked up
+/// the first time only, their id is kept in the state file.
+pub(super) async fn build_zones(
+    config: &Config,
+    state: &StateFile,
+) -> Result<Vec<Zone>, ZoneError> {
+    let (zones, failed) = build_available_zones(config, state).await?;
+
+    match failed.into_iter().next() {
+        Some(e) => Err(e),
+        None => Ok(zones),
+    }
+}
+
+/// Like `build_zones`, but the zones whose lookup failed are left out and
+/// their errors returned with the other zones. A zone missing from the
+/// account is still an error, looking it up again would not help.
+pub(super) async fn build_available_zones(
+    config: &Config,
+    state: &StateFile,
+) -> Result<(Vec<Zone>, Vec<ZoneError>), ZoneError> {
+    trace!("Building CloudFlareClients");
+    build_zones_with(config, state, &account_client(config)).await
+}
+
+/// Every zone shares the connections and the request budget of the token
+pub(super) fn account_client(config: &Config) -> CloudFlareClient {
+    let cloudflare_token = config.cloudflare.token.clone().unwrap_or_default();
+
+    CloudFlareClient::new(&cloudflare_token, "").with_dry_run(config.dry_run)
+}
+
+/// Look up the zones configured by `names` again, waiting longer after each
+/// failed round, and send them to `found` once known. A zone missing from
+/// the account is given up.
+pub(super) async fn retry_lookups(
+    config: Config,
+    account: CloudFlareClient,
+    mut names: Vec<String>,
+    retry: RetryPolicy,
+    found: mpsc::Sender<Zone>,
+) {
+    let mut attempts = 0;
+
+    while !names.is_empty() {
+        attempts += 1;
+        let delay = retry.delay(attempts);
+        info!("Looking up {} zones again in {:?}", names.len(), delay);
+        tokio::time::sleep(delay).await;
+
+        let mut failed = Vec::new();
+        for name in names {
+            match account.find_zone(&name).await {
+                Ok(Some(details)) => {
+                    info!("Found zone {} with id {}", name, details.id);
+
+                    let zone = config
+                        .zones
+                        .iter()
+                        .find(|zone| zone.name.as_deref() == Some(name.as_str()))
+                        .expect("Zone looked up without its config");
+
+                    if found
+                        .send(new_zone(&config, zone, details.id, &account))
+                        .await
+                        .is_err()
+                    {
+                        return;
+                    }
+                }
+                Ok(None) => error!("{}", ZoneError::NotFound(name)),
+                Err(e) => {
+                    warn!("{}", ZoneError::Lookup(name.clone(), e));
+                    failed.push(name);
+                }
+            }
+        }
+        names = failed;
+    }
+}
+
+fn new_zone(config: &Config, zone: &ZoneConfig, id: String, account: &CloudFlareClient) -> Zone {
+    Zone {
+        client: account.for_zone(&id),
+        id,
+        name: zone.name.clone(),
+        selectors: zone.records.clone(),
+        proxied: zone.proxied,
+        ttl: zone.ttl,
+        journal: match config.journal.enabled && !config.dry_run {
+            true => Some(
+                Journal::new(&config.journal_path()).with_max_entries(config.journal.max_entries),
+            ),
+            false => None,
+        },
+    }
+}
+
+async fn build_zones_with(
+    config: &Config,
+    state: &StateFile,
+    account: &CloudFlareClient,
+) -> Result<(Vec<Zone>, Vec<ZoneError>), ZoneError> {
+    let mut zones = Vec::new();
+    let mut failed = Vec::new();
+
+    for zone in &config.zones {
+        let id = match zone.name {
+            Some(ref name) => match zone_id(account, state, name).await {
+                Ok(id) => id,
+                Err(e @ ZoneError::Lookup(..)) => {
+                    failed.push(e);
+                    continue;
+                }
+                Err(e) => return Err(e),
+            },
+            None => zone.id.clone(),
+        };
+
+        zones.push(new_zone(config, zone, id, account));
+    }
+
+    Ok((zones, failed))
+}
+
+/// Id of the zone with the given domain name, from the state file when known
+async fn zone_id(
+    client: &CloudFlareClient,
+    state: &StateFile,
+    name: &str,
+) -> Result<String, ZoneError> {
+    if let Some(id) = state.zone_id(name) {
+        debug!("Zone {} has id {}", name, id);
+        return Ok(id);
+    }
+
+    match client.find_zone(name).await {
+        Ok(Some(zone)) => {
+            info!("Found zone {} with id {}", name, zone.id);
+            state.record_zone_id(name, &zone.id);
+            Ok(zone.id)
+        }
+        Ok(None) => Err(ZoneError::NotFound(String::from(name))),
+        Err(e) => Err(ZoneError::Lookup(String::from(name), e)),
+    }
+}
+
+/// Records of the given family managed in the zone. Without selectors, the
+/// records are the ones whose content is `ip`.
+pub(super) async fn managed_records(
+    zone: &Zone,
+    family: IpFamily,
+    ip: IpAddr,
+) -> Result<Vec<DNSRecord>, CloudFlareClientError> {
+    let record_type = family.record_type();
+
+    let records = if zone.selectors.is_empty() {
+        match zone
+            .client
+            .get_dns_records_with_content(&ip.to_string())
+            .await
+        {
+            Ok(r) => r.result,
+            Err(e) => return Err(e),
+        }
+    } else {
+        match zone.client.get_dns_records().await {
+            Ok(r) => r
+                .result
+                .into_iter()
+                .filter(|r| selector::is_selected(&zone.selectors, r))
+                .collect(),
+            Err(e) => return Err(e),
+        }
+    };
+
+    Ok(records
+        .into_iter()
+        .filter(|r| r.r#type == record_type)
+        .collect())
+}
+
+/// Records to point to `new_ip`. Without selectors, the records using any
+/// of the stale IPs.
+pub(super) async fn stale_records(
+    zone: &Zone,
+    stale_ips: &[IpAddr],
+    new_ip: IpAddr,
+) -> Result<Vec<DNSRecord>, CloudFlareClientError> {
+    let family = IpFamily::of(&new_ip);
+    let mut records: Vec<DNSRecord> = Vec::new();
+
+    if !zone.selectors.is_empty() {
+        // selected records do not depend on their content
+        records = managed_records(zone, family, new_ip).await?;
+    } else {
+        for stale_ip in stale_ips {
+            for record in managed_records(zone, family, *stale_ip).await? {
+                if !records.iter().any(|r| r.id == record.id) {
+                    records.push(record);
+                }
+            }
+        }
+    }
+
+    Ok(records)
+}
+
+/// Point the managed records to `new_ip`, returns the records now using it.
+/// Without selectors, the records using any of the stale IPs are updated.
+pub(super) async fn update_ip(
+    zone: &Zone,
+    stale_ips: &[IpAddr],
+    new_ip: IpAddr,
+) -> Result<Vec<DNSRecord>, CloudFlareClientError> {
+    let records = stale_records(zone, stale_ips, new_ip).await?;
+    debug!("Found {} records to update", records.len());
+
+    let desired = zone.desired(new_ip);
+
+    let (records, up_to_date): (Vec<DNSRecord>, Vec<DNSRecord>) = records
+        .into_iter()
+        .partition(|r| !desired.drift(r).is_empty());
+
+    for record in &up_to_date {
+        debug!("Record {} is already up to date", record.name);
+    }
+
+    zone.backup(&format!("Update to {}", new_ip), &records);
+
+    let mut updated = up_to_date;
+
+    for record in records {
+        let record_name = record.name.clone();
+
+        debug!("Updating record {}", record_name);
+
+        if let Err(e) = zone
+            .client
+            .set_dns_record(desired.fix(record.clone()))
+            .await
+        {
+            error!("Failed to update record {}", record_name);
+            return Err(e);
+        }
+
+        info!("Successfully updated record {}", record_name);
+        updated.push(record);
+    }
+
+    Ok(updated)
+}
+
+/// Managed records of the family of `ip` that differ from the desired state
+pub(super) async fn find_drift(
+    zone: &Zone,
+    ip: IpAddr,
+) -> Result<Vec<(DNSRecord, Vec<Drift>)>, CloudFlareClientError> {
+    let desired = zone.desired(ip);
+
+    Ok(managed_records(zone, IpFamily::of(&ip), ip)
+        .await?
+        .into_iter()
+        .map(|record| {
+            let drift = desired.drift(&record);
+            (record, drift)
+        })
+        .filter(|(_, drift)| !drift.is_empty())
+        .collect())
+}
+
+/// Put the records of the change back as they were before it. The records
+/// deleted since are skipped.
+pub(super) async fn rollback(
+    zone: &Zone,
+    entry: &JournalEntry,
+) -> Result<usize, CloudFlareClientError> {
+    let current = zone.client.get_dns_records().await?.result;
+
+    let mut touched = Vec::new();
+    let mut batch = BatchDNSRecordsRequest::default();
+
+    for record in &entry.records {
+        match current.iter().find(|r| r.id == record.id) {
+            Some(current) => {
+                touched.push(current.clone());
+                batch
+                    .patches
+                    .push(UpdateDNSRecordRequest::from(record.clone()));
+            }
+            None => warn!("Record {} no longer exists, skipping it", record.name),
+        }
+    }
+
+    if batch.is_empty() {
+        return Ok(0);
+    }
+
+    // the rollback can be rolled back too
+    zone.backup(&format!("Rollback of {}", entry.change_id), &touched);
+
+    zone.client.batch_dns_records(batch).await?;
+
+    Ok(touched.len())
+}
+
+/// Create and update the records of the zone to match the imported ones,
+/// returns the number of records created and updated
+pub(super) async fn import(
+    zone: &Zone,
+    records: &[CreateDNSRecordRequest],
+) -> Result<(usize, usize), CloudFlareClientError> {
+    let current = zone.client.get_dns_records().await?.result;
+
+    let batch = bind::import_changes(&current, records);
+    let counts = (batch.posts.len(), batch.patches.len());
+
+    if batch.is_empty() {
+        return Ok(counts);
+    }
+
+    let touched: Vec<DNSRecord> = current
+        .into_iter()
+        .filter(|r| batch.patches.iter().any(|p| p.id == r.id))
+        .collect();
+    zone.backup("Import", &touched);
+
+    zone.client.batch_dns_records(batch).await?;
+
+    Ok(counts)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use chrono::Utc;
+    use httpmock::prelude::*;
+
+    use super::*;
+    use crate::cloudflare::models::{ResultInfo, SuccessResponseList};
+
+    fn record(id: &str, content: &str, ttl: i32) -> DNSRecord {
+        DNSRecord {
+            id: String::from(id),
+            name: format!("{}.example.com", id),
+            content: String::from(content),
+            ttl: Some(ttl),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn rollback_restores_the_saved_records() {
+        let current = SuccessResponseList {
+            success: true,
+            result_info: ResultInfo {
+                count: 1,
+                page: 1,
+                per_page: 500,
+                total_count: 1,
+            },
+            result: vec![record("a", "1.2.3.4", 1)],
+            ..Default::default()
+        };
+
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.method(GET).path_contains("/dns_records");
+            then.status(200)
+                .header("content-type", "application/json")
+                .body(serde_json::to_string(&current).unwrap());
+        });
+        let batch_mock = server.mock(|when, then| {
+            when.method(POST)
+                .path("/client/v4/zones/1234/dns_records/batch")
+                .json_body_partial(
+                    r#"{ "patches": [{ "id": "a", "content": "1.2.3.1", "ttl": 300 }] }"#,
+                );
+            then.status(200)
+                .header("content-type", "application/json")
+                .body(r#"{ "success": true, "errors": [], "messages": [], "result": {} }"#);
+        });
+
+        let journal_path =
+            std::env::temp_dir().join(format!("cfdpip-rollback-{}.jsonl", std::process::id()));
+        let zone = Zone {
+            id: String::from("1234"),
+            name: None,
+            client: CloudFlareClient::new_with_url("", "1234", &server.url("")),
+            selectors: vec![],
+            proxied: None,
+            ttl: None,
+            journal: Some(Journal::new(&journal_path)),
+        };
+
+        // the second record was deleted since the change
+        let entry = JournalEntry {
+            change_id: String::from("20260101000000-abcd"),
+            created_on: Utc::now(),
+            zone_id: String::from("1234"),
+            description: String::from("Update to 1.2.3.4"),
+            records: vec![record("a", "1.2.3.1", 300), record("b", "1.2.3.1", 300)],
+        };
+
+        let restored = rollback(&zone, &entry).await.unwrap();
+        let entries = zone.journal.as_ref().unwrap().entries().unwrap();
+        std::fs::remove_file(&journal_path).unwrap();
+
+        assert_eq!(restored, 1);
+        batch_mock.assert();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].records[0].content, "1.2.3.4");
+    }
+
+    #[tokio::test]
+    async fn zone_id_is_looked_up_once() {
+        let server = MockServer::start();
+        let zones_mock = server.mock(|when, then| {
+            when.method(GET)
+                .path("/client/v4/zones")
+                .query_param("name", "example.com");
+            then.status(200)
+                .header("content-type", "application/json")
+                .body(r#"{ "success": true, "errors": [], "messages": [], "result_info": { "count": 1, "page": 1, "per_page": 20, "total_count": 1 }, "result": [{ "id": "5678", "name": "example.com", "status": "active" }] }"#);
+        });
+
+        let path =
+            std::env::temp_dir().join(format!("cfdpip-zone-ids-state-{}.json", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let state = StateFile::open(&path).unwrap();
+        let client = CloudFlareClient::new_with_url("", "", &server.url(""));
+
+        let first = zone_id(&client, &state, "example.com").await.unwrap();
+        let second = zone_id(&client, &state, "example.com").await.unwrap();
+        let missing = zone_id(&client, &state, "example.org").await;
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(first, "5678");
+        assert_eq!(second, "5678");
+        zones_mock.assert_hits(1);
+        assert!(missing.is_err());
+    }
+
+    #[tokio::test]
+    async fn failed_lookup_leaves_the_zone_out() {
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.method(GET)
+                .path("/client/v4/zones")
+                .query_param("name", "example.com");
+            then.status(503).body("{}");
+        });
+        server.mock(|when, then| {
+            when.method(GET)
+                .path("/client/v4/zones")
+                .query_param("name", "example.org");
+            then.status(200)
+                .header("content-type", "application/json")
+                .body(r#"{ "success": true, "errors": [], "messages": [], "result_info": { "count": 0, "page": 1, "per_page": 20, "total_count": 0 }, "result": [] }"#);
+        });
+
+        let path = std::env::temp_dir().join(format!(
+            "cfdpip-zone-lookup-state-{}.json",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        let state = StateFile::open(&path).unwrap();
+        let account = CloudFlareClient::new_with_url("", "", &server.url(""));
+
+        let mut config = Config {
+            zones: vec![
+                ZoneConfig {
+                    id: String::from("1234"),
+                    ..ZoneConfig::default()
+                },
+                ZoneConfig {
+                    name: Some(String::from("example.com")),
+                    ..ZoneConfig::default()
+                },
+            ],
+            ..Config::default()
+        };
+        let (zones, failed) = build_zones_with(&config, &state, &account).await.unwrap();
+
+        config.zones.push(ZoneConfig {
+            name: Some(String::from("example.org")),
+            ..ZoneConfig::default()
+        });
+        let missing = build_zones_with(&config, &state, &account).await;
+        // nothing was found to be saved
+        assert!(!path.exists());
+
+        assert_eq!(zones.len(), 1);
+        assert_eq!(zones[0].id, "1234");
+        assert!(matches!(failed.as_slice(), [ZoneError::Lookup(name, _)] if name == "example.com"));
+        assert!(matches!(missing, Err(ZoneError::NotFound(_))));
+    }
+
+    #[tokio::test]
+    async fn retried_lookup_sends_the_found_zones() {
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.method(GET)
+                .path("/client/v4/zones")
+                .query_param("name", "example.com");
+            then.status(200)
+                .header("content-type", "application/json")
+                .body(r#"{ "success": true, "errors": [], "messages": [], "result_info": { "count": 1, "page": 1, "per_page": 20, "total_count": 1 }, "result": [{ "id": "5678", "name": "example.com", "status": "active" }] }"#);
+        });
+        server.mock(|when, then| {
+            when.method(GET)
+                .path("/client/v4/zones")
+                .query_param("name", "example.org");
+            then.status(200)
+                .header("content-type", "application/json")
+                .body(r#"{ "success": true, "errors": [], "messages": [], "result_info": { "count": 0, "page": 1, "per_page": 20, "total_count": 0 }, "result": [] }"#);
+        });
+
+        let config = Config {
+            zones: vec![
+                ZoneConfig {
+                    name: Some(String::from("example.com")),
+                    ..ZoneConfig::default()
+                },
+                ZoneConfig {
+                    name: Some(String::from("example.org")),
+                    ..ZoneConfig::default()
+                },
+            ],
+            ..Config::default()
+        };
+        let retry = RetryPolicy {
+            max_attempts: None,
+            base_delay: Duration::from_millis(10),
+            max_delay: Duration::from_millis(10),
+            jitter: false,
+        };
+        let (found_tx, mut found) = mpsc::channel(2);
+
+        retry_lookups(
+            config,
+            CloudFlareClient::new_with_url("", "", &server.url("")),
+            vec![String::from("example.com"), String::from("example.org")],
+            retry,
+            found_tx,
+        )
+        .await;
+
+        let zone = found.recv().await.unwrap();
+        assert_eq!(zone.id, "5678");
+        assert_eq!(zone.name.as_deref(), Some("example.com"));
+        // a zone missing from the account is given up
+        assert!(found.recv().await.is_none());
+    }
+}
@@ -0,0 +1,652 @@
+use core::fmt;
+use std::str::FromStr;
+
+use super::models::{
+    BatchDNSRecordsRequest, CreateDNSRecordRequest, DNSRecord, DNSType, UpdateDNSRecordRequest,
+};
+
+/// TTL written for the records using the automatic TTL of Cloudflare
+const AUTOMATIC_TTL: i32 = 300;
+
+/// Longest character string of a TXT record
+const TXT_CHUNK_LEN: usize = 255;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct BindError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for BindError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for BindError {}
+
+/// Render the records as an RFC 1035 zone file, with absolute names. The
+/// records are sorted so that exports of the same zone can be diffed, and
+/// what a zone file cannot hold is kept in a comment read back by `parse`.
+pub fn render(records: &[DNSRecord]) -> String {
+    let mut records: Vec<&DNSRecord> = records.iter().collect();
+    records.sort_by(|a, b| {
+        (&a.name, a.r#type.to_string(), &a.content).cmp(&(
+            &b.name,
+            b.r#type.to_string(),
+            &b.content,
+        ))
+    });
+
+    let mut text = String::new();
+
+    for record in records {
+        let ttl = match record.ttl {
+            Some(1) | None => AUTOMATIC_TTL,
+            Some(ttl) => ttl,
+        };
+
+        let mut notes = vec![format!("proxied={}", record.proxied.unwrap_or(false))];
+        if matches!(record.ttl, Some(1) | None) {
+            notes.push(String::from("ttl=auto"));
+        }
+
+        text.push_str(&format!(
+            "{}\t{}\tIN\t{}\t{} ; {}\n",
+            fqdn(&record.name),
+            ttl,
+            record.r#type,
+            rdata(record),
+            notes.join(" ")
+        ));
+    }
+
+    text
+}
+
+fn rdata(record: &DNSRecord) -> String {
+    let priority = record.priority.unwrap_or(0);
+
+    match record.r#type {
+        DNSType::CNAME | DNSType::NS | DNSType::PTR => fqdn(&record.content),
+        DNSType::MX => format!("{} {}", priority, fqdn(&record.content)),
+        DNSType::SRV => {
+            let mut parts: Vec<String> = record
+                .content
+                .split_whitespace()
+                .map(String::from)
+                .collect();
+            if let Some(target) = parts.last_mut() {
+                *target = fqdn(target);
+            }
+            // the priority is not always part of the content
+            match parts.len() {
+                3 => format!("{} {}", priority, parts.join(" ")),
+                _ => parts.join(" "),
+            }
+        }
+        DNSType::URI => match record.content.split_whitespace().count() {
+            2 => format!("{} {}", priority, record.content),
+            _ => record.content.clone(),
+        },
+        DNSType::TXT => quote_txt(&record.content),
+        _ => record.content.clone(),
+    }
+}
+
+fn fqdn(name: &str) -> String {
+    match name.ends_with('.') {
+        true => String::from(name),
+        false => format!("{}.", name),
+    }
+}
+
+fn quote_txt(content: &str) -> String {
+    if content.starts_with('"') {
+        return String::from(content);
+    }
+
+    let chars: Vec<char> = content.chars().collect();
+    let chunks: Vec<String> = chars
+        .chunks(TXT_CHUNK_LEN)
+        .map(|chunk| {
+            let text: String = chunk.iter().collect();
+            format!("\"{}\"", text.replace('\\', "\\\\").replace('"', "\\\""))
+        })
+        .collect();
+
+    match chunks.is_empty() {
+        true => String::from("\"\""),
+        false => chunks.join(" "),
+    }
+}
+
+/// A record read from a zone file, with the line it starts on
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParsedRecord {
+    pub line: usize,
+    pub record: CreateDNSRecordRequest,
+}
+
+/// Parse an RFC 1035 zone file. Relative names are completed with `origin`
+/// until a `$ORIGIN` directive changes it. The SOA and apex NS records are
+/// managed by Cloudflare and skipped.
+pub fn parse(text: &str, origin: Option<&str>) -> Result<Vec<ParsedRecord>, BindError> {
+    let mut origin = origin.map(|o| String::from(o.trim_end_matches('.')));
+    let mut default_ttl: Option<i32> = None;
+    let mut last_owner: Option<String> = None;
+    let mut records = Vec::new();
+
+    for entry in entries(text)? {
+        let error = |message: String| BindError {
+            line: entry.line,
+            message,
+        };
+
+        let mut tokens = entry.tokens.iter().map(String::as_str).peekable();
+
+        let first = match tokens.peek() {
+            Some(first) => *first,
+            None => continue,
+        };
+
+        match first.to_uppercase().as_str() {
+            "$ORIGIN" => {
+                tokens.next();
+                match tokens.next() {
+                    Some(name) => origin = Some(String::from(name.trim_end_matches('.'))),
+                    None => return Err(error(String::from("$ORIGIN requires a name"))),
+                }
+                continue;
+            }
+            "$TTL" => {
+                tokens.next();
+                match tokens.next().map(parse_ttl) {
+                    Some(Some(ttl)) => default_ttl = Some(ttl),
+                    _ => return Err(error(String::from("$TTL requires a duration"))),
+                }
+                continue;
+            }
+            directive if directive.starts_with('$') => {
+                return Err(error(format!("unsupported directive {}", directive)))
+            }
+            _ => {}
+        }
+
+        let owner = match entry.continued {
+            true => match last_owner {
+                Some(ref owner) => owner.clone(),
+                None => return Err(error(String::from("record without a name"))),
+            },
+            false => {
+                let name = tokens.next().unwrap_or_default();
+                absolute_name(name, origin.as_deref())
+            }
+        };
+        last_owner = Some(owner.clone());
+
+        // the TTL and class are optional and come in any order
+        let mut ttl = default_ttl;
+        let mut record_type = None;
+        for token in tokens.by_ref() {
+            if token.eq_ignore_ascii_case("IN") {
+                continue;
+            }
+            if let Some(value) = parse_ttl(token) {
+                ttl = Some(value);
+                continue;
+            }
+            record_type = Some(token);
+            break;
+        }
+
+        let record_type = match record_type {
+            Some(record_type) => record_type,
+            None => return Err(error(String::from("missing record type"))),
+        };
+
+        if record_type.eq_ignore_ascii_case("SOA")
+            || (record_type.eq_ignore_ascii_case("NS") && Some(owner.as_str()) == origin.as_deref())
+        {
+            continue;
+        }
+
+        let r#type = DNSType::from_str(record_type).map_err(error)?;
+        let rdata: Vec<&str> = tokens.collect();
+
+        if rdata.is_empty() {
+            return Err(error(format!("missing {} data", r#type)));
+        }
+
+        let (priority, content) = match r#type {
+            DNSType::MX | DNSType::SRV | DNSType::URI => match rdata[0].parse::<u16>() {
+                Ok(priority) => (Some(priority), rdata[1..].to_vec()),
+                Err(_) => return Err(error(format!("invalid {} priority {}", r#type, rdata[0]))),
+            },
+            _ => (None, rdata),
+        };
+
+        let content = match r#type {
+            DNSType::CNAME | DNSType::NS | DNSType::PTR | DNSType::MX => {
+                absolute_name(&content.join(" "), origin.as_deref())
+            }
+            DNSType::SRV => {
+                let mut parts: Vec<String> = content.iter().map(|p| String::from(*p)).collect();
+                if let Some(target) = parts.last_mut() {
+                    *target = absolute_name(target, origin.as_deref());
+                }
+                parts.join(" ")
+            }
+            _ => content.join(" "),
+        };
+
+        let proxied = entry
+            .comment
+            .split_whitespace()
+            .find_map(|note| match note {
+                "proxied=true" => Some(true),
+                "proxied=false" => Some(false),
+                _ => None,
+            });
+        if entry
+            .comment
+            .split_whitespace()
+            .any(|note| note == "ttl=auto")
+        {
+            ttl = Some(1);
+        }
+
+        records.push(ParsedRecord {
+            line: entry.line,
+            record: CreateDNSRecordRequest {
+                content,
+                name: owner,
+                proxied,
+                r#type,
+                comment: None,
+                tags: None,
+                ttl,
+                priority,
+            },
+        });
+    }
+
+    Ok(records)
+}
+
+/// A record spread over one or more lines, split into tokens
+struct Entry {
+    line: usize,
+    /// Starts with a blank, the owner is the one of the previous record
+    continued: bool,
+    tokens: Vec<String>,
+    comment: String,
+}
+
+fn entries(text: &str) -> Result<Vec<Entry>, BindError> {
+    let mut entries = Vec::new();
+    let mut current: Option<Entry> = None;
+    let mut depth = 0;
+
+    for (i, line) in text.lines().enumerate() {
+        let entry = match current.take() {
+            Some(entry) => entry,
+            None => Entry {
+                line: i + 1,
+                continued: line.starts_with([' ', '\t']),
+                tokens: vec![],
+                comment: String::new(),
+            },
+        };
+        let mut entry = entry;
+
+        let mut token = String::new();
+        let mut quoted = false;
+        let mut escaped = false;
+        let mut chars = line.chars();
+
+        while let Some(c) = chars.next() {
+            if quoted {
+                token.push(c);
+                match (escaped, c) {
+                    (true, _) => escaped = false,
+                    (false, '\\') => escaped = true,
+                    (false, '"') => quoted = false,
+                    _ => {}
+                }
+                continue;
+            }
+
+            match c {
+                '"' => {
+                    quoted = true;
+                    token.push(c);
+                }
+                ';' => {
+                    entry.comment.push_str(chars.as_str());
+                    entry.comment.push(' ');
+                    break;
+                }
+                '(' => depth += 1,
+                ')' => depth -= 1,
+                c if c.is_whitespace() => {
+                    if !token.is_empty() {
+                        entry.tokens.push(std::mem::take(&mut token));
+                    }
+                }
+                c => token.push(c),
+            }
+        }
+
+        if quoted {
+            return Err(BindError {
+                line: i + 1,
+                message: String::from("unterminated quoted string"),
+            });
+        }
+        if !token.is_empty() {
+            entry.tokens.push(token);
+        }
+
+        match depth {
+            0 => {
+                if !entry.tokens.is_empty() {
+                    entries.push(entry);
+                }
+            }
+            d if d > 0 => current = Some(entry),
+            _ => {
+                return Err(BindError {
+                    line: i + 1,
+                    message: String::from("unbalanced parentheses"),
+                })
+            }
+        }
+    }
+
+    match current {
+        Some(entry) => Err(BindError {
+            line: entry.line,
+            message: String::from("unbalanced parentheses"),
+        }),
+        None => Ok(entries),
+    }
+}
+
+/// Name without the trailing dot, completed with the origin when relative
+fn absolute_name(name: &str, origin: Option<&str>) -> String {
+    match (name, origin) {
+        ("@", Some(origin)) => String::from(origin),
+        (name, _) if name.ends_with('.') => String::from(name.trim_end_matches('.')),
+        (name, Some(origin)) => format!("{}.{}", name, origin),
+        (name, None) => String::from(name),
+    }
+}
+
+/// TTL in seconds, or with the `s`, `m`, `h`, `d` and `w` units
+fn parse_ttl(token: &str) -> Option<i32> {
+    if !token.starts_with(|c: char| c.is_ascii_digit()) {
+        return None;
+    }
+
+    let mut total: i32 = 0;
+    let mut number = String::new();
+
+    for c in token.chars() {
+        if c.is_ascii_digit() {
+            number.push(c);
+            continue;
+        }
+
+        let unit = match c.to_ascii_lowercase() {
+            's' => 1,
+            'm' => 60,
+            'h' => 3600,
+            'd' => 86400,
+            'w' => 604800,
+            _ => return None,
+        };
+        total = total.checked_add(number.parse::<i32>().ok()?.checked_mul(unit)?)?;
+        number.clear();
+    }
+
+    if !number.is_empty() {
+        total = total.checked_add(number.parse().ok()?)?;
+    }
+
+    Some(total)
+}
+
+/// Changes making the zone hold the imported records. A record with the same
+/// name, type and content is updated when its TTL or proxy status differs, the
+/// only record of a name and type is updated when it is the only one imported
+/// too, and the others are created. No record is deleted.
+pub fn import_changes(
+    existing: &[DNSRecord],
+    imported: &[CreateDNSRecordRequest],
+) -> BatchDNSRecordsRequest {
+    let mut batch = BatchDNSRecordsRequest::default();
+
+    let same_set =
+        |a: &str, t: &DNSType, b: &DNSRecord| b.name.eq_ignore_ascii_case(a) && b.r#type == *t;
+
+    for record in imported {
+        let current: Vec<&DNSRecord> = existing
+            .iter()
+            .filter(|e| same_set(&record.name, &record.r#type, e))
+            .collect();
+        let imported_count = imported
+            .iter()
+            .filter(|i| i.name.eq_ignore_ascii_case(&record.name) && i.r#type == record.r#type)
+            .count();
+
+        let exact = current
+            .iter()
+            .find(|e| e.content == record.content && e.priority == record.priority);
+
+        let target = match (exact, current.len(), imported_count) {
+            (Some(exact), _, _) => *exact,
+            (None, 1, 1) => current[0],
+            _ => {
+                batch.posts.push(record.clone());
+                continue;
+            }
+        };
+
+        let mut update = UpdateDNSRecordRequest::from(target.clone());
+        update.content = record.content.clone();
+        update.priority = record.priority;
+        if record.ttl.is_some() {
+            update.ttl = record.ttl;
+        }
+        if record.proxied.is_some() {
+            update.proxied = record.proxied;
+        }
+
+        if update != UpdateDNSRecordRequest::from(target.clone()) {
+            batch.patches.push(update);
+        }
+    }
+
+    batch
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(name: &str, r#type: DNSType, content: &str, ttl: i32) -> DNSRecord {
+        DNSRecord {
+            id: format!("{}-{}", name, r#type),
+            name: String::from(name),
+            r#type,
+            content: String::from(content),
+            ttl: Some(ttl),
+            proxied: Some(false),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn render_every_kind_of_record() {
+        let mut mx = record("example.com", DNSType::MX, "mail.example.com", 3600);
+        mx.priority = Some(10);
+        let mut www = record("www.example.com", DNSType::CNAME, "example.com", 1);
+        www.proxied = Some(true);
+
+        let text = render(&[
+            www,
+            record("example.com", DNSType::TXT, "v=spf1 -all", 300),
+            mx,
+            record("example.com", DNSType::A, "1.2.3.4", 1),
+        ]);
+
+        assert_eq!(
+            text,
+            "example.com.\t300\tIN\tA\t1.2.3.4 ; proxied=false ttl=auto\n\
+             example.com.\t3600\tIN\tMX\t10 mail.example.com. ; proxied=false\n\
+             example.com.\t300\tIN\tTXT\t\"v=spf1 -all\" ; proxied=false\n\
+             www.example.com.\t300\tIN\tCNAME\texample.com. ; proxied=true ttl=auto\n"
+        );
+    }
+
+    #[test]
+    fn long_txt_is_split() {
+        let content = "a".repeat(300);
+        let quoted = quote_txt(&content);
+
+        assert_eq!(
+            quoted,
+            format!("\"{}\" \"{}\"", "a".repeat(255), "a".repeat(45))
+        );
+    }
+
+    #[test]
+    fn render_then_parse() {
+        let mut mx = record("example.com", DNSType::MX, "mail.example.com", 3600);
+        mx.priority = Some(10);
+        let mut www = record("www.example.com", DNSType::CNAME, "example.com", 1);
+        www.proxied = Some(true);
+
+        let parsed = parse(&render(&[mx, www]), None).unwrap();
+
+        assert_eq!(parsed[0].record.name, "example.com");
+        assert_eq!(parsed[0].record.content, "mail.example.com");
+        assert_eq!(parsed[0].record.priority, Some(10));
+        assert_eq!(parsed[0].record.ttl, Some(3600));
+        assert_eq!(parsed[1].record.proxied, Some(true));
+        assert_eq!(parsed[1].record.ttl, Some(1));
+    }
+
+    #[test]
+    fn parse_zone_file_syntax() {
+        let text = r#"
+$ORIGIN example.com.
+$TTL 1h
+@       IN  SOA ns1.example.com. admin.example.com. (
+            2024010101 ; serial
+            7200 3600 1209600 300 )
+@       IN  NS  ns1.example.com.
+@           A   1.2.3.4
+            AAAA ::1
+home    300 IN  A   1.2.3.5
+_sip._tcp   SRV 10 5 5060 sip
+txt         TXT "a \" quote; not a comment" "second"
+"#;
+
+        let parsed = parse(text, None).unwrap();
+        let records: Vec<(&str, String, &str, Option<i32>)> = parsed
+            .iter()
+            .map(|p| {
+                (
+                    p.record.name.as_str(),
+                    p.record.r#type.to_string(),
+                    p.record.content.as_str(),
+                    p.record.ttl,
+                )
+            })
+            .collect();
+
+        assert_eq!(
+            records,
+            vec![
+                ("example.com", String::from("A"), "1.2.3.4", Some(3600)),
+                ("example.com", String::from("AAAA"), "::1", Some(3600)),
+                ("home.example.com", String::from("A"), "1.2.3.5", Some(300)),
+                (
+                    "_sip._tcp.example.com",
+                    String::from("SRV"),
+                    "5 5060 sip.example.com",
+                    Some(3600)
+                ),
+                (
+                    "txt.example.com",
+                    String::from("TXT"),
+                    r#""a \" quote; not a comment" "second""#,
+                    Some(3600)
+                ),
+            ]
+        );
+        assert_eq!(parsed[3].record.priority, Some(10));
+        assert_eq!(parsed[2].line, 10);
+    }
+
+    #[test]
+    fn parse_errors_point_at_the_line() {
+        let error = parse("example.com. 300 IN BOGUS data\n", None).unwrap_err();
+        assert_eq!(error.line, 1);
+
+        let error = parse("\nexample.com. 300 IN TXT \"open\n", None).unwrap_err();
+        assert_eq!(error.to_string(), "line 2: unterminated quoted string");
+
+        let error = parse("example.com. IN MX ten mail\n", None).unwrap_err();
+        assert_eq!(error.line, 1);
+    }
+
+    #[test]
+    fn parse_ttl_units() {
+        assert_eq!(parse_ttl("300"), Some(300));
+        assert_eq!(parse_ttl("1h30m"), Some(5400));
+        assert_eq!(parse_ttl("1W"), Some(604800));
+        assert_eq!(parse_ttl("IN"), None);
+        assert_eq!(parse_ttl("1x"), None);
+    }
+
+    #[test]
+    fn import_updates_creates_and_keeps() {
+        let existing = vec![
+            record("a.example.com", DNSType::A, "1.2.3.4", 300),
+            record("b.example.com", DNSType::A, "1.2.3.4", 300),
+            record("c.example.com", DNSType::A, "1.2.3.4", 300),
+        ];
+
+        let imported: Vec<CreateDNSRecordRequest> = parse(
+            "a.example.com. 300 A 1.2.3.4\n\
+             b.example.com. 300 A 1.2.3.5\n\
+             c.example.com. 60 A 1.2.3.4\n\
+             d.example.com. 300 A 1.2.3.4\n",
+            None,
+        )
+        .unwrap()
+        .into_iter()
+        .map(|p| p.record)
+        .collect();
+
+        let batch = import_changes(&existing, &imported);
+
+        let patched: Vec<(&str, &str, Option<i32>)> = batch
+            .patches
+            .iter()
+            .map(|p| (p.name.as_str(), p.content.as_str(), p.ttl))
+            .collect();
+        assert_eq!(
+            patched,
+            vec![
+                ("b.example.com", "1.2.3.5", Some(300)),
+                ("c.example.com", "1.2.3.4", Some(60)),
+            ]
+        );
+        assert_eq!(batch.posts.len(), 1);
+        assert_eq!(batch.posts[0].name, "d.example.com");
+    }
+}
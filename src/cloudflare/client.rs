@@ -1,16 +1,33 @@
 #![allow(dead_code)]
 
-use log::{debug, warn};
-use reqwest::{Request, StatusCode};
+use chrono::Utc;
+use futures::{stream, Stream, TryStreamExt};
+use log::{debug, info, warn};
+use reqwest::{Method, Request, StatusCode};
+use serde::de::DeserializeOwned;
 
-use super::models::*;
-use std::time::Duration;
+use super::{
+    models::*,
+    rate_limit::{parse_retry_after, RateLimiter},
+};
+use crate::retry::{is_retryable_status, RetryPolicy};
+use std::{sync::Arc, time::Duration};
+
+/// Number of records requested per page when listing
+const PER_PAGE: i32 = 500;
+
+/// Wait after a 429 without `Retry-After`
+const DEFAULT_RETRY_AFTER: Duration = Duration::from_secs(60);
 
 pub struct CloudFlareClient {
     client: reqwest::Client,
     token: String,
     zone_id: String,
     base_url: String,
+    rate_limiter: Arc<RateLimiter>,
+    retry_policy: RetryPolicy,
+    /// Log the requests changing the zone instead of sending them
+    dry_run: bool,
 }
 
 impl CloudFlareClient {
@@ -25,116 +42,306 @@ impl CloudFlareClient {
             token: String::from(token),
             zone_id: String::from(zone_id),
             base_url: String::from(url),
+            rate_limiter: RateLimiter::for_token(token),
+            retry_policy: RetryPolicy::requests(),
+            dry_run: false,
+        }
+    }
+
+    /// Replace how requests failing on the network or with a retryable status are retried
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.retry_policy = retry_policy;
+        self
+    }
+
+    pub fn with_dry_run(mut self, dry_run: bool) -> Self {
+        self.dry_run = dry_run;
+        self
+    }
+
+    /// Client of another zone, sharing the connections and the request budget
+    pub fn for_zone(&self, zone_id: &str) -> Self {
+        Self {
+            client: self.client.clone(),
+            token: self.token.clone(),
+            zone_id: String::from(zone_id),
+            base_url: self.base_url.clone(),
+            rate_limiter: self.rate_limiter.clone(),
+            retry_policy: self.retry_policy.clone(),
+            dry_run: self.dry_run,
         }
     }
 
+    /// Requests left in the budget of the token, shared by every zone using it
+    pub fn remaining_budget(&self) -> u32 {
+        self.rate_limiter.remaining()
+    }
+
     async fn send_request(&self, request: Request) -> Result<reqwest::Response, reqwest::Error> {
         let mut attempts = 0;
 
         loop {
-            let request = request.try_clone().expect("Failed to clone request");
+            self.rate_limiter.acquire().await;
+
+            // a streamed body cannot be sent again
+            let request = match request.try_clone() {
+                Some(request) => request,
+                None => return self.client.execute(request).await,
+            };
 
             debug!("{} {}", request.method().to_string(), request.url());
-            match self.client.execute(request).await {
-                Ok(res) => return Ok(res),
-                Err(e) => {
-                    let delay = Duration::from_millis(match attempts {
-                        0 => 100,
-                        1 => 200,
-                        2 => 400,
-                        3 => 800,
-                        4 => 1200,
-                        _ => return Err(e),
-                    });
-
-                    warn!("Request failed, retrying in {:?}", delay);
-                    tokio::time::sleep(delay).await;
-
-                    attempts += 1;
-                    continue;
+            let res = self.client.execute(request).await;
+            attempts += 1;
+
+            let reason = match &res {
+                Ok(res) => {
+                    self.rate_limiter.observe_headers(res.headers());
+
+                    if res.status() == StatusCode::TOO_MANY_REQUESTS {
+                        // the next attempt waits for the limiter to resume
+                        let pause = parse_retry_after(res.headers(), Utc::now())
+                            .unwrap_or(DEFAULT_RETRY_AFTER);
+                        self.rate_limiter.pause_for(pause);
+                    }
+
+                    match is_retryable_status(res.status()) {
+                        true => Some(format!("status {}", res.status())),
+                        false => None,
+                    }
                 }
+                Err(e) => Some(e.to_string()),
+            };
+
+            let reason = match reason {
+                Some(reason) => reason,
+                None => return res,
+            };
+
+            if !self.retry_policy.can_retry(attempts) {
+                return res;
             }
+
+            let delay = self.retry_policy.delay(attempts);
+            warn!("Request failed ({}), retrying in {:?}", reason, delay);
+            tokio::time::sleep(delay).await;
         }
     }
 
-    async fn get(&self, url: &str) -> Result<reqwest::Response, reqwest::Error> {
+    /// The `query` parameters are URL-encoded, unlike `url`
+    async fn get(
+        &self,
+        url: &str,
+        query: &[(&str, &str)],
+    ) -> Result<reqwest::Response, reqwest::Error> {
         let url = format!("{}{}", self.base_url, url);
 
         let request = self
             .client
             .get(url)
+            .query(query)
             .bearer_auth(&self.token)
-            .build()
-            .unwrap();
+            .build()?;
 
         self.send_request(request).await
     }
 
-    async fn patch_body(
+    /// Send a request changing the zone. In dry run mode the request is only
+    /// logged and the default value is returned instead of the result.
+    async fn mutate<T: DeserializeOwned + Default>(
         &self,
+        method: Method,
         url: &str,
-        body: impl serde::Serialize,
-    ) -> Result<reqwest::Response, reqwest::Error> {
+        body: Option<impl serde::Serialize>,
+    ) -> Result<T, CloudFlareClientError> {
         let url = format!("{}{}", self.base_url, url);
 
-        let request = self
-            .client
-            .patch(url)
-            .json(&body)
-            .bearer_auth(&self.token)
-            .build()
-            .unwrap();
+        if self.dry_run {
+            let body = match body {
+                Some(body) => serde_json::to_string(&body).unwrap_or_default(),
+                None => String::new(),
+            };
+            info!("Dry run, not sending {} {} {}", method, url, body);
+            return Ok(T::default());
+        }
 
-        self.send_request(request).await
-    }
+        let mut request = self.client.request(method, url).bearer_auth(&self.token);
 
-    /// api doc: https://developers.cloudflare.com/api/operations/dns-records-for-a-zone-list-dns-records
-    pub async fn get_dns_records(
-        &self,
-    ) -> Result<SuccessResponseList<DNSRecord>, CloudFlareClientError> {
-        //TODO: implement paging ?page=1
+        if let Some(body) = body {
+            request = request.json(&body);
+        }
 
-        let url = format!(
-            "/client/v4/zones/{}/dns_records?per_page=5000000",
-            self.zone_id
-        );
+        let res = match request.build() {
+            Ok(request) => self.send_request(request).await,
+            Err(e) => Err(e),
+        };
 
-        let res = match self.get(&url).await {
+        Self::read_result(res).await
+    }
+
+    /// Deserialize the body of a successful response, or turn it into the
+    /// most precise error possible while keeping what was received
+    async fn read_response<T: DeserializeOwned>(
+        res: Result<reqwest::Response, reqwest::Error>,
+    ) -> Result<T, CloudFlareClientError> {
+        let res = match res {
             Ok(res) => res,
             Err(e) => return Err(CloudFlareClientError::Request(e)),
         };
 
-        match res.status() {
-            StatusCode::OK => Ok(res.json::<SuccessResponseList<DNSRecord>>().await.unwrap()),
-            _ => Err(CloudFlareClientError::Api(
-                res.json::<ErrorResponse>().await.unwrap(),
-            )),
+        let status = res.status();
+        let ray = res
+            .headers()
+            .get("cf-ray")
+            .and_then(|value| value.to_str().ok())
+            .map(String::from);
+        let retry_after = parse_retry_after(res.headers(), Utc::now());
+
+        let body = match res.text().await {
+            Ok(body) => body,
+            Err(e) => return Err(CloudFlareClientError::Request(e)),
+        };
+
+        let raw = RawResponse { status, body, ray };
+
+        if status == StatusCode::TOO_MANY_REQUESTS {
+            return Err(CloudFlareClientError::RateLimited(raw, retry_after));
+        }
+
+        if !status.is_success() {
+            return match serde_json::from_str::<ErrorResponse>(&raw.body) {
+                Ok(error) => Err(CloudFlareClientError::Api(error)),
+                Err(_) => Err(CloudFlareClientError::Status(raw)),
+            };
+        }
+
+        match serde_json::from_str::<T>(&raw.body) {
+            Ok(value) => Ok(value),
+            Err(e) => match serde_json::from_str::<ErrorResponse>(&raw.body) {
+                Ok(error) if !error.success => Err(CloudFlareClientError::Api(error)),
+                Ok(_) => Err(CloudFlareClientError::Decode(raw, e)),
+                Err(_) => Err(CloudFlareClientError::UnexpectedBody(raw)),
+            },
         }
     }
 
-    /// api doc: https://developers.cloudflare.com/api/operations/dns-records-for-a-zone-list-dns-records
+    /// Result of a single object response
+    async fn read_result<T: DeserializeOwned>(
+        res: Result<reqwest::Response, reqwest::Error>,
+    ) -> Result<T, CloudFlareClientError> {
+        Ok(Self::read_response::<SuccessResponse<T>>(res).await?.result)
+    }
+
+    /// Find a zone of the account by its domain name, whatever the zone of the client
+    ///
+    /// api doc: https://developers.cloudflare.com/api/operations/zones-get
+    pub async fn find_zone(
+        &self,
+        name: &str,
+    ) -> Result<Option<ZoneDetails>, CloudFlareClientError> {
+        let res: SuccessResponseList<ZoneDetails> =
+            Self::read_response(self.get("/client/v4/zones", &[("name", name)]).await).await?;
+
+        Ok(res
+            .result
+            .into_iter()
+            .find(|zone| zone.name.eq_ignore_ascii_case(name)))
+    }
+
+    /// Whether the zone of the client still exists, Cloudflare answers 404
+    /// for an unknown zone id
+    pub async fn zone_exists(&self) -> Result<bool, CloudFlareClientError> {
+        let url = format!("/client/v4/zones/{}", self.zone_id);
+
+        let res = self.get(&url, &[]).await;
+        if let Ok(ref res) = res {
+            if res.status() == StatusCode::NOT_FOUND {
+                return Ok(false);
+            }
+        }
+
+        let _: SuccessResponse<ZoneDetails> = Self::read_response(res).await?;
+        Ok(true)
+    }
+
+    /// Get every DNS record of the zone, fetching all pages
+    pub async fn get_dns_records(
+        &self,
+    ) -> Result<SuccessResponseList<DNSRecord>, CloudFlareClientError> {
+        self.get_all_dns_records(None).await
+    }
+
+    /// Get every DNS record of the zone with the given content, fetching all pages
     pub async fn get_dns_records_with_content(
         &self,
         content: &str,
     ) -> Result<SuccessResponseList<DNSRecord>, CloudFlareClientError> {
-        //TODO: implement paging ?page=1
+        self.get_all_dns_records(Some(content)).await
+    }
 
-        let url = format!(
-            "/client/v4/zones/{}/dns_records?per_page=5000000&content={}",
-            self.zone_id, content
-        );
+    /// Lazily fetch the DNS records of the zone, the next page is only requested
+    /// once the records of the previous one are consumed
+    pub fn dns_records_stream<'a>(
+        &'a self,
+        content: Option<&'a str>,
+    ) -> impl Stream<Item = Result<DNSRecord, CloudFlareClientError>> + 'a {
+        stream::try_unfold(Some(1), move |page| async move {
+            let page = match page {
+                Some(page) => page,
+                None => return Ok(None),
+            };
 
-        let res = match self.get(&url).await {
-            Ok(res) => res,
-            Err(e) => return Err(CloudFlareClientError::Request(e)),
-        };
+            let res = self.get_dns_records_page(page, PER_PAGE, content).await?;
+            let next = match res.result_info.has_next_page() {
+                true => Some(page + 1),
+                false => None,
+            };
+
+            Ok(Some((stream::iter(res.result.into_iter().map(Ok)), next)))
+        })
+        .try_flatten()
+    }
+
+    async fn get_all_dns_records(
+        &self,
+        content: Option<&str>,
+    ) -> Result<SuccessResponseList<DNSRecord>, CloudFlareClientError> {
+        let mut page = 1;
+        let mut all = self.get_dns_records_page(page, PER_PAGE, content).await?;
 
-        match res.status() {
-            StatusCode::OK => Ok(res.json::<SuccessResponseList<DNSRecord>>().await.unwrap()),
-            _ => Err(CloudFlareClientError::Api(
-                res.json::<ErrorResponse>().await.unwrap(),
-            )),
+        let mut result_info = all.result_info.clone();
+        while result_info.has_next_page() {
+            page += 1;
+            let mut res = self.get_dns_records_page(page, PER_PAGE, content).await?;
+
+            all.errors.append(&mut res.errors);
+            all.messages.append(&mut res.messages);
+            all.result.append(&mut res.result);
+            result_info = res.result_info;
         }
+
+        all.result_info.count = all.result.len() as i32;
+        all.result_info.total_count = result_info.total_count;
+
+        Ok(all)
+    }
+
+    /// api doc: https://developers.cloudflare.com/api/operations/dns-records-for-a-zone-list-dns-records
+    pub async fn get_dns_records_page(
+        &self,
+        page: i32,
+        per_page: i32,
+        content: Option<&str>,
+    ) -> Result<SuccessResponseList<DNSRecord>, CloudFlareClientError> {
+        let url = format!("/client/v4/zones/{}/dns_records", self.zone_id);
+        let page = page.to_string();
+        let per_page = per_page.to_string();
+
+        let mut query = vec![("page", page.as_str()), ("per_page", per_page.as_str())];
+        if let Some(content) = content {
+            query.push(("content", content));
+        }
+
+        Self::read_response(self.get(&url, &query).await).await
     }
 
     /// api doc: https://developers.cloudflare.com/api/operations/dns-records-for-a-zone-patch-dns-record
@@ -147,17 +354,10 @@ impl CloudFlareClient {
             self.zone_id, request.id
         );
 
-        let res = match self.patch_body(&url, request).await {
-            Ok(res) => res,
-            Err(e) => return Err(CloudFlareClientError::Request(e)),
-        };
+        self.mutate::<serde_json::Value>(Method::PATCH, &url, Some(request))
+            .await?;
 
-        match res.status() {
-            StatusCode::OK => Ok(()),
-            _ => Err(CloudFlareClientError::Api(
-                res.json::<ErrorResponse>().await.unwrap(),
-            )),
-        }
+        Ok(())
     }
 
     /// api doc: https://developers.cloudflare.com/api/operations/dns-records-for-a-zone-patch-dns-record
@@ -173,39 +373,82 @@ impl CloudFlareClient {
             content: String,
         }
 
-        let res = match self
-            .patch_body(
-                &url,
-                Body {
-                    content: String::from(content),
-                },
-            )
-            .await
-        {
-            Ok(res) => res,
-            Err(e) => return Err(CloudFlareClientError::Request(e)),
+        let body = Body {
+            content: String::from(content),
         };
 
-        match res.status() {
-            StatusCode::OK => Ok(()),
-            _ => Err(CloudFlareClientError::Api(
-                res.json::<ErrorResponse>().await.unwrap(),
-            )),
-        }
+        self.mutate::<serde_json::Value>(Method::PATCH, &url, Some(body))
+            .await?;
+
+        Ok(())
+    }
+
+    /// api doc: https://developers.cloudflare.com/api/operations/dns-records-for-a-zone-create-dns-record
+    pub async fn create_dns_record(
+        &self,
+        request: CreateDNSRecordRequest,
+    ) -> Result<DNSRecord, CloudFlareClientError> {
+        let url = format!("/client/v4/zones/{}/dns_records", self.zone_id);
+
+        self.mutate(Method::POST, &url, Some(request)).await
+    }
+
+    /// Replace every field of the record, unlike `set_dns_record`
+    ///
+    /// api doc: https://developers.cloudflare.com/api/operations/dns-records-for-a-zone-update-dns-record
+    pub async fn overwrite_dns_record(
+        &self,
+        request: UpdateDNSRecordRequest,
+    ) -> Result<DNSRecord, CloudFlareClientError> {
+        let url = format!(
+            "/client/v4/zones/{}/dns_records/{}",
+            self.zone_id, request.id
+        );
+
+        self.mutate(Method::PUT, &url, Some(request)).await
+    }
+
+    /// api doc: https://developers.cloudflare.com/api/operations/dns-records-for-a-zone-delete-dns-record
+    pub async fn delete_dns_record(
+        &self,
+        id: &str,
+    ) -> Result<DeletedDNSRecord, CloudFlareClientError> {
+        let url = format!("/client/v4/zones/{}/dns_records/{}", self.zone_id, id);
+
+        self.mutate(Method::DELETE, &url, None::<()>).await
+    }
+
+    /// Apply all the changes in one request, either all of them succeed or none
+    ///
+    /// api doc: https://developers.cloudflare.com/api/operations/dns-records-for-a-zone-batch-dns-records
+    pub async fn batch_dns_records(
+        &self,
+        request: BatchDNSRecordsRequest,
+    ) -> Result<BatchDNSRecordsResult, CloudFlareClientError> {
+        let url = format!("/client/v4/zones/{}/dns_records/batch", self.zone_id);
+
+        self.mutate(Method::POST, &url, Some(request)).await
     }
 }
 
 #[cfg(test)]
+#[allow(clippy::needless_borrows_for_generic_args)]
 mod tests {
     use chrono::DateTime;
+    use futures::StreamExt;
     use httpmock::prelude::*;
 
-    use crate::cloudflare::{
-        client::CloudFlareClient,
-        models::{
-            CloudFlareClientError, DNSRecord, DNSType, ErrorResponse, Message, ResultInfo,
-            SuccessResponseList,
+    use crate::{
+        cloudflare::{
+            client::CloudFlareClient,
+            models::{
+                BatchDNSRecordsRequest, BatchDNSRecordsResult, CloudFlareClientError,
+                CreateDNSRecordRequest, DNSRecord, DNSType, DeletedDNSRecord, ErrorResponse,
+                Message, RawResponse, ResultInfo, SuccessResponse, SuccessResponseList,
+                UpdateDNSRecordRequest, ZoneDetails,
+            },
         },
+        retry::RetryPolicy,
     };
 
     fn simple_dnsrecord_reponse() -> SuccessResponseList<DNSRecord> {
@@ -234,9 +477,35 @@ mod tests {
                 tags: None,
                 tags_modified_on: None,
                 ttl: None,
+                priority: None,
             }],
         }
     }
+    fn dnsrecord_page_response(
+        page: i32,
+        per_page: i32,
+        total_count: i32,
+        names: &[&str],
+    ) -> SuccessResponseList<DNSRecord> {
+        SuccessResponseList::<DNSRecord> {
+            success: true,
+            result_info: ResultInfo {
+                count: names.len() as i32,
+                page,
+                per_page,
+                total_count,
+            },
+            result: names
+                .iter()
+                .map(|name| DNSRecord {
+                    name: String::from(*name),
+                    ..Default::default()
+                })
+                .collect(),
+            ..Default::default()
+        }
+    }
+
     fn simple_api_error() -> ErrorResponse {
         ErrorResponse {
             errors: vec![Message {
@@ -289,4 +558,606 @@ mod tests {
             panic!("wrong");
         }
     }
+
+    #[tokio::test]
+    async fn get_dns_records_fetches_all_pages() {
+        let server = MockServer::start();
+        let page_1_mock = server.mock(|when, then| {
+            when.method(GET)
+                .path_contains("/dns_records")
+                .query_param("page", "1");
+            then.status(200)
+                .header("content-type", "application/json")
+                .body(
+                    serde_json::to_string(&dnsrecord_page_response(1, 2, 3, &["a", "b"])).unwrap(),
+                );
+        });
+        let page_2_mock = server.mock(|when, then| {
+            when.method(GET)
+                .path_contains("/dns_records")
+                .query_param("page", "2");
+            then.status(200)
+                .header("content-type", "application/json")
+                .body(serde_json::to_string(&dnsrecord_page_response(2, 2, 3, &["c"])).unwrap());
+        });
+
+        let client = CloudFlareClient::new_with_url("", "1234", &server.url("/"));
+
+        let response = client.get_dns_records().await.unwrap();
+
+        page_1_mock.assert();
+        page_2_mock.assert();
+
+        let names: Vec<_> = response.result.iter().map(|r| r.name.as_str()).collect();
+        assert_eq!(names, vec!["a", "b", "c"]);
+        assert_eq!(response.result_info.count, 3);
+        assert_eq!(response.result_info.total_count, 3);
+    }
+
+    #[tokio::test]
+    async fn get_dns_records_with_content_sends_content_on_every_page() {
+        let server = MockServer::start();
+        let page_1_mock = server.mock(|when, then| {
+            when.method(GET)
+                .path_contains("/dns_records")
+                .query_param("page", "1")
+                .query_param("content", "1.2.3.4");
+            then.status(200)
+                .header("content-type", "application/json")
+                .body(serde_json::to_string(&dnsrecord_page_response(1, 1, 2, &["a"])).unwrap());
+        });
+        let page_2_mock = server.mock(|when, then| {
+            when.method(GET)
+                .path_contains("/dns_records")
+                .query_param("page", "2")
+                .query_param("content", "1.2.3.4");
+            then.status(200)
+                .header("content-type", "application/json")
+                .body(serde_json::to_string(&dnsrecord_page_response(2, 1, 2, &["b"])).unwrap());
+        });
+
+        let client = CloudFlareClient::new_with_url("", "1234", &server.url("/"));
+
+        let response = client
+            .get_dns_records_with_content("1.2.3.4")
+            .await
+            .unwrap();
+
+        page_1_mock.assert();
+        page_2_mock.assert();
+        assert_eq!(response.count(), 2);
+    }
+
+    #[tokio::test]
+    async fn get_dns_records_page_encodes_the_content() {
+        let server = MockServer::start();
+        let cloudflare_mock = server.mock(|when, then| {
+            when.method(GET)
+                .path_contains("/dns_records")
+                .query_param("content", "v=spf1 +a ~all");
+            then.status(200)
+                .header("content-type", "application/json")
+                .body(serde_json::to_string(&dnsrecord_page_response(1, 1, 1, &["a"])).unwrap());
+        });
+
+        let client = CloudFlareClient::new_with_url("", "1234", &server.url("/"));
+
+        let response = client
+            .get_dns_records_page(1, 100, Some("v=spf1 +a ~all"))
+            .await
+            .unwrap();
+
+        cloudflare_mock.assert();
+        assert_eq!(response.count(), 1);
+    }
+
+    #[tokio::test]
+    async fn get_dns_records_fails_when_a_page_fails() {
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.method(GET)
+                .path_contains("/dns_records")
+                .query_param("page", "1");
+            then.status(200)
+                .header("content-type", "application/json")
+                .body(serde_json::to_string(&dnsrecord_page_response(1, 1, 2, &["a"])).unwrap());
+        });
+        server.mock(|when, then| {
+            when.method(GET)
+                .path_contains("/dns_records")
+                .query_param("page", "2");
+            then.status(500)
+                .header("content-type", "application/json")
+                .body(serde_json::to_string(&simple_api_error()).unwrap());
+        });
+
+        let client = CloudFlareClient::new_with_url("", "1234", &server.url("/"));
+
+        let response = client.get_dns_records().await;
+
+        assert!(matches!(response, Err(CloudFlareClientError::Api(_))));
+    }
+
+    #[tokio::test]
+    async fn dns_records_stream_is_lazy() {
+        let server = MockServer::start();
+        let page_1_mock = server.mock(|when, then| {
+            when.method(GET)
+                .path_contains("/dns_records")
+                .query_param("page", "1");
+            then.status(200)
+                .header("content-type", "application/json")
+                .body(
+                    serde_json::to_string(&dnsrecord_page_response(1, 2, 4, &["a", "b"])).unwrap(),
+                );
+        });
+        let page_2_mock = server.mock(|when, then| {
+            when.method(GET)
+                .path_contains("/dns_records")
+                .query_param("page", "2");
+            then.status(200)
+                .header("content-type", "application/json")
+                .body(
+                    serde_json::to_string(&dnsrecord_page_response(2, 2, 4, &["c", "d"])).unwrap(),
+                );
+        });
+
+        let client = CloudFlareClient::new_with_url("", "1234", &server.url("/"));
+
+        let first: Vec<_> = client.dns_records_stream(None).take(2).collect().await;
+        assert_eq!(first.len(), 2);
+        page_1_mock.assert_hits(1);
+        page_2_mock.assert_hits(0);
+
+        let all: Vec<_> = client
+            .dns_records_stream(None)
+            .map(|r| r.unwrap().name)
+            .collect()
+            .await;
+        assert_eq!(all, vec!["a", "b", "c", "d"]);
+        page_2_mock.assert_hits(1);
+    }
+
+    #[tokio::test]
+    async fn dns_records_stream_empty_zone() {
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.method(GET).path_contains("/dns_records");
+            then.status(200)
+                .header("content-type", "application/json")
+                .body(serde_json::to_string(&dnsrecord_page_response(1, 500, 0, &[])).unwrap());
+        });
+
+        let client = CloudFlareClient::new_with_url("", "1234", &server.url("/"));
+
+        let all: Vec<_> = client.dns_records_stream(None).collect().await;
+        assert!(all.is_empty());
+    }
+
+    fn success_response<T>(result: T) -> SuccessResponse<T> {
+        SuccessResponse {
+            errors: vec![],
+            messages: vec![],
+            success: true,
+            result,
+        }
+    }
+
+    fn record(id: &str, name: &str, content: &str) -> DNSRecord {
+        DNSRecord {
+            id: String::from(id),
+            name: String::from(name),
+            content: String::from(content),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn create_dns_record_returns_the_record() {
+        let server = MockServer::start();
+        let cloudflare_mock = server.mock(|when, then| {
+            when.method(POST)
+                .path("/client/v4/zones/1234/dns_records")
+                .json_body_partial(r#"{ "name": "home.example.com", "content": "1.2.3.4" }"#);
+            then.status(200)
+                .header("content-type", "application/json")
+                .body(
+                    serde_json::to_string(&success_response(record(
+                        "abcd",
+                        "home.example.com",
+                        "1.2.3.4",
+                    )))
+                    .unwrap(),
+                );
+        });
+
+        let client = CloudFlareClient::new_with_url("", "1234", &server.url(""));
+
+        let response = client
+            .create_dns_record(CreateDNSRecordRequest {
+                content: String::from("1.2.3.4"),
+                name: String::from("home.example.com"),
+                proxied: None,
+                r#type: DNSType::A,
+                comment: None,
+                tags: None,
+                ttl: None,
+                priority: None,
+            })
+            .await;
+
+        cloudflare_mock.assert();
+        assert_eq!(response.unwrap().id, "abcd");
+    }
+
+    #[tokio::test]
+    async fn overwrite_dns_record_puts_the_record() {
+        let server = MockServer::start();
+        let cloudflare_mock = server.mock(|when, then| {
+            when.method(PUT)
+                .path("/client/v4/zones/1234/dns_records/abcd")
+                .json_body_partial(r#"{ "content": "1.2.3.4" }"#);
+            then.status(200)
+                .header("content-type", "application/json")
+                .body(
+                    serde_json::to_string(&success_response(record(
+                        "abcd",
+                        "home.example.com",
+                        "1.2.3.4",
+                    )))
+                    .unwrap(),
+                );
+        });
+
+        let client = CloudFlareClient::new_with_url("", "1234", &server.url(""));
+
+        let mut request = UpdateDNSRecordRequest::from(record("abcd", "home.example.com", ""));
+        request.content = String::from("1.2.3.4");
+        let response = client.overwrite_dns_record(request).await;
+
+        cloudflare_mock.assert();
+        assert_eq!(response.unwrap().content, "1.2.3.4");
+    }
+
+    #[tokio::test]
+    async fn delete_dns_record_returns_the_id() {
+        let server = MockServer::start();
+        let cloudflare_mock = server.mock(|when, then| {
+            when.method(DELETE)
+                .path("/client/v4/zones/1234/dns_records/abcd");
+            then.status(200)
+                .header("content-type", "application/json")
+                .body(
+                    serde_json::to_string(&success_response(DeletedDNSRecord {
+                        id: String::from("abcd"),
+                    }))
+                    .unwrap(),
+                );
+        });
+
+        let client = CloudFlareClient::new_with_url("", "1234", &server.url(""));
+
+        let response = client.delete_dns_record("abcd").await;
+
+        cloudflare_mock.assert();
+        assert_eq!(response.unwrap().id, "abcd");
+    }
+
+    #[tokio::test]
+    async fn delete_dns_record_backend_returns_404() {
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.method(DELETE).path_contains("/dns_records/");
+            then.status(404)
+                .header("content-type", "application/json")
+                .body(serde_json::to_string(&simple_api_error()).unwrap());
+        });
+
+        let client = CloudFlareClient::new_with_url("", "1234", &server.url(""));
+
+        let response = client.delete_dns_record("abcd").await;
+
+        assert!(matches!(response, Err(CloudFlareClientError::Api(_))));
+    }
+
+    #[tokio::test]
+    async fn batch_dns_records_sends_every_operation() {
+        let server = MockServer::start();
+        let cloudflare_mock = server.mock(|when, then| {
+            when.method(POST)
+                .path("/client/v4/zones/1234/dns_records/batch")
+                .json_body_partial(
+                    r#"{
+                        "deletes": [{ "id": "old" }],
+                        "patches": [{ "id": "abcd", "content": "1.2.3.4" }]
+                    }"#,
+                );
+            then.status(200)
+                .header("content-type", "application/json")
+                .body(
+                    serde_json::to_string(&success_response(BatchDNSRecordsResult {
+                        deletes: vec![record("old", "old.example.com", "5.6.7.8")],
+                        patches: vec![record("abcd", "home.example.com", "1.2.3.4")],
+                        ..Default::default()
+                    }))
+                    .unwrap(),
+                );
+        });
+
+        let client = CloudFlareClient::new_with_url("", "1234", &server.url(""));
+
+        let mut patch = UpdateDNSRecordRequest::from(record("abcd", "home.example.com", ""));
+        patch.content = String::from("1.2.3.4");
+        let response = client
+            .batch_dns_records(BatchDNSRecordsRequest {
+                deletes: vec![DeletedDNSRecord {
+                    id: String::from("old"),
+                }],
+                patches: vec![patch],
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+
+        cloudflare_mock.assert();
+        assert_eq!(response.deletes[0].id, "old");
+        assert_eq!(response.patches[0].content, "1.2.3.4");
+        assert!(response.posts.is_empty());
+    }
+
+    #[tokio::test]
+    async fn batch_dns_records_backend_returns_400() {
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.method(POST).path_contains("/dns_records/batch");
+            then.status(400)
+                .header("content-type", "application/json")
+                .body(serde_json::to_string(&simple_api_error()).unwrap());
+        });
+
+        let client = CloudFlareClient::new_with_url("", "1234", &server.url(""));
+
+        let response = client
+            .batch_dns_records(BatchDNSRecordsRequest::default())
+            .await;
+
+        if let CloudFlareClientError::Api(error) = response.unwrap_err() {
+            assert_eq!(error, simple_api_error());
+        } else {
+            panic!("wrong");
+        }
+    }
+
+    #[tokio::test]
+    async fn get_dns_records_proxy_error_keeps_body_and_ray() {
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.method(GET).path_contains("/dns_records");
+            then.status(502)
+                .header("content-type", "text/html")
+                .header("cf-ray", "8a1b2c3d4e5f-YUL")
+                .body("<html><body>502 Bad Gateway</body></html>");
+        });
+
+        let client = CloudFlareClient::new_with_url("", "1234", &server.url(""));
+
+        match client.get_dns_records().await {
+            Err(CloudFlareClientError::Status(RawResponse { status, body, ray })) => {
+                assert_eq!(status.as_u16(), 502);
+                assert_eq!(body, "<html><body>502 Bad Gateway</body></html>");
+                assert_eq!(ray.as_deref(), Some("8a1b2c3d4e5f-YUL"));
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn get_dns_records_success_with_html_body() {
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.method(GET).path_contains("/dns_records");
+            then.status(200).body("<html>captive portal</html>");
+        });
+
+        let client = CloudFlareClient::new_with_url("", "1234", &server.url(""));
+
+        assert!(matches!(
+            client.get_dns_records().await,
+            Err(CloudFlareClientError::UnexpectedBody(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn get_dns_records_schema_change() {
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.method(GET).path_contains("/dns_records");
+            then.status(200)
+                .header("content-type", "application/json")
+                .body(r#"{ "errors": [], "messages": [], "success": true, "result": "moved" }"#);
+        });
+
+        let client = CloudFlareClient::new_with_url("", "1234", &server.url(""));
+
+        assert!(matches!(
+            client.get_dns_records().await,
+            Err(CloudFlareClientError::Decode(_, _))
+        ));
+    }
+
+    #[tokio::test]
+    async fn set_dns_record_content_error_status_without_body() {
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.method(httpmock::Method::PATCH)
+                .path_contains("/dns_records/abcd");
+            then.status(503);
+        });
+
+        let client = CloudFlareClient::new_with_url("", "1234", &server.url(""));
+
+        let response = client.set_dns_record_content("abcd", "1.2.3.4").await;
+
+        assert!(matches!(response, Err(CloudFlareClientError::Status(_))));
+    }
+
+    #[tokio::test]
+    async fn set_dns_record_content_ok() {
+        let server = MockServer::start();
+        let cloudflare_mock = server.mock(|when, then| {
+            when.method(httpmock::Method::PATCH)
+                .path("/client/v4/zones/1234/dns_records/abcd")
+                .json_body_partial(r#"{ "content": "1.2.3.4" }"#);
+            then.status(200)
+                .header("content-type", "application/json")
+                .body(
+                    serde_json::to_string(&success_response(record(
+                        "abcd",
+                        "home.example.com",
+                        "1.2.3.4",
+                    )))
+                    .unwrap(),
+                );
+        });
+
+        let client = CloudFlareClient::new_with_url("", "1234", &server.url(""));
+
+        let response = client.set_dns_record_content("abcd", "1.2.3.4").await;
+
+        cloudflare_mock.assert();
+        assert!(response.is_ok());
+    }
+
+    #[tokio::test]
+    async fn rate_limited_requests_are_retried_then_reported() {
+        let server = MockServer::start();
+        let cloudflare_mock = server.mock(|when, then| {
+            when.method(GET).path_contains("/dns_records");
+            then.status(429)
+                .header("retry-after", "0")
+                .header("content-type", "application/json")
+                .body(serde_json::to_string(&simple_api_error()).unwrap());
+        });
+
+        let client = CloudFlareClient::new_with_url("rate-limited-token", "1234", &server.url(""))
+            .with_retry_policy(RetryPolicy {
+                max_attempts: Some(4),
+                ..RetryPolicy::requests()
+            });
+
+        let response = client.get_dns_records().await;
+
+        cloudflare_mock.assert_hits(4);
+        match response {
+            Err(CloudFlareClientError::RateLimited(raw, retry_after)) => {
+                assert_eq!(raw.status.as_u16(), 429);
+                assert_eq!(retry_after, Some(std::time::Duration::ZERO));
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn remaining_budget_follows_the_ratelimit_header() {
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.method(GET).path_contains("/dns_records");
+            then.status(200)
+                .header("content-type", "application/json")
+                .header("ratelimit", "\"default\";r=17;t=300")
+                .body(serde_json::to_string(&dnsrecord_page_response(1, 500, 0, &[])).unwrap());
+        });
+
+        let client = CloudFlareClient::new_with_url("budget-token", "1234", &server.url(""));
+
+        client.get_dns_records().await.unwrap();
+
+        assert_eq!(client.remaining_budget(), 17);
+    }
+
+    #[tokio::test]
+    async fn dry_run_does_not_change_the_zone() {
+        let server = MockServer::start();
+        let cloudflare_mock = server.mock(|when, then| {
+            when.any_request();
+            then.status(500);
+        });
+
+        let client = CloudFlareClient::new_with_url("", "1234", &server.url("")).with_dry_run(true);
+
+        let mut request = UpdateDNSRecordRequest::from(record("abcd", "home.example.com", ""));
+        request.content = String::from("1.2.3.4");
+
+        assert!(client.set_dns_record(request.clone()).await.is_ok());
+        assert!(client.overwrite_dns_record(request).await.is_ok());
+        assert!(client.delete_dns_record("abcd").await.is_ok());
+
+        cloudflare_mock.assert_hits(0);
+    }
+
+    #[tokio::test]
+    async fn find_zone_by_name() {
+        let zones = SuccessResponseList {
+            success: true,
+            result_info: ResultInfo {
+                count: 1,
+                page: 1,
+                per_page: 20,
+                total_count: 1,
+            },
+            result: vec![ZoneDetails {
+                id: String::from("5678"),
+                name: String::from("example.com"),
+                status: String::from("active"),
+            }],
+            ..Default::default()
+        };
+
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.method(GET)
+                .path("/client/v4/zones")
+                .query_param("name", "example.com");
+            then.status(200)
+                .header("content-type", "application/json")
+                .body(serde_json::to_string(&zones).unwrap());
+        });
+        server.mock(|when, then| {
+            when.method(GET)
+                .path("/client/v4/zones")
+                .query_param("name", "example.org");
+            then.status(200)
+                .header("content-type", "application/json")
+                .body(r#"{ "success": true, "errors": [], "messages": [], "result_info": { "count": 0, "page": 1, "per_page": 20, "total_count": 0 }, "result": [] }"#);
+        });
+
+        let client = CloudFlareClient::new_with_url("", "", &server.url(""));
+
+        let zone = client.find_zone("example.com").await.unwrap();
+        assert_eq!(zone.map(|z| z.id), Some(String::from("5678")));
+        assert_eq!(client.find_zone("example.org").await.unwrap(), None);
+        assert_eq!(client.for_zone("5678").zone_id, "5678");
+    }
+
+    #[tokio::test]
+    async fn zone_exists() {
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.method(GET).path("/client/v4/zones/5678");
+            then.status(200)
+                .header("content-type", "application/json")
+                .body(r#"{ "success": true, "errors": [], "messages": [], "result": { "id": "5678", "name": "example.com", "status": "active" } }"#);
+        });
+        server.mock(|when, then| {
+            when.method(GET).path("/client/v4/zones/1234");
+            then.status(404)
+                .header("content-type", "application/json")
+                .body(serde_json::to_string(&simple_api_error()).unwrap());
+        });
+
+        let client = CloudFlareClient::new_with_url("", "5678", &server.url(""));
+
+        assert!(client.zone_exists().await.unwrap());
+        assert!(!client.for_zone("1234").zone_exists().await.unwrap());
+    }
 }
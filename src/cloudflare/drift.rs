@@ -0,0 +1,183 @@
+use core::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use super::models::{DNSRecord, UpdateDNSRecordRequest};
+
+/// What a managed record should look like, the settings not set are left as is
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct DesiredRecord {
+    pub content: String,
+    pub proxied: Option<bool>,
+    pub ttl: Option<i32>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum DriftField {
+    Content,
+    Proxied,
+    Ttl,
+}
+
+impl fmt::Display for DriftField {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DriftField::Content => write!(f, "content"),
+            DriftField::Proxied => write!(f, "proxied"),
+            DriftField::Ttl => write!(f, "ttl"),
+        }
+    }
+}
+
+/// A setting of a record that differs from the desired one
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct Drift {
+    pub field: DriftField,
+    pub expected: String,
+    pub actual: String,
+}
+
+impl DesiredRecord {
+    pub fn drift(&self, record: &DNSRecord) -> Vec<Drift> {
+        let mut drift = Vec::new();
+
+        if record.content != self.content {
+            drift.push(Drift {
+                field: DriftField::Content,
+                expected: self.content.clone(),
+                actual: record.content.clone(),
+            });
+        }
+
+        let proxied = record.proxied.unwrap_or(false);
+        if let Some(expected) = self.proxied {
+            // only some records can be proxied, there is nothing to fix on the others
+            if proxied != expected && (record.proxiable || !expected) {
+                drift.push(Drift {
+                    field: DriftField::Proxied,
+                    expected: expected.to_string(),
+                    actual: proxied.to_string(),
+                });
+            }
+        }
+
+        // the TTL of proxied records is always automatic
+        let proxied = self.proxied.unwrap_or(proxied);
+        if let (Some(expected), false) = (self.ttl, proxied) {
+            let actual = record.ttl.unwrap_or(1);
+            if actual != expected {
+                drift.push(Drift {
+                    field: DriftField::Ttl,
+                    expected: expected.to_string(),
+                    actual: actual.to_string(),
+                });
+            }
+        }
+
+        drift
+    }
+
+    /// Request putting the record back to the desired state
+    pub fn fix(&self, record: DNSRecord) -> UpdateDNSRecordRequest {
+        let mut request = UpdateDNSRecordRequest::from(record);
+        request.content = self.content.clone();
+
+        if let Some(proxied) = self.proxied {
+            request.proxied = Some(proxied);
+        }
+        if let Some(ttl) = self.ttl {
+            request.ttl = Some(ttl);
+        }
+
+        request
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(content: &str, proxied: bool, ttl: i32) -> DNSRecord {
+        DNSRecord {
+            content: String::from(content),
+            name: String::from("home.example.com"),
+            proxied: Some(proxied),
+            proxiable: true,
+            ttl: Some(ttl),
+            ..Default::default()
+        }
+    }
+
+    fn desired(proxied: Option<bool>, ttl: Option<i32>) -> DesiredRecord {
+        DesiredRecord {
+            content: String::from("1.2.3.4"),
+            proxied,
+            ttl,
+        }
+    }
+
+    #[test]
+    fn no_drift() {
+        assert!(desired(None, None)
+            .drift(&record("1.2.3.4", true, 1))
+            .is_empty());
+        assert!(desired(Some(false), Some(300))
+            .drift(&record("1.2.3.4", false, 300))
+            .is_empty());
+    }
+
+    #[test]
+    fn every_field_drifts() {
+        let drift = desired(Some(false), Some(300)).drift(&record("1.2.3.5", true, 1));
+
+        assert_eq!(
+            drift,
+            vec![
+                Drift {
+                    field: DriftField::Content,
+                    expected: String::from("1.2.3.4"),
+                    actual: String::from("1.2.3.5"),
+                },
+                Drift {
+                    field: DriftField::Proxied,
+                    expected: String::from("false"),
+                    actual: String::from("true"),
+                },
+                Drift {
+                    field: DriftField::Ttl,
+                    expected: String::from("300"),
+                    actual: String::from("1"),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn ttl_of_proxied_records_is_ignored() {
+        assert!(desired(None, Some(300))
+            .drift(&record("1.2.3.4", true, 1))
+            .is_empty());
+    }
+
+    #[test]
+    fn not_proxiable_records_are_not_proxied() {
+        let mut record = record("1.2.3.4", false, 1);
+        record.proxiable = false;
+
+        assert!(desired(Some(true), None).drift(&record).is_empty());
+    }
+
+    #[test]
+    fn fix_keeps_the_other_settings() {
+        let mut record = record("1.2.3.5", true, 1);
+        record.comment = Some(String::from("#cfdpip"));
+
+        let request = desired(Some(false), Some(300)).fix(record);
+
+        assert_eq!(request.content, "1.2.3.4");
+        assert_eq!(request.proxied, Some(false));
+        assert_eq!(request.ttl, Some(300));
+        assert_eq!(request.comment, Some(String::from("#cfdpip")));
+    }
+}
@@ -1,2 +1,6 @@
+pub mod bind;
 pub mod client;
+pub mod drift;
 pub mod models;
+pub mod rate_limit;
+pub mod selector;
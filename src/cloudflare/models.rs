@@ -1,6 +1,6 @@
 #![allow(dead_code)]
 use core::fmt;
-use std::{net::Ipv4Addr, str::FromStr};
+use std::{net::IpAddr, str::FromStr};
 
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
@@ -9,6 +9,73 @@ use serde::{Deserialize, Serialize};
 pub enum CloudFlareClientError {
     Request(reqwest::Error),
     Api(ErrorResponse),
+    /// Success status and an API envelope, but the result does not match the models
+    Decode(RawResponse, serde_json::Error),
+    /// Error status without an API error in the body, such as a 502 from a proxy
+    Status(RawResponse),
+    /// Success status but the body is not an API response
+    UnexpectedBody(RawResponse),
+    /// Still rate limited after waiting, with the `Retry-After` of the last response
+    RateLimited(RawResponse, Option<std::time::Duration>),
+}
+
+impl fmt::Display for CloudFlareClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CloudFlareClientError::Request(e) => write!(f, "request failed: {}", e),
+            CloudFlareClientError::Api(e) => write!(f, "Cloudflare API error: {}", e),
+            CloudFlareClientError::Decode(raw, e) => {
+                write!(f, "could not decode response: {} ({})", e, raw)
+            }
+            CloudFlareClientError::Status(raw) => write!(f, "unexpected status ({})", raw),
+            CloudFlareClientError::UnexpectedBody(raw) => {
+                write!(f, "unexpected response body ({})", raw)
+            }
+            CloudFlareClientError::RateLimited(raw, Some(retry_after)) => {
+                write!(f, "rate limited, retry in {:?} ({})", retry_after, raw)
+            }
+            CloudFlareClientError::RateLimited(raw, None) => write!(f, "rate limited ({})", raw),
+        }
+    }
+}
+
+impl std::error::Error for CloudFlareClientError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            CloudFlareClientError::Request(e) => Some(e),
+            CloudFlareClientError::Decode(_, e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// What came back when a response could not be understood, kept for the logs
+#[derive(Debug, Clone, PartialEq)]
+pub struct RawResponse {
+    pub status: reqwest::StatusCode,
+    pub body: String,
+    /// `cf-ray` header, the id Cloudflare support asks for
+    pub ray: Option<String>,
+}
+
+/// Longest part of the body shown when displaying a response
+const DISPLAYED_BODY_LEN: usize = 200;
+
+impl fmt::Display for RawResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "HTTP {}", self.status)?;
+
+        if let Some(ref ray) = self.ray {
+            write!(f, ", cf-ray {}", ray)?;
+        }
+
+        let body = self.body.trim();
+        match body.char_indices().nth(DISPLAYED_BODY_LEN) {
+            Some((end, _)) => write!(f, ": {}...", &body[..end]),
+            None if body.is_empty() => Ok(()),
+            None => write!(f, ": {}", body),
+        }
+    }
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
@@ -18,6 +85,21 @@ pub struct ErrorResponse {
     pub success: bool,
 }
 
+impl fmt::Display for ErrorResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let errors: Vec<String> = self
+            .errors
+            .iter()
+            .map(|e| format!("{} ({})", e.message, e.code))
+            .collect();
+
+        match errors.is_empty() {
+            true => write!(f, "no error given"),
+            false => write!(f, "{}", errors.join(", ")),
+        }
+    }
+}
+
 #[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Default)]
 pub struct SuccessResponseList<T> {
     pub errors: Vec<Message>,
@@ -33,6 +115,28 @@ impl<T> SuccessResponseList<T> {
     }
 }
 
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Default)]
+pub struct SuccessResponse<T> {
+    pub errors: Vec<Message>,
+    pub messages: Vec<Message>,
+    pub success: bool,
+    pub result: T,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct CreateDNSRecordRequest {
+    pub content: String,
+    pub name: String,
+    pub proxied: Option<bool>,
+    pub r#type: DNSType,
+    pub comment: Option<String>,
+    pub tags: Option<Vec<String>>,
+    pub ttl: Option<i32>,
+    /// Only for MX, SRV and URI records
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub priority: Option<u16>,
+}
+
 #[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
 pub struct UpdateDNSRecordRequest {
     pub content: String,
@@ -43,6 +147,9 @@ pub struct UpdateDNSRecordRequest {
     pub id: String,
     pub tags: Option<Vec<String>>,
     pub ttl: Option<i32>,
+    /// Only for MX, SRV and URI records
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub priority: Option<u16>,
 }
 
 impl From<DNSRecord> for UpdateDNSRecordRequest {
@@ -56,10 +163,49 @@ impl From<DNSRecord> for UpdateDNSRecordRequest {
             id: value.id,
             tags: value.tags,
             ttl: value.ttl,
+            priority: value.priority,
         }
     }
 }
 
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Default)]
+pub struct DeletedDNSRecord {
+    pub id: String,
+}
+
+/// Changes applied atomically by the batch endpoint, in the order deletes,
+/// patches, puts then posts
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Default)]
+pub struct BatchDNSRecordsRequest {
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub deletes: Vec<DeletedDNSRecord>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub patches: Vec<UpdateDNSRecordRequest>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub puts: Vec<UpdateDNSRecordRequest>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub posts: Vec<CreateDNSRecordRequest>,
+}
+
+impl BatchDNSRecordsRequest {
+    pub fn is_empty(&self) -> bool {
+        self.deletes.is_empty()
+            && self.patches.is_empty()
+            && self.puts.is_empty()
+            && self.posts.is_empty()
+    }
+}
+
+/// Records as they are after a batch, grouped like the request
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Default)]
+#[serde(default)]
+pub struct BatchDNSRecordsResult {
+    pub deletes: Vec<DNSRecord>,
+    pub patches: Vec<DNSRecord>,
+    pub puts: Vec<DNSRecord>,
+    pub posts: Vec<DNSRecord>,
+}
+
 #[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
 pub struct Message {
     pub code: i32,
@@ -74,6 +220,20 @@ pub struct ResultInfo {
     pub total_count: i32,
 }
 
+impl ResultInfo {
+    pub fn has_next_page(&self) -> bool {
+        self.count > 0 && self.page * self.per_page < self.total_count
+    }
+}
+
+/// Zone of the account, as listed by the zones endpoint
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Default)]
+pub struct ZoneDetails {
+    pub id: String,
+    pub name: String,
+    pub status: String,
+}
+
 #[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Default)]
 pub struct DNSRecord {
     pub content: String,
@@ -90,6 +250,8 @@ pub struct DNSRecord {
     pub tags: Option<Vec<String>>,
     pub tags_modified_on: Option<DateTime<Utc>>,
     pub ttl: Option<i32>,
+    /// Only for MX, SRV and URI records
+    pub priority: Option<u16>,
 }
 
 impl DNSRecord {
@@ -100,13 +262,14 @@ impl DNSRecord {
         }
     }
 
-    pub fn content_as_ip(&self) -> Result<Ipv4Addr, std::net::AddrParseError> {
-        Ipv4Addr::from_str(&self.content)
+    pub fn content_as_ip(&self) -> Result<IpAddr, std::net::AddrParseError> {
+        IpAddr::from_str(&self.content)
     }
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Default)]
 #[repr(i32)]
+#[allow(clippy::upper_case_acronyms)]
 pub enum DNSType {
     #[default]
     A = 1,
@@ -144,6 +307,36 @@ impl DNSType {
     }
 }
 
+impl FromStr for DNSType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_uppercase().as_str() {
+            "A" => Ok(DNSType::A),
+            "AAAA" => Ok(DNSType::AAAA),
+            "CAA" => Ok(DNSType::CAA),
+            "CERT" => Ok(DNSType::CERT),
+            "CNAME" => Ok(DNSType::CNAME),
+            "DNSKEY" => Ok(DNSType::DNSKEY),
+            "DS" => Ok(DNSType::DS),
+            "HTTPS" => Ok(DNSType::HTTPS),
+            "LOC" => Ok(DNSType::LOC),
+            "MX" => Ok(DNSType::MX),
+            "NAPTR" => Ok(DNSType::NAPTR),
+            "NS" => Ok(DNSType::NS),
+            "PTR" => Ok(DNSType::PTR),
+            "SMIMEA" => Ok(DNSType::SMIMEA),
+            "SRV" => Ok(DNSType::SRV),
+            "SSHFP" => Ok(DNSType::SSHFP),
+            "SVCB" => Ok(DNSType::SVCB),
+            "TLSA" => Ok(DNSType::TLSA),
+            "TXT" => Ok(DNSType::TXT),
+            "URI" => Ok(DNSType::URI),
+            _ => Err(format!("unsupported record type {}", s)),
+        }
+    }
+}
+
 #[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
 pub struct DNSRecordMeta {
     pub auto_added: bool,
@@ -151,6 +344,7 @@ pub struct DNSRecordMeta {
 }
 
 #[cfg(test)]
+#[allow(clippy::field_reassign_with_default, clippy::bool_assert_comparison)]
 mod tests {
     use super::*;
 
@@ -161,6 +355,88 @@ mod tests {
         assert_eq!(o.count(), 5);
     }
 
+    #[test]
+    fn batch_request_skips_empty_operations() {
+        let request = BatchDNSRecordsRequest {
+            deletes: vec![DeletedDNSRecord {
+                id: String::from("1234"),
+            }],
+            ..Default::default()
+        };
+
+        assert!(!request.is_empty());
+        assert_eq!(
+            serde_json::to_string(&request).unwrap(),
+            r#"{"deletes":[{"id":"1234"}]}"#
+        );
+        assert!(BatchDNSRecordsRequest::default().is_empty());
+    }
+
+    #[test]
+    fn error_display_includes_ray_and_truncated_body() {
+        let error = CloudFlareClientError::Status(RawResponse {
+            status: reqwest::StatusCode::BAD_GATEWAY,
+            body: "x".repeat(1000),
+            ray: Some(String::from("8a1b2c3d4e5f-YUL")),
+        });
+
+        let text = error.to_string();
+        assert!(text
+            .starts_with("unexpected status (HTTP 502 Bad Gateway, cf-ray 8a1b2c3d4e5f-YUL: xxx"));
+        assert!(text.ends_with("...)"));
+        assert!(text.len() < 300);
+    }
+
+    #[test]
+    fn api_error_display() {
+        let error = CloudFlareClientError::Api(ErrorResponse {
+            errors: vec![Message {
+                code: 81044,
+                message: String::from("Record does not exist."),
+            }],
+            messages: vec![],
+            success: false,
+        });
+
+        assert_eq!(
+            error.to_string(),
+            "Cloudflare API error: Record does not exist. (81044)"
+        );
+    }
+
+    #[test]
+    fn result_info_has_next_page() {
+        let info = ResultInfo {
+            count: 2,
+            page: 1,
+            per_page: 2,
+            total_count: 3,
+        };
+        assert!(info.has_next_page());
+    }
+
+    #[test]
+    fn result_info_last_page() {
+        let info = ResultInfo {
+            count: 1,
+            page: 2,
+            per_page: 2,
+            total_count: 3,
+        };
+        assert!(!info.has_next_page());
+    }
+
+    #[test]
+    fn result_info_empty_page() {
+        let info = ResultInfo {
+            count: 0,
+            page: 1,
+            per_page: 2,
+            total_count: 3,
+        };
+        assert!(!info.has_next_page());
+    }
+
     #[test]
     fn dns_record_has_tags() {
         let mut o = DNSRecord::default();
@@ -188,7 +464,18 @@ mod tests {
         o.content = String::from("127.0.0.1");
         assert_eq!(
             o.content_as_ip().unwrap(),
-            std::net::Ipv4Addr::new(127, 0, 0, 1)
+            IpAddr::V4(std::net::Ipv4Addr::new(127, 0, 0, 1))
+        );
+    }
+
+    #[test]
+    fn dns_record_content_as_ipv6_pass() {
+        let mut o = DNSRecord::default();
+        o.content = String::from("2001:db8::1");
+        o.r#type = DNSType::AAAA;
+        assert_eq!(
+            o.content_as_ip().unwrap(),
+            IpAddr::V6(std::net::Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1))
         );
     }
 }
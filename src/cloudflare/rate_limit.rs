@@ -0,0 +1,275 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, OnceLock},
+    time::Duration,
+};
+
+use chrono::{DateTime, Utc};
+use log::{debug, warn};
+use reqwest::header::HeaderMap;
+use tokio::time::Instant;
+
+/// Cloudflare allows 1200 requests per 5 minutes for each token
+const CAPACITY: u32 = 1200;
+const WINDOW: Duration = Duration::from_secs(300);
+
+/// Warn once the budget goes under this fraction of the capacity
+const LOW_BUDGET_RATIO: f64 = 0.1;
+
+/// Token bucket holding the requests left for an API token. It refills
+/// continuously, is drained by the `ratelimit` headers Cloudflare sends back
+/// and is emptied for the `Retry-After` duration when a 429 is received.
+pub struct RateLimiter {
+    capacity: f64,
+    /// Requests regained per second
+    refill_rate: f64,
+    bucket: Mutex<Bucket>,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    paused_until: Option<Instant>,
+}
+
+impl RateLimiter {
+    pub fn new(capacity: u32, window: Duration) -> Self {
+        Self {
+            capacity: capacity as f64,
+            refill_rate: capacity as f64 / window.as_secs_f64(),
+            bucket: Mutex::new(Bucket {
+                tokens: capacity as f64,
+                updated: Instant::now(),
+                paused_until: None,
+            }),
+        }
+    }
+
+    /// Limiter shared by every client using this API token
+    pub fn for_token(token: &str) -> Arc<RateLimiter> {
+        static LIMITERS: OnceLock<Mutex<HashMap<String, Arc<RateLimiter>>>> = OnceLock::new();
+
+        let mut limiters = LIMITERS
+            .get_or_init(|| Mutex::new(HashMap::new()))
+            .lock()
+            .unwrap();
+
+        limiters
+            .entry(String::from(token))
+            .or_insert_with(|| Arc::new(RateLimiter::new(CAPACITY, WINDOW)))
+            .clone()
+    }
+
+    /// Wait until a request can be sent and take it out of the budget
+    pub async fn acquire(&self) {
+        loop {
+            let wait = {
+                let mut bucket = self.bucket.lock().unwrap();
+                let now = Instant::now();
+                self.refill(&mut bucket, now);
+
+                match bucket.paused_until {
+                    Some(until) if until > now => until - now,
+                    _ if bucket.tokens >= 1.0 => {
+                        bucket.tokens -= 1.0;
+                        return;
+                    }
+                    _ => Duration::from_secs_f64((1.0 - bucket.tokens) / self.refill_rate),
+                }
+            };
+
+            debug!("Waiting {:?} for the Cloudflare rate limit", wait);
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    /// Requests that can be sent right now
+    pub fn remaining(&self) -> u32 {
+        let mut bucket = self.bucket.lock().unwrap();
+        let now = Instant::now();
+        self.refill(&mut bucket, now);
+
+        match bucket.paused_until {
+            Some(until) if until > now => 0,
+            _ => bucket.tokens as u32,
+        }
+    }
+
+    /// Stop sending requests for this long, after a 429
+    pub fn pause_for(&self, duration: Duration) {
+        let mut bucket = self.bucket.lock().unwrap();
+        let until = Instant::now() + duration;
+
+        bucket.tokens = 0.0;
+        bucket.paused_until = Some(match bucket.paused_until {
+            Some(paused_until) if paused_until > until => paused_until,
+            _ => until,
+        });
+    }
+
+    /// Align the budget with what Cloudflare says is left
+    pub fn observe_headers(&self, headers: &HeaderMap) {
+        if let Some(remaining) = parse_remaining(headers) {
+            let mut bucket = self.bucket.lock().unwrap();
+            bucket.tokens = bucket.tokens.min(remaining as f64);
+        }
+
+        let remaining = self.remaining();
+        debug!("Cloudflare API budget: {} requests left", remaining);
+
+        if (remaining as f64) < self.capacity * LOW_BUDGET_RATIO {
+            warn!(
+                "Cloudflare API budget is running low: {} requests left",
+                remaining
+            );
+        }
+    }
+
+    fn refill(&self, bucket: &mut Bucket, now: Instant) {
+        // Cloudflare accepts requests again once `Retry-After` is over
+        if let Some(until) = bucket.paused_until {
+            if until <= now {
+                bucket.paused_until = None;
+                bucket.tokens = bucket.tokens.max(1.0);
+            }
+        }
+
+        let elapsed = now.saturating_duration_since(bucket.updated);
+        bucket.tokens =
+            (bucket.tokens + elapsed.as_secs_f64() * self.refill_rate).min(self.capacity);
+        bucket.updated = now;
+    }
+}
+
+/// `Retry-After` is either a number of seconds or an HTTP date
+pub fn parse_retry_after(headers: &HeaderMap, now: DateTime<Utc>) -> Option<Duration> {
+    let value = headers.get("retry-after")?.to_str().ok()?.trim();
+
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+
+    let date = DateTime::parse_from_rfc2822(value).ok()?;
+    Some(
+        (date.with_timezone(&Utc) - now)
+            .to_std()
+            .unwrap_or_default(),
+    )
+}
+
+/// Requests left according to the `ratelimit` header (`"default";r=1199;t=300`)
+/// or the older `x-ratelimit-remaining`
+fn parse_remaining(headers: &HeaderMap) -> Option<u32> {
+    if let Some(value) = headers.get("ratelimit").and_then(|v| v.to_str().ok()) {
+        let remaining = value
+            .split(';')
+            .find_map(|param| param.trim().strip_prefix("r="));
+
+        if let Some(remaining) = remaining.and_then(|r| r.parse().ok()) {
+            return Some(remaining);
+        }
+    }
+
+    headers
+        .get("x-ratelimit-remaining")?
+        .to_str()
+        .ok()?
+        .trim()
+        .parse()
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use reqwest::header::HeaderValue;
+
+    use super::*;
+
+    fn headers(name: &'static str, value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(name, HeaderValue::from_str(value).unwrap());
+        headers
+    }
+
+    #[test]
+    fn retry_after_seconds() {
+        assert_eq!(
+            parse_retry_after(&headers("retry-after", "30"), Utc::now()),
+            Some(Duration::from_secs(30))
+        );
+    }
+
+    #[test]
+    fn retry_after_date() {
+        let now = DateTime::parse_from_rfc2822("Wed, 21 Oct 2015 07:28:00 GMT")
+            .unwrap()
+            .with_timezone(&Utc);
+
+        assert_eq!(
+            parse_retry_after(
+                &headers("retry-after", "Wed, 21 Oct 2015 07:29:30 GMT"),
+                now
+            ),
+            Some(Duration::from_secs(90))
+        );
+        assert_eq!(parse_retry_after(&HeaderMap::new(), now), None);
+    }
+
+    #[test]
+    fn remaining_headers() {
+        assert_eq!(
+            parse_remaining(&headers("ratelimit", "\"default\";r=42;t=300")),
+            Some(42)
+        );
+        assert_eq!(
+            parse_remaining(&headers("x-ratelimit-remaining", "7")),
+            Some(7)
+        );
+        assert_eq!(parse_remaining(&HeaderMap::new()), None);
+    }
+
+    #[tokio::test]
+    async fn bucket_is_drained_and_refilled() {
+        let limiter = RateLimiter::new(2, Duration::from_millis(200));
+
+        limiter.acquire().await;
+        limiter.acquire().await;
+        assert_eq!(limiter.remaining(), 0);
+
+        // a third request waits for the refill
+        let start = Instant::now();
+        limiter.acquire().await;
+        assert!(start.elapsed() >= Duration::from_millis(50));
+    }
+
+    #[tokio::test]
+    async fn pause_blocks_requests() {
+        let limiter = RateLimiter::new(10, Duration::from_secs(300));
+
+        limiter.pause_for(Duration::from_millis(100));
+        assert_eq!(limiter.remaining(), 0);
+
+        let start = Instant::now();
+        limiter.acquire().await;
+        assert!(start.elapsed() >= Duration::from_millis(100));
+    }
+
+    #[test]
+    fn observed_remaining_lowers_the_budget() {
+        let limiter = RateLimiter::new(100, Duration::from_secs(300));
+
+        limiter.observe_headers(&headers("ratelimit", "\"default\";r=5;t=300"));
+
+        assert_eq!(limiter.remaining(), 5);
+    }
+
+    #[test]
+    fn limiter_shared_per_token() {
+        let a = RateLimiter::for_token("rate-limit-test-token");
+        let b = RateLimiter::for_token("rate-limit-test-token");
+        let other = RateLimiter::for_token("rate-limit-other-token");
+
+        assert!(Arc::ptr_eq(&a, &b));
+        assert!(!Arc::ptr_eq(&a, &other));
+    }
+}
@@ -0,0 +1,154 @@
+use serde::{Deserialize, Serialize};
+
+use super::models::DNSRecord;
+
+/// Describes which DNS records are managed
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "lowercase", deny_unknown_fields)]
+pub enum RecordSelector {
+    /// Exact record name, or a glob where `*` matches any characters and `?` a single one
+    Name(String),
+    /// Cloudflare tag, `name:value` matches exactly while `name` matches any value
+    Tag(String),
+    /// Text contained in the record comment
+    Comment(String),
+}
+
+impl RecordSelector {
+    pub fn matches(&self, record: &DNSRecord) -> bool {
+        match self {
+            RecordSelector::Name(pattern) => {
+                glob_match(&pattern.to_lowercase(), &record.name.to_lowercase())
+            }
+            RecordSelector::Tag(tag) => match &record.tags {
+                Some(tags) => tags
+                    .iter()
+                    .any(|t| t == tag || (!tag.contains(':') && t.split(':').next() == Some(tag))),
+                None => false,
+            },
+            RecordSelector::Comment(marker) => match &record.comment {
+                Some(comment) => comment.contains(marker.as_str()),
+                None => false,
+            },
+        }
+    }
+
+    pub fn value(&self) -> &str {
+        match self {
+            RecordSelector::Name(v) | RecordSelector::Tag(v) | RecordSelector::Comment(v) => v,
+        }
+    }
+
+    pub fn key(&self) -> &'static str {
+        match self {
+            RecordSelector::Name(_) => "name",
+            RecordSelector::Tag(_) => "tag",
+            RecordSelector::Comment(_) => "comment",
+        }
+    }
+}
+
+/// True when the record is selected by any of the selectors
+pub fn is_selected(selectors: &[RecordSelector], record: &DNSRecord) -> bool {
+    selectors.iter().any(|s| s.matches(record))
+}
+
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+
+    let (mut p, mut t) = (0, 0);
+    // position of the last `*` in the pattern and the text position it was tried at
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            backtrack = Some((p, t));
+            p += 1;
+        } else if let Some((star_p, star_t)) = backtrack {
+            p = star_p + 1;
+            t = star_t + 1;
+            backtrack = Some((star_p, star_t + 1));
+        } else {
+            return false;
+        }
+    }
+
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(name: &str, tags: Option<Vec<&str>>, comment: Option<&str>) -> DNSRecord {
+        DNSRecord {
+            name: String::from(name),
+            tags: tags.map(|t| t.into_iter().map(String::from).collect()),
+            comment: comment.map(String::from),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn glob() {
+        assert!(glob_match("home.example.com", "home.example.com"));
+        assert!(glob_match("*.example.com", "home.example.com"));
+        assert!(glob_match("*.example.com", "a.b.example.com"));
+        assert!(glob_match("h?me.*", "home.example.com"));
+        assert!(glob_match("*", "example.com"));
+        assert!(!glob_match("*.example.com", "example.com"));
+        assert!(!glob_match("home.example.com", "home.example.org"));
+        assert!(!glob_match("h?me.example.com", "hme.example.com"));
+    }
+
+    #[test]
+    fn name_selector_is_case_insensitive() {
+        let selector = RecordSelector::Name(String::from("Home.Example.com"));
+        assert!(selector.matches(&record("home.example.com", None, None)));
+    }
+
+    #[test]
+    fn tag_selector() {
+        let exact = RecordSelector::Tag(String::from("cfdpip:managed"));
+        let name_only = RecordSelector::Tag(String::from("cfdpip"));
+
+        let tagged = record("a", Some(vec!["cfdpip:managed"]), None);
+        let other = record("a", Some(vec!["cfdpip:ignored"]), None);
+        let untagged = record("a", None, None);
+
+        assert!(exact.matches(&tagged));
+        assert!(!exact.matches(&other));
+        assert!(!exact.matches(&untagged));
+        assert!(name_only.matches(&tagged));
+        assert!(name_only.matches(&other));
+    }
+
+    #[test]
+    fn comment_selector() {
+        let selector = RecordSelector::Comment(String::from("#cfdpip"));
+        assert!(selector.matches(&record("a", None, Some("home router #cfdpip"))));
+        assert!(!selector.matches(&record("a", None, Some("home router"))));
+        assert!(!selector.matches(&record("a", None, None)));
+    }
+
+    #[test]
+    fn is_selected_any() {
+        let selectors = vec![
+            RecordSelector::Name(String::from("home.example.com")),
+            RecordSelector::Tag(String::from("cfdpip")),
+        ];
+        assert!(is_selected(
+            &selectors,
+            &record("home.example.com", None, None)
+        ));
+        assert!(is_selected(
+            &selectors,
+            &record("x", Some(vec!["cfdpip"]), None)
+        ));
+        assert!(!is_selected(&selectors, &record("x", None, None)));
+    }
+}
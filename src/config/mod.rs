@@ -0,0 +1,1261 @@
+use core::fmt;
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    cloudflare::selector::RecordSelector,
+    ip::{IpFamily, IpSourceConfig},
+    mqtt::command::command_topic,
+};
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(PathBuf, std::io::Error),
+    Parse(PathBuf, String),
+    Invalid { key: String, message: String },
+}
+
+impl ConfigError {
+    fn invalid(key: &str, message: &str) -> Self {
+        ConfigError::Invalid {
+            key: String::from(key),
+            message: String::from(message),
+        }
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigError::Io(path, e) => write!(f, "Could not read {}: {}", path.display(), e),
+            ConfigError::Parse(path, e) => write!(f, "Could not parse {}: {}", path.display(), e),
+            ConfigError::Invalid { key, message } => write!(f, "{}: {}", key, message),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// Full configuration, merged from the config file, environment variables and command line flags
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Log the changes to the DNS records instead of applying them
+    pub dry_run: bool,
+    pub cloudflare: CloudflareConfig,
+    pub zones: Vec<ZoneConfig>,
+    pub detection: DetectionConfig,
+    pub monitor: MonitorConfig,
+    pub retry: RetryConfig,
+    pub reconcile: ReconcileConfig,
+    pub state: StateConfig,
+    pub journal: JournalConfig,
+    pub mqtt: MqttConfig,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct CloudflareConfig {
+    pub token: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Default)]
+#[serde(deny_unknown_fields)]
+pub struct ZoneConfig {
+    #[serde(default)]
+    pub id: String,
+    /// Domain of the zone, looked up on Cloudflare instead of setting the id
+    pub name: Option<String>,
+    /// Managed records, when empty the records using the previous IP are updated
+    #[serde(default)]
+    pub records: Vec<RecordSelector>,
+    /// Proxy status the managed records should have, left as is when not set
+    pub proxied: Option<bool>,
+    /// TTL the managed records should have in seconds, 1 being automatic,
+    /// left as is when not set
+    pub ttl: Option<i32>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct DetectionConfig {
+    pub ip_versions: Vec<IpFamily>,
+    /// Tried in order until one finds the IP, unless a quorum is set
+    pub sources: Vec<IpSourceConfig>,
+    /// Query every source and require this many of them to agree
+    pub quorum: Option<usize>,
+}
+
+impl Default for DetectionConfig {
+    fn default() -> Self {
+        Self {
+            ip_versions: vec![IpFamily::Ipv4, IpFamily::Ipv6],
+            sources: vec![IpSourceConfig::PublicIp],
+            quorum: None,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct MonitorConfig {
+    /// Delay between IP checks in seconds
+    pub check_delay: u64,
+}
+
+impl Default for MonitorConfig {
+    fn default() -> Self {
+        Self { check_delay: 300 }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetryConfig {
+    /// Delay before the first retry to update the DNS records in seconds,
+    /// doubled after every failed attempt
+    pub delay: u64,
+    /// Longest delay between attempts in seconds
+    pub max_delay: u64,
+    /// Give up updating after this many attempts, retry forever when not set
+    pub max_attempts: Option<u32>,
+    /// Wait a random part of the delay so that instances do not retry together
+    pub jitter: bool,
+    pub circuit_breaker: CircuitBreakerConfig,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        Self {
+            delay: 120,
+            max_delay: 1800,
+            max_attempts: None,
+            jitter: true,
+            circuit_breaker: CircuitBreakerConfig::default(),
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct CircuitBreakerConfig {
+    /// Consecutive failed updates before the Cloudflare API is left alone
+    pub failure_threshold: u32,
+    /// Time in seconds without calling the API once the circuit is open
+    pub cooldown: u64,
+}
+
+impl Default for CircuitBreakerConfig {
+    fn default() -> Self {
+        Self {
+            failure_threshold: 5,
+            cooldown: 900,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ReconcileConfig {
+    pub enabled: bool,
+    /// Delay between drift checks of the managed records in seconds
+    pub interval: u64,
+    pub mode: DriftMode,
+}
+
+impl Default for ReconcileConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            interval: 3600,
+            mode: DriftMode::Fix,
+        }
+    }
+}
+
+/// What to do with a managed record that no longer has the expected content,
+/// proxy status or TTL
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum DriftMode {
+    /// Update the record back
+    Fix,
+    /// Only report it
+    Alert,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct StateConfig {
+    /// File keeping the last IP applied to the records, to repair them on start
+    pub path: PathBuf,
+}
+
+impl Default for StateConfig {
+    fn default() -> Self {
+        Self {
+            path: PathBuf::from("cfdpip-state.json"),
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct JournalConfig {
+    pub enabled: bool,
+    /// File keeping the records as they were before each change, to roll them
+    /// back, next to the state file when not set
+    pub path: Option<PathBuf>,
+    /// Changes kept in the journal, the oldest ones are dropped
+    pub max_entries: usize,
+}
+
+impl Default for JournalConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            path: None,
+            max_entries: 1000,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct MqttConfig {
+    pub enabled: bool,
+    pub host: Option<String>,
+    pub port: u16,
+    pub id: String,
+    pub base_topic: String,
+    pub username: Option<String>,
+    /// Only sent along with the username
+    pub password: Option<String>,
+    pub tls: MqttTlsConfig,
+    /// Seconds between the heartbeats of the monitor, 0 to disable them
+    pub heartbeat_interval: u64,
+    pub home_assistant: HomeAssistantConfig,
+}
+
+impl Default for MqttConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            host: None,
+            port: 1883,
+            id: String::from("cfdpip"),
+            base_topic: String::from("cfdpip"),
+            username: None,
+            password: None,
+            tls: MqttTlsConfig::default(),
+            heartbeat_interval: 60,
+            home_assistant: HomeAssistantConfig::default(),
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct HomeAssistantConfig {
+    /// Publish the discovery configs of the entities
+    pub enabled: bool,
+    pub discovery_prefix: String,
+}
+
+impl Default for HomeAssistantConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            discovery_prefix: String::from("homeassistant"),
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct MqttTlsConfig {
+    pub enabled: bool,
+    /// PEM certificates of the authorities signing the broker certificate,
+    /// the ones of the system when not set
+    pub ca_file: Option<PathBuf>,
+    /// PEM certificate and key authenticating cfdpip to the broker
+    pub client_cert_file: Option<PathBuf>,
+    pub client_key_file: Option<PathBuf>,
+}
+
+impl Config {
+    /// Read the config file if any, then apply the environment variable overrides
+    pub fn load(path: Option<&Path>) -> Result<Self, ConfigError> {
+        let mut config = match path {
+            Some(path) => Config::from_file(path)?,
+            None => Config::default(),
+        };
+
+        config.apply_env(|name| std::env::var(name).ok())?;
+
+        Ok(config)
+    }
+
+    /// Parse a TOML file, or a YAML file when the extension is `.yaml` or `.yml`
+    pub fn from_file(path: &Path) -> Result<Self, ConfigError> {
+        let text = match std::fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) => return Err(ConfigError::Io(path.to_path_buf(), e)),
+        };
+
+        let is_yaml = matches!(
+            path.extension().and_then(|e| e.to_str()),
+            Some("yaml") | Some("yml")
+        );
+
+        let parsed = if is_yaml {
+            serde_yaml::from_str(&text).map_err(|e| e.to_string())
+        } else {
+            toml::from_str(&text).map_err(|e| e.to_string())
+        };
+
+        parsed.map_err(|e| ConfigError::Parse(path.to_path_buf(), e))
+    }
+
+    pub fn apply_env(&mut self, env: impl Fn(&str) -> Option<String>) -> Result<(), ConfigError> {
+        if let Some(dry_run) = env("DRY_RUN") {
+            self.dry_run = dry_run
+                .parse()
+                .map_err(|_| ConfigError::invalid("DRY_RUN", "must be a boolean"))?;
+        }
+        if let Some(token) = env("CLOUDFLARE_TOKEN") {
+            self.cloudflare.token = Some(token);
+        }
+        let zone_id = env("CLOUDFLARE_ZONE_ID");
+        let zone_name = env("CLOUDFLARE_ZONE_NAME");
+        if zone_id.is_some() || zone_name.is_some() {
+            // a single zone replaced by a variable, never a list silently
+            if self.zones.len() > 1 {
+                let key = match zone_id {
+                    Some(_) => "CLOUDFLARE_ZONE_ID",
+                    None => "CLOUDFLARE_ZONE_NAME",
+                };
+                return Err(ConfigError::invalid(
+                    key,
+                    &format!(
+                        "cannot replace the {} zones of the config file",
+                        self.zones.len()
+                    ),
+                ));
+            }
+
+            // keep the settings of the zone being overridden
+            let zone = match self.zones.first() {
+                Some(zone) => zone.clone(),
+                None => ZoneConfig::default(),
+            };
+            self.zones = vec![ZoneConfig {
+                id: zone_id.unwrap_or_default(),
+                name: zone_name,
+                ..zone
+            }];
+        }
+        if let Some(names) = env("CLOUDFLARE_RECORDS") {
+            let records = names
+                .split(',')
+                .map(|n| n.trim())
+                .filter(|n| !n.is_empty())
+                .map(|n| RecordSelector::Name(String::from(n)))
+                .collect();
+
+            match self.zones.first_mut() {
+                Some(zone) => zone.records = records,
+                None => {
+                    return Err(ConfigError::invalid(
+                        "CLOUDFLARE_RECORDS",
+                        "requires a zone, set CLOUDFLARE_ZONE_ID",
+                    ))
+                }
+            }
+        }
+        if let Some(path) = env("STATE_FILE") {
+            self.state.path = PathBuf::from(path);
+        }
+        if let Some(path) = env("JOURNAL_FILE") {
+            self.journal.path = Some(PathBuf::from(path));
+        }
+        if let Some(enabled) = env("MQTT_ENABLED") {
+            self.mqtt.enabled = enabled
+                .parse()
+                .map_err(|_| ConfigError::invalid("MQTT_ENABLED", "must be a boolean"))?;
+        }
+        if let Some(host) = env("MQTT_HOST") {
+            self.mqtt.host = Some(host);
+        }
+        if let Some(port) = env("MQTT_PORT") {
+            self.mqtt.port = port
+                .parse()
+                .map_err(|_| ConfigError::invalid("MQTT_PORT", "must be a valid port number"))?;
+        }
+        if let Some(id) = env("MQTT_ID") {
+            self.mqtt.id = id;
+        }
+        if let Some(base_topic) = env("MQTT_BASE_TOPIC") {
+            self.mqtt.base_topic = base_topic;
+        }
+        if let Some(username) = env("MQTT_USERNAME") {
+            self.mqtt.username = Some(username);
+        }
+        if let Some(password) = env("MQTT_PASSWORD") {
+            self.mqtt.password = Some(password);
+        }
+        if let Some(enabled) = env("MQTT_TLS") {
+            self.mqtt.tls.enabled = enabled
+                .parse()
+                .map_err(|_| ConfigError::invalid("MQTT_TLS", "must be a boolean"))?;
+        }
+        if let Some(path) = env("MQTT_CA_FILE") {
+            self.mqtt.tls.ca_file = Some(PathBuf::from(path));
+        }
+        if let Some(path) = env("MQTT_CLIENT_CERT_FILE") {
+            self.mqtt.tls.client_cert_file = Some(PathBuf::from(path));
+        }
+        if let Some(path) = env("MQTT_CLIENT_KEY_FILE") {
+            self.mqtt.tls.client_key_file = Some(PathBuf::from(path));
+        }
+        if let Some(enabled) = env("MQTT_HOME_ASSISTANT") {
+            self.mqtt.home_assistant.enabled = enabled
+                .parse()
+                .map_err(|_| ConfigError::invalid("MQTT_HOME_ASSISTANT", "must be a boolean"))?;
+        }
+        if let Some(interval) = env("MQTT_HEARTBEAT_INTERVAL") {
+            self.mqtt.heartbeat_interval = interval.parse().map_err(|_| {
+                ConfigError::invalid("MQTT_HEARTBEAT_INTERVAL", "must be a number of seconds")
+            })?;
+        }
+
+        Ok(())
+    }
+
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        self.validate_cloudflare()?;
+        self.detection.validate("detection")?;
+        self.monitor.validate("monitor")?;
+        self.retry.validate("retry")?;
+        self.reconcile.validate("reconcile")?;
+        self.state.validate("state")?;
+        self.journal.validate("journal")?;
+        self.mqtt.validate("mqtt")?;
+
+        // the MQTT sources receive their messages over the MQTT connection,
+        // routed to a single receiver by their exact topic
+        let mut topics = HashSet::new();
+        for (i, source) in self.detection.sources.iter().enumerate() {
+            let IpSourceConfig::Mqtt { topic, .. } = source else {
+                continue;
+            };
+
+            if !self.mqtt.enabled {
+                return Err(ConfigError::invalid(
+                    &format!("detection.sources[{}]", i),
+                    "requires mqtt.enabled",
+                ));
+            }
+
+            // the wildcards are refused along with the other source options
+            let key = format!("detection.sources[{}].topic", i);
+            if *topic == command_topic(&self.mqtt.base_topic) {
+                return Err(ConfigError::invalid(&key, "is the topic of the commands"));
+            }
+            if !topics.insert(topic) {
+                return Err(ConfigError::invalid(&key, "is used by another source"));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// File of the journal, next to the state file unless set
+    pub fn journal_path(&self) -> PathBuf {
+        match self.journal.path {
+            Some(ref path) => path.clone(),
+            None => self.state.path.with_file_name("cfdpip-journal.jsonl"),
+        }
+    }
+
+    /// Only validate what is needed to talk to the Cloudflare API
+    pub fn validate_cloudflare(&self) -> Result<(), ConfigError> {
+        match &self.cloudflare.token {
+            Some(token) if !token.is_empty() => {}
+            _ => {
+                return Err(ConfigError::invalid(
+                    "cloudflare.token",
+                    "is required, set it in the config file or with CLOUDFLARE_TOKEN",
+                ))
+            }
+        }
+
+        if self.zones.is_empty() {
+            return Err(ConfigError::invalid(
+                "zones",
+                "at least one zone is required, set it in the config file or with CLOUDFLARE_ZONE_ID or CLOUDFLARE_ZONE_NAME",
+            ));
+        }
+
+        for (i, zone) in self.zones.iter().enumerate() {
+            match (zone.id.is_empty(), &zone.name) {
+                (true, None) => {
+                    return Err(ConfigError::invalid(
+                        &format!("zones[{}].id", i),
+                        "must not be empty",
+                    ))
+                }
+                (false, Some(_)) => {
+                    return Err(ConfigError::invalid(
+                        &format!("zones[{}].name", i),
+                        "cannot be set along with the id",
+                    ))
+                }
+                (true, Some(name)) if name.trim().is_empty() => {
+                    return Err(ConfigError::invalid(
+                        &format!("zones[{}].name", i),
+                        "must not be empty",
+                    ))
+                }
+                _ => {}
+            }
+
+            if let Some(ttl) = zone.ttl {
+                if ttl != 1 && !(30..=86400).contains(&ttl) {
+                    return Err(ConfigError::invalid(
+                        &format!("zones[{}].ttl", i),
+                        "must be 1 for automatic, or between 30 and 86400 seconds",
+                    ));
+                }
+            }
+
+            for (j, selector) in zone.records.iter().enumerate() {
+                if selector.value().is_empty() {
+                    return Err(ConfigError::invalid(
+                        &format!("zones[{}].records[{}].{}", i, j, selector.key()),
+                        "must not be empty",
+                    ));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Copy of the configuration safe to print
+    pub fn redacted(&self) -> Self {
+        let mut config = self.clone();
+        if config.cloudflare.token.is_some() {
+            config.cloudflare.token = Some(String::from("********"));
+        }
+        if config.mqtt.password.is_some() {
+            config.mqtt.password = Some(String::from("********"));
+        }
+        config
+    }
+}
+
+impl DetectionConfig {
+    pub fn validate(&self, key: &str) -> Result<(), ConfigError> {
+        let versions_key = format!("{}.ip_versions", key);
+
+        if self.ip_versions.is_empty() {
+            return Err(ConfigError::invalid(&versions_key, "must not be empty"));
+        }
+
+        for (i, family) in self.ip_versions.iter().enumerate() {
+            if self.ip_versions[..i].contains(family) {
+                return Err(ConfigError::invalid(
+                    &format!("{}[{}]", versions_key, i),
+                    &format!("{} is listed more than once", family),
+                ));
+            }
+        }
+
+        let sources_key = format!("{}.sources", key);
+
+        if self.sources.is_empty() {
+            return Err(ConfigError::invalid(&sources_key, "must not be empty"));
+        }
+
+        if let Some(quorum) = self.quorum {
+            if quorum == 0 || quorum > self.sources.len() {
+                return Err(ConfigError::invalid(
+                    &format!("{}.quorum", key),
+                    &format!(
+                        "must be between 1 and the number of sources ({})",
+                        self.sources.len()
+                    ),
+                ));
+            }
+        }
+
+        for (i, source) in self.sources.iter().enumerate() {
+            if let Err(e) = source.build() {
+                return Err(ConfigError::invalid(
+                    &format!("{}[{}]", sources_key, i),
+                    &e.to_string(),
+                ));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl MonitorConfig {
+    pub fn validate(&self, key: &str) -> Result<(), ConfigError> {
+        if self.check_delay == 0 {
+            return Err(ConfigError::invalid(
+                &format!("{}.check_delay", key),
+                "must be greater than 0",
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl RetryConfig {
+    pub fn validate(&self, key: &str) -> Result<(), ConfigError> {
+        if self.max_attempts == Some(0) {
+            return Err(ConfigError::invalid(
+                &format!("{}.max_attempts", key),
+                "must be greater than 0",
+            ));
+        }
+
+        if self.max_delay < self.delay {
+            return Err(ConfigError::invalid(
+                &format!("{}.max_delay", key),
+                &format!("must be at least the delay of {} seconds", self.delay),
+            ));
+        }
+
+        if self.circuit_breaker.failure_threshold == 0 {
+            return Err(ConfigError::invalid(
+                &format!("{}.circuit_breaker.failure_threshold", key),
+                "must be greater than 0",
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl ReconcileConfig {
+    pub fn validate(&self, key: &str) -> Result<(), ConfigError> {
+        if self.enabled && self.interval == 0 {
+            return Err(ConfigError::invalid(
+                &format!("{}.interval", key),
+                "must be greater than 0",
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl StateConfig {
+    pub fn validate(&self, key: &str) -> Result<(), ConfigError> {
+        if self.path.as_os_str().is_empty() {
+            return Err(ConfigError::invalid(
+                &format!("{}.path", key),
+                "must not be empty",
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl JournalConfig {
+    pub fn validate(&self, key: &str) -> Result<(), ConfigError> {
+        if !self.enabled {
+            return Ok(());
+        }
+
+        if matches!(self.path, Some(ref path) if path.as_os_str().is_empty()) {
+            return Err(ConfigError::invalid(
+                &format!("{}.path", key),
+                "must not be empty",
+            ));
+        }
+        if self.max_entries == 0 {
+            return Err(ConfigError::invalid(
+                &format!("{}.max_entries", key),
+                "must be at least 1",
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl MqttConfig {
+    pub fn validate(&self, key: &str) -> Result<(), ConfigError> {
+        if !self.enabled {
+            return Ok(());
+        }
+
+        match &self.host {
+            Some(host) if !host.is_empty() => {}
+            _ => {
+                return Err(ConfigError::invalid(
+                    &format!("{}.host", key),
+                    "is required when MQTT is enabled",
+                ))
+            }
+        }
+
+        if self.id.is_empty() {
+            return Err(ConfigError::invalid(
+                &format!("{}.id", key),
+                "must not be empty",
+            ));
+        }
+
+        if self.base_topic.is_empty() {
+            return Err(ConfigError::invalid(
+                &format!("{}.base_topic", key),
+                "must not be empty",
+            ));
+        }
+
+        match (&self.username, &self.password) {
+            (Some(username), _) if username.is_empty() => {
+                return Err(ConfigError::invalid(
+                    &format!("{}.username", key),
+                    "must not be empty",
+                ))
+            }
+            (None, Some(_)) => {
+                return Err(ConfigError::invalid(
+                    &format!("{}.password", key),
+                    "requires a username",
+                ))
+            }
+            _ => {}
+        }
+
+        self.tls.validate(&format!("{}.tls", key))?;
+
+        if self.home_assistant.enabled && self.home_assistant.discovery_prefix.is_empty() {
+            return Err(ConfigError::invalid(
+                &format!("{}.home_assistant.discovery_prefix", key),
+                "must not be empty",
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+impl MqttTlsConfig {
+    pub fn validate(&self, key: &str) -> Result<(), ConfigError> {
+        let files = [&self.ca_file, &self.client_cert_file, &self.client_key_file];
+        if !self.enabled && files.iter().any(|f| f.is_some()) {
+            return Err(ConfigError::invalid(
+                &format!("{}.enabled", key),
+                "must be true to use the certificate files",
+            ));
+        }
+
+        match (&self.client_cert_file, &self.client_key_file) {
+            (Some(_), None) => Err(ConfigError::invalid(
+                &format!("{}.client_key_file", key),
+                "is required with client_cert_file",
+            )),
+            (None, Some(_)) => Err(ConfigError::invalid(
+                &format!("{}.client_cert_file", key),
+                "is required with client_key_file",
+            )),
+            (Some(_), Some(_)) if self.ca_file.is_none() => Err(ConfigError::invalid(
+                &format!("{}.ca_file", key),
+                "is required with a client certificate",
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    fn valid_config() -> Config {
+        Config {
+            cloudflare: CloudflareConfig {
+                token: Some(String::from("token")),
+            },
+            zones: vec![ZoneConfig {
+                id: String::from("1234"),
+                ..Default::default()
+            }],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn parse_toml() {
+        let config: Config = toml::from_str(
+            r#"
+            zones = [{ id = "1234" }]
+
+            [cloudflare]
+            token = "abc"
+
+            [detection]
+            ip_versions = ["ipv6"]
+
+            [mqtt]
+            enabled = true
+            host = "localhost"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.cloudflare.token, Some(String::from("abc")));
+        assert_eq!(config.zones[0].id, "1234");
+        assert_eq!(config.detection.ip_versions, vec![IpFamily::Ipv6]);
+        assert_eq!(config.monitor.check_delay, 300);
+        assert_eq!(config.mqtt.port, 1883);
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn parse_yaml() {
+        let config: Config = serde_yaml::from_str(
+            r#"
+            cloudflare:
+              token: abc
+            zones:
+              - id: "1234"
+            monitor:
+              check_delay: 60
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.monitor.check_delay, 60);
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn parse_unknown_key_is_reported() {
+        let error = toml::from_str::<Config>("[mqtt]\nhots = \"localhost\"\n").unwrap_err();
+        assert!(error.to_string().contains("hots"));
+    }
+
+    #[test]
+    fn env_overrides_file() {
+        let mut config = valid_config();
+        let env = HashMap::from([
+            ("CLOUDFLARE_ZONE_ID", "5678"),
+            ("MQTT_ENABLED", "true"),
+            ("MQTT_HOST", "broker"),
+            ("MQTT_PORT", "8883"),
+            ("STATE_FILE", "/data/state.json"),
+            ("JOURNAL_FILE", "/data/journal.jsonl"),
+            ("DRY_RUN", "true"),
+        ]);
+
+        config
+            .apply_env(|name| env.get(name).map(|v| String::from(*v)))
+            .unwrap();
+
+        assert_eq!(config.zones[0].id, "5678");
+        assert!(config.mqtt.enabled);
+        assert_eq!(config.mqtt.host, Some(String::from("broker")));
+        assert_eq!(config.mqtt.port, 8883);
+        assert_eq!(config.state.path, PathBuf::from("/data/state.json"));
+        assert_eq!(config.journal_path(), PathBuf::from("/data/journal.jsonl"));
+        assert!(config.dry_run);
+    }
+
+    #[test]
+    fn journal_defaults_next_to_the_state_file() {
+        let mut config = valid_config();
+        config.state.path = PathBuf::from("/data/state.json");
+
+        assert_eq!(
+            config.journal_path(),
+            PathBuf::from("/data/cfdpip-journal.jsonl")
+        );
+    }
+
+    #[test]
+    fn env_zone_refuses_to_replace_several_zones() {
+        let mut config = valid_config();
+        config.zones.push(ZoneConfig {
+            name: Some(String::from("example.org")),
+            ..ZoneConfig::default()
+        });
+        let env = HashMap::from([("CLOUDFLARE_ZONE_NAME", "example.com")]);
+
+        let error = config
+            .apply_env(|name| env.get(name).map(|v| String::from(*v)))
+            .unwrap_err();
+
+        assert_eq!(
+            error.to_string(),
+            "CLOUDFLARE_ZONE_NAME: cannot replace the 2 zones of the config file"
+        );
+        assert_eq!(config.zones.len(), 2);
+    }
+
+    #[test]
+    fn parse_record_selectors() {
+        let config: Config = toml::from_str(
+            r##"
+            [[zones]]
+            id = "1234"
+            records = [
+                { name = "*.example.com" },
+                { tag = "cfdpip:managed" },
+                { comment = "#cfdpip" },
+            ]
+            "##,
+        )
+        .unwrap();
+
+        assert_eq!(
+            config.zones[0].records,
+            vec![
+                RecordSelector::Name(String::from("*.example.com")),
+                RecordSelector::Tag(String::from("cfdpip:managed")),
+                RecordSelector::Comment(String::from("#cfdpip")),
+            ]
+        );
+    }
+
+    #[test]
+    fn env_records_override_selectors() {
+        let mut config = valid_config();
+        config.zones[0].records = vec![RecordSelector::Tag(String::from("cfdpip"))];
+
+        config
+            .apply_env(|name| match name {
+                "CLOUDFLARE_ZONE_ID" => Some(String::from("5678")),
+                "CLOUDFLARE_RECORDS" => Some(String::from("a.example.com, b.example.com")),
+                _ => None,
+            })
+            .unwrap();
+
+        assert_eq!(config.zones[0].id, "5678");
+        assert_eq!(
+            config.zones[0].records,
+            vec![
+                RecordSelector::Name(String::from("a.example.com")),
+                RecordSelector::Name(String::from("b.example.com")),
+            ]
+        );
+    }
+
+    #[test]
+    fn validate_empty_selector() {
+        let mut config = valid_config();
+        config.zones[0].records = vec![RecordSelector::Tag(String::new())];
+
+        let error = config.validate().unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "zones[0].records[0].tag: must not be empty"
+        );
+    }
+
+    #[test]
+    fn env_invalid_value_points_at_variable() {
+        let mut config = valid_config();
+
+        let error = config
+            .apply_env(|name| match name {
+                "MQTT_PORT" => Some(String::from("abc")),
+                _ => None,
+            })
+            .unwrap_err();
+
+        assert_eq!(error.to_string(), "MQTT_PORT: must be a valid port number");
+    }
+
+    #[test]
+    fn validate_missing_token() {
+        let mut config = valid_config();
+        config.cloudflare.token = None;
+
+        let error = config.validate().unwrap_err();
+        assert!(error.to_string().starts_with("cloudflare.token:"));
+    }
+
+    #[test]
+    fn validate_empty_zone_id() {
+        let mut config = valid_config();
+        config.zones.push(ZoneConfig::default());
+
+        let error = config.validate().unwrap_err();
+        assert_eq!(error.to_string(), "zones[1].id: must not be empty");
+    }
+
+    #[test]
+    fn zone_by_name() {
+        let config: Config = toml::from_str(
+            r#"
+            [cloudflare]
+            token = "abc"
+
+            [[zones]]
+            name = "example.com"
+            "#,
+        )
+        .unwrap();
+        assert!(config.validate().is_ok());
+        assert_eq!(config.zones[0].name.as_deref(), Some("example.com"));
+
+        let mut config = valid_config();
+        config.zones[0].name = Some(String::from("example.com"));
+        let error = config.validate().unwrap_err();
+        assert!(error.to_string().starts_with("zones[0].name:"));
+    }
+
+    #[test]
+    fn zone_name_from_env() {
+        let mut config = valid_config();
+        config
+            .apply_env(|key| match key {
+                "CLOUDFLARE_ZONE_NAME" => Some(String::from("example.com")),
+                _ => None,
+            })
+            .unwrap();
+
+        assert_eq!(config.zones[0].id, "");
+        assert_eq!(config.zones[0].name.as_deref(), Some("example.com"));
+    }
+
+    #[test]
+    fn mqtt_authentication_and_tls() {
+        let config: Config = toml::from_str(
+            r#"
+            [mqtt]
+            enabled = true
+            host = "broker"
+            port = 8883
+            username = "cfdpip"
+            password = "secret"
+
+            [mqtt.tls]
+            enabled = true
+            ca_file = "ca.pem"
+            client_cert_file = "client.pem"
+            client_key_file = "client.key"
+            "#,
+        )
+        .unwrap();
+
+        assert!(config.mqtt.validate("mqtt").is_ok());
+        assert_eq!(config.mqtt.username.as_deref(), Some("cfdpip"));
+        assert_eq!(config.mqtt.tls.ca_file, Some(PathBuf::from("ca.pem")));
+        assert_eq!(config.redacted().mqtt.password.as_deref(), Some("********"));
+    }
+
+    #[test]
+    fn validate_mqtt_tls() {
+        let mut config = valid_config();
+        config.mqtt.enabled = true;
+        config.mqtt.host = Some(String::from("broker"));
+
+        config.mqtt.password = Some(String::from("secret"));
+        let error = config.validate().unwrap_err();
+        assert_eq!(error.to_string(), "mqtt.password: requires a username");
+        config.mqtt.username = Some(String::from("cfdpip"));
+
+        config.mqtt.tls.client_cert_file = Some(PathBuf::from("client.pem"));
+        let error = config.validate().unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "mqtt.tls.enabled: must be true to use the certificate files"
+        );
+
+        config.mqtt.tls.enabled = true;
+        let error = config.validate().unwrap_err();
+        assert!(error.to_string().starts_with("mqtt.tls.client_key_file:"));
+
+        config.mqtt.tls.client_key_file = Some(PathBuf::from("client.key"));
+        let error = config.validate().unwrap_err();
+        assert!(error.to_string().starts_with("mqtt.tls.ca_file:"));
+
+        config.mqtt.tls.ca_file = Some(PathBuf::from("ca.pem"));
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn validate_mqtt_host_required_when_enabled() {
+        let mut config = valid_config();
+        config.mqtt.enabled = true;
+
+        let error = config.validate().unwrap_err();
+        assert!(error.to_string().starts_with("mqtt.host:"));
+    }
+
+    #[test]
+    fn validate_duplicate_ip_version() {
+        let mut config = valid_config();
+        config.detection.ip_versions = vec![IpFamily::Ipv4, IpFamily::Ipv4];
+
+        let error = config.validate().unwrap_err();
+        assert!(error.to_string().starts_with("detection.ip_versions[1]:"));
+    }
+
+    #[test]
+    fn validate_invalid_source() {
+        let mut config = valid_config();
+        config.detection.sources = vec![
+            IpSourceConfig::PublicIp,
+            IpSourceConfig::Http {
+                url: String::from("https://api.ipify.org"),
+                json_path: None,
+                regex: Some(String::from("(")),
+            },
+        ];
+
+        let error = config.validate().unwrap_err();
+        assert!(error
+            .to_string()
+            .starts_with("detection.sources[1]: invalid regex"));
+    }
+
+    #[test]
+    fn validate_mqtt_source_requires_mqtt() {
+        let mut config = valid_config();
+        config.detection.sources = vec![IpSourceConfig::Mqtt {
+            topic: String::from("router/wan/ip"),
+            json_path: None,
+        }];
+
+        let error = config.validate().unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "detection.sources[0]: requires mqtt.enabled"
+        );
+
+        config.mqtt.enabled = true;
+        config.mqtt.host = Some(String::from("localhost"));
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn validate_mqtt_source_topics() {
+        let mut config = valid_config();
+        config.mqtt.enabled = true;
+        config.mqtt.host = Some(String::from("localhost"));
+        config.detection.sources = vec![
+            IpSourceConfig::Mqtt {
+                topic: String::from("router/wan/ip"),
+                json_path: None,
+            },
+            IpSourceConfig::Mqtt {
+                topic: String::from("router/+/ip"),
+                json_path: None,
+            },
+        ];
+
+        let error = config.validate().unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "detection.sources[1]: wildcards are not supported in topic router/+/ip"
+        );
+
+        config.detection.sources[1] = IpSourceConfig::Mqtt {
+            topic: String::from("router/wan/ip"),
+            json_path: Some(String::from("ipv4")),
+        };
+        let error = config.validate().unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "detection.sources[1].topic: is used by another source"
+        );
+
+        config.detection.sources[1] = IpSourceConfig::Mqtt {
+            topic: format!("{}/cmd", config.mqtt.base_topic),
+            json_path: None,
+        };
+        let error = config.validate().unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "detection.sources[1].topic: is the topic of the commands"
+        );
+    }
+
+    #[test]
+    fn validate_quorum_larger_than_sources() {
+        let mut config = valid_config();
+        config.detection.quorum = Some(2);
+
+        let error = config.validate().unwrap_err();
+        assert!(error.to_string().starts_with("detection.quorum:"));
+    }
+
+    #[test]
+    fn validate_retry_max_delay_below_delay() {
+        let mut config = valid_config();
+        config.retry.delay = 600;
+        config.retry.max_delay = 60;
+
+        let error = config.validate().unwrap_err();
+        assert!(error.to_string().starts_with("retry.max_delay:"));
+    }
+
+    #[test]
+    fn parse_circuit_breaker() {
+        let config: Config = toml::from_str(
+            r#"
+            [retry]
+            delay = 30
+
+            [retry.circuit_breaker]
+            failure_threshold = 3
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.retry.delay, 30);
+        assert!(config.retry.jitter);
+        assert_eq!(config.retry.circuit_breaker.failure_threshold, 3);
+        assert_eq!(config.retry.circuit_breaker.cooldown, 900);
+    }
+
+    #[test]
+    fn parse_drift_settings() {
+        let config: Config = toml::from_str(
+            r#"
+            zones = [{ id = "1234", proxied = false, ttl = 300 }]
+
+            [reconcile]
+            mode = "alert"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.zones[0].proxied, Some(false));
+        assert_eq!(config.zones[0].ttl, Some(300));
+        assert_eq!(config.reconcile.mode, DriftMode::Alert);
+        assert_eq!(config.reconcile.interval, 3600);
+    }
+
+    #[test]
+    fn validate_zone_ttl() {
+        let mut config = valid_config();
+        config.zones[0].ttl = Some(1);
+        assert!(config.validate().is_ok());
+
+        config.zones[0].ttl = Some(10);
+        let error = config.validate().unwrap_err();
+        assert!(error.to_string().starts_with("zones[0].ttl:"));
+    }
+
+    #[test]
+    fn redacted_hides_token() {
+        let config = valid_config().redacted();
+        assert_eq!(config.cloudflare.token, Some(String::from("********")));
+    }
+}
@@ -0,0 +1,387 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use tokio::net::UdpSocket;
+
+use super::{parse_ip, parse_socket_addr, IpFamily, IpSource, IpSourceError};
+
+const TYPE_A: u16 = 1;
+const TYPE_TXT: u16 = 16;
+const TYPE_AAAA: u16 = 28;
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+pub fn default_name() -> String {
+    String::from("myip.opendns.com")
+}
+
+pub fn default_servers() -> Vec<String> {
+    vec![
+        String::from("208.67.222.222"),
+        String::from("208.67.220.220"),
+        String::from("2620:0:ccc::2"),
+        String::from("2620:0:ccd::2"),
+    ]
+}
+
+/// Which record holds our IP in the answer
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum DnsQuery {
+    /// `A` record for IPv4, `AAAA` for IPv6
+    #[default]
+    Address,
+    Txt,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum DnsClass {
+    #[default]
+    In,
+    /// Chaos class, used by `whoami.cloudflare`
+    Ch,
+}
+
+impl DnsClass {
+    fn code(&self) -> u16 {
+        match self {
+            DnsClass::In => 1,
+            DnsClass::Ch => 3,
+        }
+    }
+}
+
+/// Asks a DNS server who we are, such as `myip.opendns.com` on the OpenDNS
+/// resolvers or `whoami.cloudflare` TXT CH on `1.1.1.1`. The server sees the
+/// address of the family used to reach it, so only servers of the requested
+/// family are queried.
+pub struct DnsSource {
+    name: String,
+    servers: Vec<SocketAddr>,
+    query: DnsQuery,
+    class: DnsClass,
+}
+
+impl DnsSource {
+    pub fn new(
+        name: &str,
+        servers: &[String],
+        query: DnsQuery,
+        class: DnsClass,
+    ) -> Result<Self, IpSourceError> {
+        let mut parsed = Vec::new();
+
+        for server in servers {
+            parsed.push(parse_socket_addr(server, 53)?);
+        }
+
+        if name.is_empty() || name.split('.').any(|label| label.len() > 63) {
+            return Err(IpSourceError::Config(format!(
+                "invalid DNS name {:?}",
+                name
+            )));
+        }
+
+        Ok(Self {
+            name: String::from(name),
+            servers: parsed,
+            query,
+            class,
+        })
+    }
+
+    async fn query_server(
+        &self,
+        server: SocketAddr,
+        family: IpFamily,
+    ) -> Result<IpAddr, IpSourceError> {
+        let record_type = match (self.query, family) {
+            (DnsQuery::Address, IpFamily::Ipv4) => TYPE_A,
+            (DnsQuery::Address, IpFamily::Ipv6) => TYPE_AAAA,
+            (DnsQuery::Txt, _) => TYPE_TXT,
+        };
+
+        let local: SocketAddr = match server {
+            SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+            SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+        };
+
+        let socket = UdpSocket::bind(local).await?;
+        socket.connect(server).await?;
+
+        let id: u16 = rand::random();
+        socket
+            .send(&build_query(id, &self.name, record_type, self.class.code()))
+            .await?;
+
+        let mut buf = [0u8; 1500];
+        let len = match tokio::time::timeout(TIMEOUT, socket.recv(&mut buf)).await {
+            Ok(res) => res?,
+            Err(_) => {
+                return Err(IpSourceError::Io(std::io::Error::new(
+                    std::io::ErrorKind::TimedOut,
+                    format!("no answer from {}", server),
+                )))
+            }
+        };
+
+        let answers = parse_answers(&buf[..len], id, record_type)?;
+
+        answers
+            .iter()
+            .find_map(|answer| parse_ip(answer, family).ok())
+            .ok_or(IpSourceError::NotFound(answers.join(" ")))
+    }
+}
+
+#[async_trait]
+impl IpSource for DnsSource {
+    fn name(&self) -> String {
+        format!("dns {}", self.name)
+    }
+
+    async fn detect(&self, family: IpFamily) -> Result<IpAddr, IpSourceError> {
+        let mut result = Err(IpSourceError::Config(format!(
+            "no {} DNS server configured",
+            family
+        )));
+
+        for server in self
+            .servers
+            .iter()
+            .filter(|s| IpFamily::of(&s.ip()) == family)
+        {
+            result = self.query_server(*server, family).await;
+            if result.is_ok() {
+                break;
+            }
+        }
+
+        result
+    }
+}
+
+fn build_query(id: u16, name: &str, record_type: u16, class: u16) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(32 + name.len());
+
+    packet.extend_from_slice(&id.to_be_bytes());
+    // standard query, recursion desired
+    packet.extend_from_slice(&0x0100u16.to_be_bytes());
+    // one question, no answer, authority or additional records
+    packet.extend_from_slice(&[0, 1, 0, 0, 0, 0, 0, 0]);
+
+    for label in name.trim_end_matches('.').split('.') {
+        packet.push(label.len() as u8);
+        packet.extend_from_slice(label.as_bytes());
+    }
+    packet.push(0);
+
+    packet.extend_from_slice(&record_type.to_be_bytes());
+    packet.extend_from_slice(&class.to_be_bytes());
+
+    packet
+}
+
+/// Text of the answers of the given type, addresses are formatted as text
+fn parse_answers(packet: &[u8], id: u16, record_type: u16) -> Result<Vec<String>, IpSourceError> {
+    let malformed = || IpSourceError::NotFound(String::from("malformed DNS answer"));
+
+    let read_u16 = |pos: usize| -> Result<u16, IpSourceError> {
+        match packet.get(pos..pos + 2) {
+            Some(bytes) => Ok(u16::from_be_bytes([bytes[0], bytes[1]])),
+            None => Err(malformed()),
+        }
+    };
+
+    if read_u16(0)? != id {
+        return Err(IpSourceError::NotFound(String::from(
+            "DNS answer id does not match",
+        )));
+    }
+
+    let rcode = read_u16(2)? & 0x000f;
+    if rcode != 0 {
+        return Err(IpSourceError::NotFound(format!(
+            "DNS answer with response code {}",
+            rcode
+        )));
+    }
+
+    let questions = read_u16(4)?;
+    let answers = read_u16(6)?;
+
+    let mut pos = 12;
+    for _ in 0..questions {
+        pos = skip_name(packet, pos).ok_or_else(malformed)? + 4;
+    }
+
+    let mut found = Vec::new();
+
+    for _ in 0..answers {
+        pos = skip_name(packet, pos).ok_or_else(malformed)?;
+        let answer_type = read_u16(pos)?;
+        let length = read_u16(pos + 8)? as usize;
+        pos += 10;
+
+        let data = packet.get(pos..pos + length).ok_or_else(malformed)?;
+        pos += length;
+
+        if answer_type != record_type {
+            continue;
+        }
+
+        match answer_type {
+            TYPE_A if length == 4 => {
+                found.push(Ipv4Addr::new(data[0], data[1], data[2], data[3]).to_string())
+            }
+            TYPE_AAAA if length == 16 => {
+                let mut octets = [0u8; 16];
+                octets.copy_from_slice(data);
+                found.push(Ipv6Addr::from(octets).to_string())
+            }
+            TYPE_TXT => {
+                // one or more length prefixed strings
+                let mut text = String::new();
+                let mut i = 0;
+                while i < data.len() {
+                    let len = data[i] as usize;
+                    let part = data.get(i + 1..i + 1 + len).ok_or_else(malformed)?;
+                    text.push_str(&String::from_utf8_lossy(part));
+                    i += 1 + len;
+                }
+                found.push(text)
+            }
+            _ => {}
+        }
+    }
+
+    Ok(found)
+}
+
+/// Position right after the name starting at `pos`
+fn skip_name(packet: &[u8], mut pos: usize) -> Option<usize> {
+    loop {
+        let len = *packet.get(pos)?;
+        if len == 0 {
+            return Some(pos + 1);
+        }
+        if len & 0xc0 == 0xc0 {
+            // compression pointer
+            return Some(pos + 2);
+        }
+        pos += 1 + len as usize;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Answer the first query received on the socket with a single record
+    async fn answer_once(socket: UdpSocket, record_type: u16, data: Vec<u8>) {
+        let mut buf = [0u8; 512];
+        let (len, peer) = socket.recv_from(&mut buf).await.unwrap();
+        let query = &buf[..len];
+
+        let mut answer = Vec::from(&query[..2]);
+        // response, recursion desired and available
+        answer.extend_from_slice(&[0x81, 0x80, 0, 1, 0, 1, 0, 0, 0, 0]);
+        answer.extend_from_slice(&query[12..]);
+        // pointer to the name in the question
+        answer.extend_from_slice(&[0xc0, 12]);
+        answer.extend_from_slice(&record_type.to_be_bytes());
+        answer.extend_from_slice(&query[len - 2..]);
+        answer.extend_from_slice(&[0, 0, 0, 0]);
+        answer.extend_from_slice(&(data.len() as u16).to_be_bytes());
+        answer.extend_from_slice(&data);
+
+        socket.send_to(&answer, peer).await.unwrap();
+    }
+
+    #[test]
+    fn query_packet() {
+        let packet = build_query(0x1234, "myip.opendns.com", TYPE_A, 1);
+        assert_eq!(&packet[..2], &[0x12, 0x34]);
+        assert_eq!(&packet[12..17], &[4, b'm', b'y', b'i', b'p']);
+        assert_eq!(&packet[packet.len() - 4..], &[0, 1, 0, 1]);
+    }
+
+    #[test]
+    fn answer_with_wrong_id() {
+        let packet = build_query(1, "a", TYPE_A, 1);
+        assert!(parse_answers(&packet, 2, TYPE_A).is_err());
+    }
+
+    #[test]
+    fn truncated_answer() {
+        let mut packet = build_query(1, "a", TYPE_A, 1);
+        packet[7] = 1;
+        assert!(parse_answers(&packet, 1, TYPE_A).is_err());
+    }
+
+    #[test]
+    fn invalid_server() {
+        assert!(DnsSource::new(
+            "myip.opendns.com",
+            &[String::from("resolver1")],
+            DnsQuery::Address,
+            DnsClass::In
+        )
+        .is_err());
+    }
+
+    #[tokio::test]
+    async fn dns_source_address() {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let server = socket.local_addr().unwrap().to_string();
+        tokio::spawn(answer_once(socket, TYPE_A, vec![1, 2, 3, 4]));
+
+        let source = DnsSource::new(
+            "myip.opendns.com",
+            &[server],
+            DnsQuery::Address,
+            DnsClass::In,
+        )
+        .unwrap();
+
+        assert_eq!(
+            source.detect(IpFamily::Ipv4).await.unwrap(),
+            IpAddr::V4(Ipv4Addr::new(1, 2, 3, 4))
+        );
+    }
+
+    #[tokio::test]
+    async fn dns_source_txt() {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let server = socket.local_addr().unwrap().to_string();
+        let mut txt = vec![7];
+        txt.extend_from_slice(b"1.2.3.4");
+        tokio::spawn(answer_once(socket, TYPE_TXT, txt));
+
+        let source =
+            DnsSource::new("whoami.cloudflare", &[server], DnsQuery::Txt, DnsClass::Ch).unwrap();
+
+        assert_eq!(
+            source.detect(IpFamily::Ipv4).await.unwrap(),
+            IpAddr::V4(Ipv4Addr::new(1, 2, 3, 4))
+        );
+    }
+
+    #[tokio::test]
+    async fn dns_source_without_server_of_family() {
+        let source = DnsSource::new(
+            "myip.opendns.com",
+            &[String::from("127.0.0.1")],
+            DnsQuery::Address,
+            DnsClass::In,
+        )
+        .unwrap();
+
+        assert!(source.detect(IpFamily::Ipv6).await.is_err());
+    }
+}
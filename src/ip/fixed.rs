@@ -0,0 +1,105 @@
+use std::{
+    net::IpAddr,
+    path::{Path, PathBuf},
+};
+
+use async_trait::async_trait;
+
+use super::{parse_ip, IpFamily, IpSource, IpSourceError};
+
+/// Always returns the configured addresses
+pub struct StaticSource {
+    addresses: Vec<IpAddr>,
+}
+
+impl StaticSource {
+    pub fn new(addresses: Vec<IpAddr>) -> Self {
+        Self { addresses }
+    }
+}
+
+#[async_trait]
+impl IpSource for StaticSource {
+    fn name(&self) -> String {
+        String::from("static")
+    }
+
+    async fn detect(&self, family: IpFamily) -> Result<IpAddr, IpSourceError> {
+        self.addresses
+            .iter()
+            .find(|ip| IpFamily::of(ip) == family)
+            .copied()
+            .ok_or(IpSourceError::NotFound(format!(
+                "{} of the static addresses",
+                family
+            )))
+    }
+}
+
+/// Reads the addresses from a file, one per line, written by another tool
+pub struct FileSource {
+    path: PathBuf,
+}
+
+impl FileSource {
+    pub fn new(path: &Path) -> Self {
+        Self {
+            path: path.to_path_buf(),
+        }
+    }
+}
+
+#[async_trait]
+impl IpSource for FileSource {
+    fn name(&self) -> String {
+        format!("file {}", self.path.display())
+    }
+
+    async fn detect(&self, family: IpFamily) -> Result<IpAddr, IpSourceError> {
+        let text = tokio::fs::read_to_string(&self.path).await?;
+
+        text.lines()
+            .find_map(|line| parse_ip(line, family).ok())
+            .ok_or(IpSourceError::NotFound(text))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn static_source() {
+        let source = StaticSource::new(vec![IpAddr::V6(Ipv6Addr::LOCALHOST)]);
+
+        assert_eq!(
+            source.detect(IpFamily::Ipv6).await.unwrap(),
+            IpAddr::V6(Ipv6Addr::LOCALHOST)
+        );
+        assert!(source.detect(IpFamily::Ipv4).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn file_source() {
+        let path = std::env::temp_dir().join(format!("cfdpip-file-source-{}", std::process::id()));
+        std::fs::write(&path, "2001:db8::1\n1.2.3.4\n").unwrap();
+
+        let source = FileSource::new(&path);
+        let ip = source.detect(IpFamily::Ipv4).await;
+
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(ip.unwrap(), IpAddr::V4(Ipv4Addr::new(1, 2, 3, 4)));
+    }
+
+    #[tokio::test]
+    async fn file_source_missing_file() {
+        let source = FileSource::new(Path::new("/this/file/does/not/exist"));
+        assert!(matches!(
+            source.detect(IpFamily::Ipv4).await,
+            Err(IpSourceError::Io(_))
+        ));
+    }
+}
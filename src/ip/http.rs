@@ -0,0 +1,278 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use regex::Regex;
+
+use super::{parse_ip, IpFamily, IpSource, IpSourceError};
+
+pub fn default_trace_url() -> String {
+    String::from("https://cloudflare.com/cdn-cgi/trace")
+}
+
+/// One HTTP client per address family, bound to the unspecified address of
+/// that family so that the request goes out over IPv4 or IPv6 as asked
+struct FamilyClients {
+    v4: reqwest::Client,
+    v6: reqwest::Client,
+}
+
+impl FamilyClients {
+    fn new() -> Result<Self, IpSourceError> {
+        let build = |local: IpAddr| {
+            reqwest::Client::builder()
+                .local_address(local)
+                .timeout(Duration::from_secs(10))
+                .build()
+        };
+
+        Ok(Self {
+            v4: build(IpAddr::V4(Ipv4Addr::UNSPECIFIED))?,
+            v6: build(IpAddr::V6(Ipv6Addr::UNSPECIFIED))?,
+        })
+    }
+
+    async fn get_text(&self, url: &str, family: IpFamily) -> Result<String, IpSourceError> {
+        let client = match family {
+            IpFamily::Ipv4 => &self.v4,
+            IpFamily::Ipv6 => &self.v6,
+        };
+
+        let res = client.get(url).send().await?.error_for_status()?;
+        Ok(res.text().await?)
+    }
+}
+
+/// Echo service returning the IP of the caller, either as plain text, in a
+/// JSON document or somewhere a regex can find it
+pub struct HttpSource {
+    url: String,
+    json_path: Option<String>,
+    regex: Option<Regex>,
+    clients: FamilyClients,
+}
+
+impl HttpSource {
+    pub fn new(
+        url: &str,
+        json_path: Option<&str>,
+        regex: Option<&str>,
+    ) -> Result<Self, IpSourceError> {
+        if let Err(e) = reqwest::Url::parse(url) {
+            return Err(IpSourceError::Config(format!("invalid url {}: {}", url, e)));
+        }
+
+        let regex = match regex.map(Regex::new) {
+            Some(Ok(regex)) => Some(regex),
+            Some(Err(e)) => return Err(IpSourceError::Config(format!("invalid regex: {}", e))),
+            None => None,
+        };
+
+        Ok(Self {
+            url: String::from(url),
+            json_path: json_path.map(String::from),
+            regex,
+            clients: FamilyClients::new()?,
+        })
+    }
+
+    fn extract(&self, body: &str) -> Result<String, IpSourceError> {
+        let mut text = String::from(body);
+
+        if let Some(ref path) = self.json_path {
+            let json: serde_json::Value = match serde_json::from_str(&text) {
+                Ok(json) => json,
+                Err(_) => return Err(IpSourceError::NotFound(text)),
+            };
+
+            text = match json_path_lookup(&json, path) {
+                Some(serde_json::Value::String(s)) => s.clone(),
+                Some(value) => value.to_string(),
+                None => return Err(IpSourceError::NotFound(text)),
+            };
+        }
+
+        if let Some(ref regex) = self.regex {
+            let captures = match regex.captures(&text) {
+                Some(captures) => captures,
+                None => return Err(IpSourceError::NotFound(text)),
+            };
+
+            // first capture group when there is one, the whole match otherwise
+            let found = captures.get(1).or(captures.get(0));
+            text = found.map(|m| String::from(m.as_str())).unwrap_or_default();
+        }
+
+        Ok(text)
+    }
+}
+
+#[async_trait]
+impl IpSource for HttpSource {
+    fn name(&self) -> String {
+        format!("http {}", self.url)
+    }
+
+    async fn detect(&self, family: IpFamily) -> Result<IpAddr, IpSourceError> {
+        let body = self.clients.get_text(&self.url, family).await?;
+        parse_ip(&self.extract(&body)?, family)
+    }
+}
+
+/// Look up a dotted path such as `data.ip` or `$.addresses.0`
+pub(super) fn json_path_lookup<'a>(
+    value: &'a serde_json::Value,
+    path: &str,
+) -> Option<&'a serde_json::Value> {
+    let path = path.strip_prefix("$").unwrap_or(path);
+
+    path.split('.')
+        .filter(|key| !key.is_empty())
+        .try_fold(value, |value, key| match value {
+            serde_json::Value::Array(items) => items.get(key.parse::<usize>().ok()?),
+            _ => value.get(key),
+        })
+}
+
+/// Cloudflare `cdn-cgi/trace` endpoint, answering `key=value` lines with an `ip` key
+pub struct CloudflareTraceSource {
+    url: String,
+    clients: FamilyClients,
+}
+
+impl CloudflareTraceSource {
+    pub fn new(url: &str) -> Result<Self, IpSourceError> {
+        if let Err(e) = reqwest::Url::parse(url) {
+            return Err(IpSourceError::Config(format!("invalid url {}: {}", url, e)));
+        }
+
+        Ok(Self {
+            url: String::from(url),
+            clients: FamilyClients::new()?,
+        })
+    }
+}
+
+#[async_trait]
+impl IpSource for CloudflareTraceSource {
+    fn name(&self) -> String {
+        format!("cloudflare-trace {}", self.url)
+    }
+
+    async fn detect(&self, family: IpFamily) -> Result<IpAddr, IpSourceError> {
+        let body = self.clients.get_text(&self.url, family).await?;
+
+        match parse_trace(&body) {
+            Some(ip) => parse_ip(ip, family),
+            None => Err(IpSourceError::NotFound(body)),
+        }
+    }
+}
+
+fn parse_trace(body: &str) -> Option<&str> {
+    body.lines().find_map(|line| line.strip_prefix("ip="))
+}
+
+#[cfg(test)]
+mod tests {
+    use httpmock::prelude::*;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn http_source_plain_text() {
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.method(GET).path("/");
+            then.status(200).body("1.2.3.4\n");
+        });
+
+        let source = HttpSource::new(&server.url("/"), None, None).unwrap();
+
+        assert_eq!(
+            source.detect(IpFamily::Ipv4).await.unwrap(),
+            IpAddr::V4(Ipv4Addr::new(1, 2, 3, 4))
+        );
+    }
+
+    #[tokio::test]
+    async fn http_source_json_path() {
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.method(GET).path("/");
+            then.status(200)
+                .header("content-type", "application/json")
+                .body(r#"{ "data": { "addresses": ["1.2.3.4"] } }"#);
+        });
+
+        let source = HttpSource::new(&server.url("/"), Some("$.data.addresses.0"), None).unwrap();
+
+        assert_eq!(
+            source.detect(IpFamily::Ipv4).await.unwrap(),
+            IpAddr::V4(Ipv4Addr::new(1, 2, 3, 4))
+        );
+    }
+
+    #[tokio::test]
+    async fn http_source_regex() {
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.method(GET).path("/");
+            then.status(200)
+                .body("<html><body>Current IP Address: 1.2.3.4</body></html>");
+        });
+
+        let source = HttpSource::new(&server.url("/"), None, Some(r"Address: ([0-9.]+)")).unwrap();
+
+        assert_eq!(
+            source.detect(IpFamily::Ipv4).await.unwrap(),
+            IpAddr::V4(Ipv4Addr::new(1, 2, 3, 4))
+        );
+    }
+
+    #[tokio::test]
+    async fn http_source_error_status() {
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.method(GET).path("/");
+            then.status(502).body("1.2.3.4");
+        });
+
+        let source = HttpSource::new(&server.url("/"), None, None).unwrap();
+
+        assert!(matches!(
+            source.detect(IpFamily::Ipv4).await,
+            Err(IpSourceError::Request(_))
+        ));
+    }
+
+    #[test]
+    fn http_source_invalid_config() {
+        assert!(HttpSource::new("not a url", None, None).is_err());
+        assert!(HttpSource::new("http://localhost", None, Some("(")).is_err());
+    }
+
+    #[tokio::test]
+    async fn cloudflare_trace_source() {
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.method(GET).path("/cdn-cgi/trace");
+            then.status(200)
+                .body("fl=1f1\nh=cloudflare.com\nip=1.2.3.4\nts=1.2\nvisit_scheme=https\n");
+        });
+
+        let source = CloudflareTraceSource::new(&server.url("/cdn-cgi/trace")).unwrap();
+
+        assert_eq!(
+            source.detect(IpFamily::Ipv4).await.unwrap(),
+            IpAddr::V4(Ipv4Addr::new(1, 2, 3, 4))
+        );
+    }
+
+    #[test]
+    fn parse_trace_without_ip() {
+        assert_eq!(parse_trace("fl=1f1\nh=cloudflare.com\n"), None);
+    }
+}
@@ -0,0 +1,110 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use async_trait::async_trait;
+
+use super::{IpFamily, IpSource, IpSourceError};
+
+/// Public address assigned to a local network interface, for hosts that are
+/// directly connected or get a global IPv6 prefix
+pub struct InterfaceSource {
+    name: Option<String>,
+}
+
+impl InterfaceSource {
+    pub fn new(name: Option<&str>) -> Self {
+        Self {
+            name: name.map(String::from),
+        }
+    }
+}
+
+#[async_trait]
+impl IpSource for InterfaceSource {
+    fn name(&self) -> String {
+        match self.name {
+            Some(ref name) => format!("interface {}", name),
+            None => String::from("interface"),
+        }
+    }
+
+    async fn detect(&self, family: IpFamily) -> Result<IpAddr, IpSourceError> {
+        let interfaces = if_addrs::get_if_addrs()?;
+
+        interfaces
+            .iter()
+            .filter(|i| self.name.as_ref().map_or(true, |name| &i.name == name))
+            .map(|i| i.ip())
+            .find(|ip| IpFamily::of(ip) == family && is_public(ip))
+            .ok_or(IpSourceError::NotFound(format!(
+                "{} of the local interfaces",
+                family
+            )))
+    }
+}
+
+/// Whether the address can be reached from the internet
+fn is_public(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            !(ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_broadcast()
+                || ip.is_unspecified()
+                || ip.is_documentation()
+                || is_shared_v4(ip))
+        }
+        IpAddr::V6(ip) => is_global_unicast_v6(ip) && !is_documentation_v6(ip),
+    }
+}
+
+/// 100.64.0.0/10, carrier grade NAT
+fn is_shared_v4(ip: &Ipv4Addr) -> bool {
+    ip.octets()[0] == 100 && (ip.octets()[1] & 0xc0) == 64
+}
+
+/// 2000::/3
+fn is_global_unicast_v6(ip: &Ipv6Addr) -> bool {
+    (ip.segments()[0] & 0xe000) == 0x2000
+}
+
+/// 2001:db8::/32
+fn is_documentation_v6(ip: &Ipv6Addr) -> bool {
+    ip.segments()[0] == 0x2001 && ip.segments()[1] == 0x0db8
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+
+    fn public(ip: &str) -> bool {
+        is_public(&IpAddr::from_str(ip).unwrap())
+    }
+
+    #[test]
+    fn public_addresses() {
+        assert!(public("1.2.3.4"));
+        assert!(public("2606:4700:4700::1111"));
+    }
+
+    #[test]
+    fn non_public_addresses() {
+        assert!(!public("10.0.0.1"));
+        assert!(!public("192.168.1.1"));
+        assert!(!public("127.0.0.1"));
+        assert!(!public("169.254.1.1"));
+        assert!(!public("100.64.0.1"));
+        assert!(!public("::1"));
+        assert!(!public("fe80::1"));
+        assert!(!public("fd00::1"));
+        assert!(!public("2001:db8::1"));
+    }
+
+    #[tokio::test]
+    async fn unknown_interface() {
+        let source = InterfaceSource::new(Some("this-interface-does-not-exist"));
+        assert!(source.detect(IpFamily::Ipv4).await.is_err());
+    }
+}
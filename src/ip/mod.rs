@@ -0,0 +1,611 @@
+use core::fmt;
+use std::{
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    str::FromStr,
+};
+
+use async_trait::async_trait;
+use futures::future::join_all;
+use log::{debug, trace, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::cloudflare::models::DNSType;
+
+pub mod dns;
+pub mod fixed;
+pub mod http;
+pub mod interface;
+pub mod mqtt;
+pub mod pcp;
+pub mod stun;
+mod udp;
+pub mod upnp;
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum IpFamily {
+    Ipv4,
+    Ipv6,
+}
+
+impl fmt::Display for IpFamily {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            IpFamily::Ipv4 => write!(f, "IPv4"),
+            IpFamily::Ipv6 => write!(f, "IPv6"),
+        }
+    }
+}
+
+impl IpFamily {
+    pub fn of(ip: &IpAddr) -> Self {
+        match ip {
+            IpAddr::V4(_) => IpFamily::Ipv4,
+            IpAddr::V6(_) => IpFamily::Ipv6,
+        }
+    }
+
+    /// DNS record type holding addresses of this family
+    pub fn record_type(&self) -> DNSType {
+        match self {
+            IpFamily::Ipv4 => DNSType::A,
+            IpFamily::Ipv6 => DNSType::AAAA,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum IpSourceError {
+    Request(reqwest::Error),
+    Io(std::io::Error),
+    /// The source answered but no IP address could be extracted
+    NotFound(String),
+    /// The source returned an address of the other family
+    WrongFamily(IpAddr),
+    /// The source cannot detect addresses of this family
+    Unsupported(IpFamily),
+    Config(String),
+}
+
+impl fmt::Display for IpSourceError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            IpSourceError::Request(e) => write!(f, "request failed: {}", e),
+            IpSourceError::Io(e) => write!(f, "{}", e),
+            IpSourceError::NotFound(text) => write!(f, "no IP address found in {:?}", text),
+            IpSourceError::WrongFamily(ip) => {
+                write!(f, "got {} address {}", IpFamily::of(ip), ip)
+            }
+            IpSourceError::Unsupported(family) => write!(f, "{} is not supported", family),
+            IpSourceError::Config(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for IpSourceError {}
+
+impl From<reqwest::Error> for IpSourceError {
+    fn from(value: reqwest::Error) -> Self {
+        IpSourceError::Request(value)
+    }
+}
+
+impl From<std::io::Error> for IpSourceError {
+    fn from(value: std::io::Error) -> Self {
+        IpSourceError::Io(value)
+    }
+}
+
+/// Parse an IP address and make sure it belongs to the expected family
+pub fn parse_ip(text: &str, family: IpFamily) -> Result<IpAddr, IpSourceError> {
+    let ip = match IpAddr::from_str(text.trim()) {
+        Ok(ip) => ip,
+        Err(_) => return Err(IpSourceError::NotFound(String::from(text.trim()))),
+    };
+
+    if IpFamily::of(&ip) != family {
+        return Err(IpSourceError::WrongFamily(ip));
+    }
+
+    Ok(ip)
+}
+
+/// Parse `ip`, `ip:port` or `[ipv6]:port`
+pub fn parse_socket_addr(text: &str, default_port: u16) -> Result<SocketAddr, IpSourceError> {
+    match SocketAddr::from_str(text) {
+        Ok(addr) => Ok(addr),
+        Err(_) => match IpAddr::from_str(text) {
+            Ok(ip) => Ok(SocketAddr::new(ip, default_port)),
+            Err(_) => Err(IpSourceError::Config(format!("invalid address {}", text))),
+        },
+    }
+}
+
+/// A way to find the current public IP
+#[async_trait]
+pub trait IpSource: Send + Sync {
+    /// Short description used in logs
+    fn name(&self) -> String;
+
+    async fn detect(&self, family: IpFamily) -> Result<IpAddr, IpSourceError>;
+}
+
+/// Configuration of an IP source, see the README for every option
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "kebab-case", deny_unknown_fields)]
+pub enum IpSourceConfig {
+    /// Builtin list of public HTTP and DNS services
+    PublicIp,
+    Http {
+        url: String,
+        json_path: Option<String>,
+        regex: Option<String>,
+    },
+    CloudflareTrace {
+        #[serde(default = "http::default_trace_url")]
+        url: String,
+    },
+    Dns {
+        #[serde(default = "dns::default_name")]
+        name: String,
+        #[serde(default = "dns::default_servers")]
+        servers: Vec<String>,
+        #[serde(default)]
+        query: dns::DnsQuery,
+        #[serde(default)]
+        class: dns::DnsClass,
+    },
+    Interface {
+        name: Option<String>,
+    },
+    /// STUN servers, `host`, `host:port`, `ip` or `[ipv6]:port`
+    Stun {
+        #[serde(default = "stun::default_servers")]
+        servers: Vec<String>,
+    },
+    /// UPnP IGD gateway, found with SSDP unless `location` is set
+    Upnp {
+        #[serde(default = "upnp::default_ssdp_address")]
+        ssdp_address: String,
+        location: Option<String>,
+    },
+    /// NAT-PMP gateway, the default gateway when not set
+    NatPmp {
+        gateway: Option<String>,
+    },
+    /// PCP gateway, the default gateway when not set
+    Pcp {
+        gateway: Option<String>,
+    },
+    Static {
+        addresses: Vec<IpAddr>,
+    },
+    File {
+        path: PathBuf,
+    },
+    /// Messages published by another device, received by `monitor` only
+    Mqtt {
+        topic: String,
+        json_path: Option<String>,
+    },
+}
+
+impl IpSourceConfig {
+    pub fn build(&self) -> Result<Box<dyn IpSource>, IpSourceError> {
+        Ok(match self {
+            IpSourceConfig::PublicIp => Box::new(PublicIpSource),
+            IpSourceConfig::Http {
+                url,
+                json_path,
+                regex,
+            } => Box::new(http::HttpSource::new(
+                url,
+                json_path.as_deref(),
+                regex.as_deref(),
+            )?),
+            IpSourceConfig::CloudflareTrace { url } => {
+                Box::new(http::CloudflareTraceSource::new(url)?)
+            }
+            IpSourceConfig::Dns {
+                name,
+                servers,
+                query,
+                class,
+            } => Box::new(dns::DnsSource::new(name, servers, *query, *class)?),
+            IpSourceConfig::Interface { name } => {
+                Box::new(interface::InterfaceSource::new(name.as_deref()))
+            }
+            IpSourceConfig::Stun { servers } => Box::new(stun::StunSource::new(servers)?),
+            IpSourceConfig::Upnp {
+                ssdp_address,
+                location,
+            } => Box::new(upnp::UpnpSource::new(ssdp_address, location.as_deref())?),
+            IpSourceConfig::NatPmp { gateway } => {
+                Box::new(pcp::NatPmpSource::new(gateway.as_deref())?)
+            }
+            IpSourceConfig::Pcp { gateway } => Box::new(pcp::PcpSource::new(gateway.as_deref())?),
+            IpSourceConfig::Static { addresses } => {
+                Box::new(fixed::StaticSource::new(addresses.clone()))
+            }
+            IpSourceConfig::File { path } => Box::new(fixed::FileSource::new(path)),
+            IpSourceConfig::Mqtt { topic, json_path } => {
+                Box::new(mqtt::MqttSource::new(topic, json_path.as_deref())?)
+            }
+        })
+    }
+}
+
+/// Uses the `public-ip` crate resolvers
+pub struct PublicIpSource;
+
+#[async_trait]
+impl IpSource for PublicIpSource {
+    fn name(&self) -> String {
+        String::from("public-ip")
+    }
+
+    async fn detect(&self, family: IpFamily) -> Result<IpAddr, IpSourceError> {
+        let ip = match family {
+            IpFamily::Ipv4 => public_ip::addr_v4().await.map(IpAddr::V4),
+            IpFamily::Ipv6 => public_ip::addr_v6().await.map(IpAddr::V6),
+        };
+
+        ip.ok_or(IpSourceError::NotFound(String::from("public-ip resolvers")))
+    }
+}
+
+/// Result of a single source
+pub struct SourceResult {
+    pub source: String,
+    pub result: Result<IpAddr, IpSourceError>,
+}
+
+/// Finds the public IP using the configured sources. Without a quorum, the
+/// sources are tried in order until one succeeds. With a quorum, every source
+/// is queried concurrently and an IP is only accepted when at least `quorum`
+/// sources agree on it.
+pub struct Detector {
+    sources: Vec<Box<dyn IpSource>>,
+    quorum: Option<usize>,
+    /// The MQTT sources among `sources`, to feed them the received messages
+    mqtt_sources: Vec<mqtt::MqttSource>,
+}
+
+impl Detector {
+    pub fn new(sources: Vec<Box<dyn IpSource>>, quorum: Option<usize>) -> Self {
+        Self {
+            sources,
+            quorum,
+            mqtt_sources: Vec::new(),
+        }
+    }
+
+    pub fn from_config(
+        configs: &[IpSourceConfig],
+        quorum: Option<usize>,
+    ) -> Result<Self, IpSourceError> {
+        let mut sources: Vec<Box<dyn IpSource>> = Vec::new();
+        let mut mqtt_sources = Vec::new();
+
+        for config in configs {
+            match config {
+                IpSourceConfig::Mqtt { topic, json_path } => {
+                    let source = mqtt::MqttSource::new(topic, json_path.as_deref())?;
+                    mqtt_sources.push(source.clone());
+                    sources.push(Box::new(source));
+                }
+                _ => sources.push(config.build()?),
+            }
+        }
+
+        Ok(Self {
+            mqtt_sources,
+            ..Self::new(sources, quorum)
+        })
+    }
+
+    pub fn mqtt_sources(&self) -> &[mqtt::MqttSource] {
+        &self.mqtt_sources
+    }
+
+    /// Get the current public IP for the given address family
+    pub async fn detect(&self, family: IpFamily) -> Option<IpAddr> {
+        if self.quorum.is_some() {
+            let results = self.detect_all(family).await;
+            return self.decide(family, &results);
+        }
+
+        for source in &self.sources {
+            match source.detect(family).await {
+                Ok(ip) => {
+                    trace!("{} found {} {}", source.name(), family, ip);
+                    return Some(ip);
+                }
+                Err(e) => debug!("{} could not get {}: {}", source.name(), family, e),
+            }
+        }
+
+        None
+    }
+
+    /// Query every source concurrently, results are in the configured order
+    pub async fn detect_all(&self, family: IpFamily) -> Vec<SourceResult> {
+        let results = join_all(self.sources.iter().map(|s| s.detect(family))).await;
+
+        self.sources
+            .iter()
+            .zip(results)
+            .map(|(source, result)| SourceResult {
+                source: source.name(),
+                result,
+            })
+            .collect()
+    }
+
+    /// Pick the IP from the results of every source
+    pub fn decide(&self, family: IpFamily, results: &[SourceResult]) -> Option<IpAddr> {
+        let quorum = match self.quorum {
+            Some(quorum) => quorum,
+            None => {
+                return results.iter().find_map(|r| r.result.as_ref().ok().copied());
+            }
+        };
+
+        // every distinct IP with the sources that returned it, in order of first appearance
+        let mut votes: Vec<(IpAddr, Vec<&str>)> = Vec::new();
+
+        for result in results {
+            match &result.result {
+                Ok(ip) => match votes.iter_mut().find(|(v, _)| v == ip) {
+                    Some((_, sources)) => sources.push(&result.source),
+                    None => votes.push((*ip, vec![&result.source])),
+                },
+                Err(e) => debug!("{} could not get {}: {}", result.source, family, e),
+            }
+        }
+
+        if votes.len() > 1 {
+            let text: Vec<String> = votes
+                .iter()
+                .map(|(ip, sources)| format!("{} ({})", ip, sources.join(", ")))
+                .collect();
+            warn!("Sources disagree on {}: {}", family, text.join(", "));
+        }
+
+        votes.sort_by_key(|(_, sources)| std::cmp::Reverse(sources.len()));
+
+        let (ip, sources) = votes.first()?;
+
+        if votes
+            .get(1)
+            .is_some_and(|second| second.1.len() == sources.len())
+        {
+            warn!("No {} has more votes than the others", family);
+            return None;
+        }
+
+        if sources.len() < quorum {
+            warn!(
+                "{} {} is only returned by {} of the {} required sources",
+                family,
+                ip,
+                sources.len(),
+                quorum
+            );
+            return None;
+        }
+
+        Some(*ip)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    use super::*;
+
+    #[test]
+    fn ip_family_of() {
+        assert_eq!(
+            IpFamily::of(&IpAddr::V4(Ipv4Addr::LOCALHOST)),
+            IpFamily::Ipv4
+        );
+        assert_eq!(
+            IpFamily::of(&IpAddr::V6(Ipv6Addr::LOCALHOST)),
+            IpFamily::Ipv6
+        );
+    }
+
+    #[test]
+    fn ip_family_record_type() {
+        assert_eq!(IpFamily::Ipv4.record_type(), DNSType::A);
+        assert_eq!(IpFamily::Ipv6.record_type(), DNSType::AAAA);
+    }
+
+    #[test]
+    fn parse_ip_checks_family() {
+        assert_eq!(
+            parse_ip(" 1.2.3.4\n", IpFamily::Ipv4).unwrap(),
+            IpAddr::V4(Ipv4Addr::new(1, 2, 3, 4))
+        );
+        assert!(matches!(
+            parse_ip("1.2.3.4", IpFamily::Ipv6),
+            Err(IpSourceError::WrongFamily(_))
+        ));
+        assert!(matches!(
+            parse_ip("<html>", IpFamily::Ipv4),
+            Err(IpSourceError::NotFound(_))
+        ));
+    }
+
+    #[test]
+    fn parse_source_config() {
+        #[derive(Deserialize)]
+        struct Sources {
+            sources: Vec<IpSourceConfig>,
+        }
+
+        let parsed: Sources = toml::from_str(
+            r#"
+            sources = [
+                { type = "http", url = "https://api64.ipify.org?format=json", json_path = "ip" },
+                { type = "cloudflare-trace" },
+                { type = "dns", name = "whoami.cloudflare", servers = ["1.1.1.1"], query = "txt", class = "ch" },
+                { type = "static", addresses = ["1.2.3.4"] },
+                { type = "upnp" },
+                { type = "nat-pmp", gateway = "192.168.1.1" },
+                { type = "stun" },
+            ]
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            parsed.sources[1],
+            IpSourceConfig::CloudflareTrace {
+                url: http::default_trace_url()
+            }
+        );
+        assert_eq!(
+            parsed.sources[4],
+            IpSourceConfig::Upnp {
+                ssdp_address: upnp::default_ssdp_address(),
+                location: None
+            }
+        );
+        assert_eq!(
+            parsed.sources[5],
+            IpSourceConfig::NatPmp {
+                gateway: Some(String::from("192.168.1.1"))
+            }
+        );
+        assert_eq!(
+            parsed.sources[6],
+            IpSourceConfig::Stun {
+                servers: stun::default_servers()
+            }
+        );
+        assert!(matches!(
+            parsed.sources[2],
+            IpSourceConfig::Dns {
+                query: dns::DnsQuery::Txt,
+                class: dns::DnsClass::Ch,
+                ..
+            }
+        ));
+    }
+
+    #[tokio::test]
+    async fn detector_detects_the_received_mqtt_messages() {
+        let detector = Detector::from_config(
+            &[
+                IpSourceConfig::Mqtt {
+                    topic: String::from("router/wan"),
+                    json_path: Some(String::from("ip")),
+                },
+                IpSourceConfig::Static {
+                    addresses: vec![IpAddr::V4(Ipv4Addr::new(1, 2, 3, 4))],
+                },
+            ],
+            None,
+        )
+        .unwrap();
+
+        // the static address until a message is received
+        assert_eq!(
+            detector.detect(IpFamily::Ipv4).await,
+            Some(IpAddr::V4(Ipv4Addr::new(1, 2, 3, 4)))
+        );
+
+        assert_eq!(detector.mqtt_sources().len(), 1);
+        detector.mqtt_sources()[0]
+            .receive(br#"{"ip": "1.2.3.5"}"#)
+            .unwrap();
+
+        assert_eq!(
+            detector.detect(IpFamily::Ipv4).await,
+            Some(IpAddr::V4(Ipv4Addr::new(1, 2, 3, 5)))
+        );
+    }
+
+    fn static_source(ip: [u8; 4]) -> Box<dyn IpSource> {
+        Box::new(fixed::StaticSource::new(vec![IpAddr::V4(Ipv4Addr::from(
+            ip,
+        ))]))
+    }
+
+    fn failing_source() -> Box<dyn IpSource> {
+        Box::new(fixed::FileSource::new(std::path::Path::new(
+            "/this/file/does/not/exist",
+        )))
+    }
+
+    #[tokio::test]
+    async fn detector_falls_back_to_next_source() {
+        let detector = Detector::new(vec![failing_source(), static_source([1, 2, 3, 4])], None);
+
+        assert_eq!(
+            detector.detect(IpFamily::Ipv4).await,
+            Some(IpAddr::V4(Ipv4Addr::new(1, 2, 3, 4)))
+        );
+        assert_eq!(detector.detect(IpFamily::Ipv6).await, None);
+    }
+
+    #[tokio::test]
+    async fn detector_quorum_reached() {
+        let detector = Detector::new(
+            vec![
+                static_source([1, 2, 3, 4]),
+                static_source([5, 6, 7, 8]),
+                failing_source(),
+                static_source([1, 2, 3, 4]),
+            ],
+            Some(2),
+        );
+
+        assert_eq!(
+            detector.detect(IpFamily::Ipv4).await,
+            Some(IpAddr::V4(Ipv4Addr::new(1, 2, 3, 4)))
+        );
+    }
+
+    #[tokio::test]
+    async fn detector_quorum_not_reached() {
+        let detector = Detector::new(
+            vec![
+                static_source([1, 2, 3, 4]),
+                failing_source(),
+                failing_source(),
+            ],
+            Some(2),
+        );
+
+        assert_eq!(detector.detect(IpFamily::Ipv4).await, None);
+    }
+
+    #[tokio::test]
+    async fn detector_quorum_tie() {
+        let detector = Detector::new(
+            vec![static_source([1, 2, 3, 4]), static_source([5, 6, 7, 8])],
+            Some(1),
+        );
+
+        assert_eq!(detector.detect(IpFamily::Ipv4).await, None);
+    }
+
+    #[tokio::test]
+    async fn detector_detect_all_keeps_order() {
+        let detector = Detector::new(vec![failing_source(), static_source([1, 2, 3, 4])], None);
+
+        let results = detector.detect_all(IpFamily::Ipv4).await;
+
+        assert!(results[0].result.is_err());
+        assert_eq!(results[1].source, "static");
+        assert_eq!(
+            detector.decide(IpFamily::Ipv4, &results),
+            Some(IpAddr::V4(Ipv4Addr::new(1, 2, 3, 4)))
+        );
+    }
+}
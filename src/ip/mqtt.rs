@@ -0,0 +1,153 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    str::FromStr,
+    sync::{Arc, Mutex},
+};
+
+use async_trait::async_trait;
+
+use super::{http::json_path_lookup, IpFamily, IpSource, IpSourceError};
+
+/// Last addresses published on a topic by another device, such as a router.
+/// The messages are received by `monitor`, which checks the IP as soon as
+/// one arrives instead of waiting for the next check.
+#[derive(Clone)]
+pub struct MqttSource {
+    topic: String,
+    json_path: Option<String>,
+    addresses: Arc<Mutex<HashMap<IpFamily, IpAddr>>>,
+}
+
+impl MqttSource {
+    pub fn new(topic: &str, json_path: Option<&str>) -> Result<Self, IpSourceError> {
+        if topic.is_empty() {
+            return Err(IpSourceError::Config(String::from("empty topic")));
+        }
+        // the messages are routed by their exact topic
+        if topic.contains(['+', '#']) {
+            return Err(IpSourceError::Config(format!(
+                "wildcards are not supported in topic {}",
+                topic
+            )));
+        }
+
+        Ok(Self {
+            topic: String::from(topic),
+            json_path: json_path.map(String::from),
+            addresses: Arc::new(Mutex::new(HashMap::new())),
+        })
+    }
+
+    pub fn topic(&self) -> &str {
+        &self.topic
+    }
+
+    /// Keep the address found in a message, returned by the next detection
+    pub fn receive(&self, payload: &[u8]) -> Result<IpAddr, IpSourceError> {
+        let mut text = String::from_utf8_lossy(payload).to_string();
+
+        if let Some(ref path) = self.json_path {
+            let json: serde_json::Value = match serde_json::from_str(&text) {
+                Ok(json) => json,
+                Err(_) => return Err(IpSourceError::NotFound(text)),
+            };
+
+            text = match json_path_lookup(&json, path) {
+                Some(serde_json::Value::String(s)) => s.clone(),
+                Some(value) => value.to_string(),
+                None => return Err(IpSourceError::NotFound(text)),
+            };
+        }
+
+        let ip = match IpAddr::from_str(text.trim()) {
+            Ok(ip) => ip,
+            Err(_) => return Err(IpSourceError::NotFound(String::from(text.trim()))),
+        };
+
+        self.addresses.lock().unwrap().insert(IpFamily::of(&ip), ip);
+
+        Ok(ip)
+    }
+}
+
+#[async_trait]
+impl IpSource for MqttSource {
+    fn name(&self) -> String {
+        format!("mqtt {}", self.topic)
+    }
+
+    async fn detect(&self, family: IpFamily) -> Result<IpAddr, IpSourceError> {
+        self.addresses
+            .lock()
+            .unwrap()
+            .get(&family)
+            .copied()
+            .ok_or(IpSourceError::NotFound(format!(
+                "no {} received on {}",
+                family, self.topic
+            )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn mqtt_source_keeps_the_last_address_of_each_family() {
+        let source = MqttSource::new("router/wan/ip", None).unwrap();
+
+        assert!(matches!(
+            source.detect(IpFamily::Ipv4).await,
+            Err(IpSourceError::NotFound(_))
+        ));
+
+        source.receive(b"203.0.113.7\n").unwrap();
+        source.receive(b"203.0.113.8").unwrap();
+        source.receive(b"2001:db8::1").unwrap();
+
+        assert_eq!(
+            source.detect(IpFamily::Ipv4).await.unwrap(),
+            IpAddr::V4(Ipv4Addr::new(203, 0, 113, 8))
+        );
+        assert_eq!(
+            source.detect(IpFamily::Ipv6).await.unwrap(),
+            IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1))
+        );
+    }
+
+    #[tokio::test]
+    async fn mqtt_source_json_path() {
+        let source = MqttSource::new("router/state", Some("wan.ipv4")).unwrap();
+
+        assert_eq!(
+            source
+                .receive(br#"{ "wan": { "ipv4": "203.0.113.7", "up": true } }"#)
+                .unwrap(),
+            IpAddr::V4(Ipv4Addr::new(203, 0, 113, 7))
+        );
+        assert!(matches!(
+            source.receive(br#"{ "wan": { "up": false } }"#),
+            Err(IpSourceError::NotFound(_))
+        ));
+        assert!(matches!(
+            source.receive(b"203.0.113.8"),
+            Err(IpSourceError::NotFound(_))
+        ));
+
+        // an invalid message leaves the last address
+        assert_eq!(
+            source.detect(IpFamily::Ipv4).await.unwrap(),
+            IpAddr::V4(Ipv4Addr::new(203, 0, 113, 7))
+        );
+    }
+
+    #[test]
+    fn mqtt_source_rejects_wildcards() {
+        assert!(MqttSource::new("router/+/ip", None).is_err());
+        assert!(MqttSource::new("", None).is_err());
+    }
+}
@@ -0,0 +1,330 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use async_trait::async_trait;
+
+use super::{
+    parse_socket_addr,
+    udp::{connect, exchange},
+    IpFamily, IpSource, IpSourceError,
+};
+
+/// NAT-PMP and PCP both listen on this port of the gateway
+const PORT: u16 = 5351;
+
+const PCP_VERSION: u8 = 2;
+const PCP_OPCODE_MAP: u8 = 1;
+const PCP_PROTOCOL_UDP: u8 = 17;
+/// Discard port, the mapping only exists to learn the external address
+const PCP_INTERNAL_PORT: u16 = 9;
+const PCP_LIFETIME: u32 = 120;
+
+/// Asks the gateway for its external IPv4 address using NAT-PMP (RFC 6886)
+pub struct NatPmpSource {
+    gateway: Option<SocketAddr>,
+}
+
+impl NatPmpSource {
+    pub fn new(gateway: Option<&str>) -> Result<Self, IpSourceError> {
+        Ok(Self {
+            gateway: gateway.map(|g| parse_socket_addr(g, PORT)).transpose()?,
+        })
+    }
+}
+
+#[async_trait]
+impl IpSource for NatPmpSource {
+    fn name(&self) -> String {
+        String::from("nat-pmp")
+    }
+
+    async fn detect(&self, family: IpFamily) -> Result<IpAddr, IpSourceError> {
+        if family != IpFamily::Ipv4 {
+            return Err(IpSourceError::Unsupported(family));
+        }
+
+        let gateway = gateway_or_default(self.gateway, family)?;
+
+        // version 0, opcode 0: external address request
+        let response = exchange(gateway, &[0, 0], |res| res.len() >= 12 && res[1] == 128).await?;
+
+        let result_code = u16::from_be_bytes([response[2], response[3]]);
+        if result_code != 0 {
+            return Err(IpSourceError::NotFound(format!(
+                "NAT-PMP result code {}",
+                result_code
+            )));
+        }
+
+        Ok(IpAddr::V4(Ipv4Addr::new(
+            response[8],
+            response[9],
+            response[10],
+            response[11],
+        )))
+    }
+}
+
+/// Asks the gateway for its external address using PCP (RFC 6887), by
+/// requesting a short lived mapping and reading the assigned external address
+pub struct PcpSource {
+    gateway: Option<SocketAddr>,
+}
+
+impl PcpSource {
+    pub fn new(gateway: Option<&str>) -> Result<Self, IpSourceError> {
+        Ok(Self {
+            gateway: gateway.map(|g| parse_socket_addr(g, PORT)).transpose()?,
+        })
+    }
+}
+
+#[async_trait]
+impl IpSource for PcpSource {
+    fn name(&self) -> String {
+        String::from("pcp")
+    }
+
+    async fn detect(&self, family: IpFamily) -> Result<IpAddr, IpSourceError> {
+        let gateway = gateway_or_default(self.gateway, family)?;
+
+        let client_ip = local_address_towards(gateway).await?;
+        let nonce: [u8; 12] = rand::random();
+
+        let request = pcp_map_request(client_ip, nonce, PCP_LIFETIME);
+        let response = exchange(gateway, &request, |res| {
+            res.len() >= 60 && res[1] == 0x80 | PCP_OPCODE_MAP && res[24..36] == nonce
+        })
+        .await?;
+
+        let result_code = response[3];
+        if result_code != 0 {
+            return Err(IpSourceError::NotFound(format!(
+                "PCP result code {}",
+                result_code
+            )));
+        }
+
+        // remove the mapping, the gateway lets it expire if this is lost
+        let delete = pcp_map_request(client_ip, nonce, 0);
+        if let Ok(socket) = connect(gateway).await {
+            let _ = socket.send(&delete).await;
+        }
+
+        let mut octets = [0u8; 16];
+        octets.copy_from_slice(&response[44..60]);
+        let external = Ipv6Addr::from(octets);
+
+        let ip = match external.to_ipv4_mapped() {
+            Some(ip) => IpAddr::V4(ip),
+            None => IpAddr::V6(external),
+        };
+
+        if IpFamily::of(&ip) != family {
+            return Err(IpSourceError::WrongFamily(ip));
+        }
+
+        Ok(ip)
+    }
+}
+
+fn pcp_map_request(client_ip: IpAddr, nonce: [u8; 12], lifetime: u32) -> Vec<u8> {
+    let mut request = Vec::with_capacity(60);
+
+    request.push(PCP_VERSION);
+    request.push(PCP_OPCODE_MAP);
+    request.extend_from_slice(&[0, 0]);
+    request.extend_from_slice(&lifetime.to_be_bytes());
+    request.extend_from_slice(&to_pcp_address(client_ip));
+
+    request.extend_from_slice(&nonce);
+    request.push(PCP_PROTOCOL_UDP);
+    request.extend_from_slice(&[0, 0, 0]);
+    request.extend_from_slice(&PCP_INTERNAL_PORT.to_be_bytes());
+    // no suggested external port
+    request.extend_from_slice(&[0, 0]);
+    // no suggested external address, of the same family as the client
+    let any = match client_ip {
+        IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+    };
+    request.extend_from_slice(&to_pcp_address(any));
+
+    request
+}
+
+/// PCP carries every address on 16 bytes, IPv4 being mapped to IPv6
+fn to_pcp_address(ip: IpAddr) -> [u8; 16] {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped().octets(),
+        IpAddr::V6(ip) => ip.octets(),
+    }
+}
+
+fn gateway_or_default(
+    gateway: Option<SocketAddr>,
+    family: IpFamily,
+) -> Result<SocketAddr, IpSourceError> {
+    let gateway = match gateway {
+        Some(gateway) => gateway,
+        None => match default_gateway()? {
+            Some(ip) => SocketAddr::new(IpAddr::V4(ip), PORT),
+            None => {
+                return Err(IpSourceError::Config(String::from(
+                    "could not find the default gateway, set `gateway`",
+                )))
+            }
+        },
+    };
+
+    if IpFamily::of(&gateway.ip()) != family {
+        return Err(IpSourceError::Unsupported(family));
+    }
+
+    Ok(gateway)
+}
+
+/// Our address as seen by the gateway
+async fn local_address_towards(gateway: SocketAddr) -> Result<IpAddr, IpSourceError> {
+    Ok(connect(gateway).await?.local_addr()?.ip())
+}
+
+/// Default IPv4 gateway, only known on Linux
+fn default_gateway() -> Result<Option<Ipv4Addr>, IpSourceError> {
+    if !cfg!(target_os = "linux") {
+        return Ok(None);
+    }
+
+    let routes = std::fs::read_to_string("/proc/net/route")?;
+    Ok(parse_default_route(&routes))
+}
+
+/// Parse `/proc/net/route`, the gateway is stored as little endian hex
+fn parse_default_route(routes: &str) -> Option<Ipv4Addr> {
+    routes.lines().skip(1).find_map(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() < 3 || fields[1] != "00000000" {
+            return None;
+        }
+
+        let gateway = u32::from_str_radix(fields[2], 16).ok()?;
+        match gateway {
+            0 => None,
+            _ => Some(Ipv4Addr::from(gateway.to_le_bytes())),
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::UdpSocket;
+
+    use super::*;
+
+    #[test]
+    fn default_route() {
+        let routes =
+            "Iface\tDestination\tGateway \tFlags\tRefCnt\tUse\tMetric\tMask\t\tMTU\tWindow\tIRTT\n\
+            eth0\t0000A8C0\t00000000\t0001\t0\t0\t0\t00FFFFFF\t0\t0\t0\n\
+            eth0\t00000000\t0101A8C0\t0003\t0\t0\t0\t00000000\t0\t0\t0\n";
+
+        assert_eq!(
+            parse_default_route(routes),
+            Some(Ipv4Addr::new(192, 168, 1, 1))
+        );
+        assert_eq!(parse_default_route("Iface\tDestination\tGateway\n"), None);
+    }
+
+    #[test]
+    fn map_request() {
+        let request = pcp_map_request(IpAddr::V4(Ipv4Addr::new(192, 168, 1, 2)), [7; 12], 120);
+
+        assert_eq!(request.len(), 60);
+        assert_eq!(&request[..4], &[2, 1, 0, 0]);
+        assert_eq!(&request[4..8], &120u32.to_be_bytes());
+        assert_eq!(&request[18..24], &[0xff, 0xff, 192, 168, 1, 2]);
+        assert_eq!(&request[24..36], &[7; 12]);
+        assert_eq!(request[36], PCP_PROTOCOL_UDP);
+    }
+
+    #[tokio::test]
+    async fn nat_pmp_source() {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let gateway = socket.local_addr().unwrap().to_string();
+
+        tokio::spawn(async move {
+            let mut buf = [0u8; 16];
+            let (len, peer) = socket.recv_from(&mut buf).await.unwrap();
+            assert_eq!(&buf[..len], &[0, 0]);
+
+            let response = [0, 128, 0, 0, 0, 0, 0, 42, 1, 2, 3, 4];
+            socket.send_to(&response, peer).await.unwrap();
+        });
+
+        let source = NatPmpSource::new(Some(&gateway)).unwrap();
+
+        assert_eq!(
+            source.detect(IpFamily::Ipv4).await.unwrap(),
+            IpAddr::V4(Ipv4Addr::new(1, 2, 3, 4))
+        );
+    }
+
+    #[tokio::test]
+    async fn nat_pmp_source_error_result() {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let gateway = socket.local_addr().unwrap().to_string();
+
+        tokio::spawn(async move {
+            let mut buf = [0u8; 16];
+            let (_, peer) = socket.recv_from(&mut buf).await.unwrap();
+
+            // result code 3, network failure
+            let response = [0, 128, 0, 3, 0, 0, 0, 42, 0, 0, 0, 0];
+            socket.send_to(&response, peer).await.unwrap();
+        });
+
+        let source = NatPmpSource::new(Some(&gateway)).unwrap();
+
+        assert!(source.detect(IpFamily::Ipv4).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn pcp_source() {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let gateway = socket.local_addr().unwrap().to_string();
+
+        tokio::spawn(async move {
+            let mut buf = [0u8; 1100];
+            let (len, peer) = socket.recv_from(&mut buf).await.unwrap();
+            let request = &buf[..len];
+            assert_eq!(request[0], PCP_VERSION);
+            assert_eq!(request[1], PCP_OPCODE_MAP);
+
+            let mut response = vec![PCP_VERSION, 0x80 | PCP_OPCODE_MAP, 0, 0];
+            response.extend_from_slice(&request[4..8]);
+            response.extend_from_slice(&[0; 16]);
+            // nonce, protocol and internal port
+            response.extend_from_slice(&request[24..42]);
+            // assigned external port and address
+            response.extend_from_slice(&[0, 9]);
+            response.extend_from_slice(&Ipv4Addr::new(1, 2, 3, 4).to_ipv6_mapped().octets());
+
+            socket.send_to(&response, peer).await.unwrap();
+        });
+
+        let source = PcpSource::new(Some(&gateway)).unwrap();
+
+        assert_eq!(
+            source.detect(IpFamily::Ipv4).await.unwrap(),
+            IpAddr::V4(Ipv4Addr::new(1, 2, 3, 4))
+        );
+    }
+
+    #[tokio::test]
+    async fn pcp_source_gateway_of_other_family() {
+        let source = PcpSource::new(Some("127.0.0.1")).unwrap();
+        assert!(matches!(
+            source.detect(IpFamily::Ipv6).await,
+            Err(IpSourceError::Unsupported(IpFamily::Ipv6))
+        ));
+    }
+}
@@ -0,0 +1,365 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use async_trait::async_trait;
+
+use super::{parse_socket_addr, udp::exchange, IpFamily, IpSource, IpSourceError};
+
+const DEFAULT_PORT: u16 = 3478;
+
+const MAGIC_COOKIE: u32 = 0x2112_A442;
+const BINDING_REQUEST: u16 = 0x0001;
+const BINDING_SUCCESS: u16 = 0x0101;
+
+const ATTR_MAPPED_ADDRESS: u16 = 0x0001;
+const ATTR_XOR_MAPPED_ADDRESS: u16 = 0x0020;
+
+const FAMILY_V4: u8 = 0x01;
+const FAMILY_V6: u8 = 0x02;
+
+pub fn default_servers() -> Vec<String> {
+    vec![
+        String::from("stun.cloudflare.com:3478"),
+        String::from("stun.l.google.com:19302"),
+    ]
+}
+
+/// Sends a STUN (RFC 5389) Binding Request and reads back the address the
+/// server saw. Servers are resolved on every check and only those of the
+/// requested family are asked.
+pub struct StunSource {
+    servers: Vec<String>,
+}
+
+impl StunSource {
+    pub fn new(servers: &[String]) -> Result<Self, IpSourceError> {
+        if servers.is_empty() {
+            return Err(IpSourceError::Config(String::from(
+                "at least one STUN server is required",
+            )));
+        }
+
+        Ok(Self {
+            servers: servers.to_vec(),
+        })
+    }
+
+    async fn query_server(&self, server: SocketAddr) -> Result<IpAddr, IpSourceError> {
+        let transaction_id: [u8; 12] = rand::random();
+
+        let response = exchange(server, &binding_request(transaction_id), |res| {
+            res.len() >= 20 && res[8..20] == transaction_id
+        })
+        .await?;
+
+        parse_binding_response(&response, transaction_id)
+    }
+}
+
+#[async_trait]
+impl IpSource for StunSource {
+    fn name(&self) -> String {
+        format!("stun {}", self.servers.join(","))
+    }
+
+    async fn detect(&self, family: IpFamily) -> Result<IpAddr, IpSourceError> {
+        let mut result = Err(IpSourceError::Config(format!(
+            "no {} STUN server found",
+            family
+        )));
+
+        for server in &self.servers {
+            let addresses = match resolve(server).await {
+                Ok(addresses) => addresses,
+                Err(e) => {
+                    result = Err(e);
+                    continue;
+                }
+            };
+
+            for address in addresses
+                .into_iter()
+                .filter(|a| IpFamily::of(&a.ip()) == family)
+            {
+                result = self.query_server(address).await;
+                if result.is_ok() {
+                    return result;
+                }
+            }
+        }
+
+        result
+    }
+}
+
+/// Addresses of `host`, `host:port`, `ip`, `ip:port` or `[ipv6]:port`
+async fn resolve(server: &str) -> Result<Vec<SocketAddr>, IpSourceError> {
+    if let Ok(address) = parse_socket_addr(server, DEFAULT_PORT) {
+        return Ok(vec![address]);
+    }
+
+    let addresses = match server.contains(':') {
+        true => tokio::net::lookup_host(server).await?.collect(),
+        false => tokio::net::lookup_host((server, DEFAULT_PORT))
+            .await?
+            .collect(),
+    };
+
+    Ok(addresses)
+}
+
+fn binding_request(transaction_id: [u8; 12]) -> Vec<u8> {
+    let mut request = Vec::with_capacity(20);
+
+    request.extend_from_slice(&BINDING_REQUEST.to_be_bytes());
+    // no attributes
+    request.extend_from_slice(&0u16.to_be_bytes());
+    request.extend_from_slice(&MAGIC_COOKIE.to_be_bytes());
+    request.extend_from_slice(&transaction_id);
+
+    request
+}
+
+/// Mapped address of a Binding success response, preferring
+/// XOR-MAPPED-ADDRESS over the MAPPED-ADDRESS of older servers
+fn parse_binding_response(
+    packet: &[u8],
+    transaction_id: [u8; 12],
+) -> Result<IpAddr, IpSourceError> {
+    let malformed = || IpSourceError::NotFound(String::from("malformed STUN response"));
+
+    if packet.len() < 20 {
+        return Err(malformed());
+    }
+
+    let message_type = u16::from_be_bytes([packet[0], packet[1]]);
+    if message_type != BINDING_SUCCESS {
+        return Err(IpSourceError::NotFound(format!(
+            "STUN message type {:#06x}",
+            message_type
+        )));
+    }
+
+    let length = u16::from_be_bytes([packet[2], packet[3]]) as usize;
+    let attributes = match packet.get(20..20 + length) {
+        Some(attributes) => attributes,
+        None => return Err(malformed()),
+    };
+
+    let mut mapped = None;
+    let mut pos = 0;
+
+    while pos + 4 <= attributes.len() {
+        let kind = u16::from_be_bytes([attributes[pos], attributes[pos + 1]]);
+        let len = u16::from_be_bytes([attributes[pos + 2], attributes[pos + 3]]) as usize;
+        let value = match attributes.get(pos + 4..pos + 4 + len) {
+            Some(value) => value,
+            None => return Err(malformed()),
+        };
+
+        match kind {
+            ATTR_XOR_MAPPED_ADDRESS => return parse_address(value, Some(transaction_id)),
+            ATTR_MAPPED_ADDRESS => mapped = Some(parse_address(value, None)?),
+            _ => {}
+        }
+
+        // attributes are padded to a multiple of 4 bytes
+        pos += 4 + len.div_ceil(4) * 4;
+    }
+
+    mapped.ok_or(IpSourceError::NotFound(String::from(
+        "no mapped address in the STUN response",
+    )))
+}
+
+/// Address of a (XOR-)MAPPED-ADDRESS attribute, XOR-ed with the magic cookie
+/// and the transaction id when one is given
+fn parse_address(value: &[u8], xor: Option<[u8; 12]>) -> Result<IpAddr, IpSourceError> {
+    let malformed = || IpSourceError::NotFound(String::from("malformed STUN address"));
+
+    let mut key = [0u8; 16];
+    if let Some(transaction_id) = xor {
+        key[..4].copy_from_slice(&MAGIC_COOKIE.to_be_bytes());
+        key[4..].copy_from_slice(&transaction_id);
+    }
+
+    let family = *value.get(1).ok_or_else(malformed)?;
+    let size = match family {
+        FAMILY_V4 => 4,
+        FAMILY_V6 => 16,
+        _ => return Err(malformed()),
+    };
+
+    let mut octets = [0u8; 16];
+    match value.get(4..4 + size) {
+        Some(address) => {
+            for (i, byte) in address.iter().enumerate() {
+                octets[i] = byte ^ key[i];
+            }
+        }
+        None => return Err(malformed()),
+    }
+
+    Ok(match family {
+        FAMILY_V4 => IpAddr::V4(Ipv4Addr::new(octets[0], octets[1], octets[2], octets[3])),
+        _ => IpAddr::V6(Ipv6Addr::from(octets)),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use tokio::net::UdpSocket;
+
+    use super::*;
+
+    /// Attribute encoding the address as a server would
+    fn address_attribute(kind: u16, ip: IpAddr, port: u16, transaction_id: [u8; 12]) -> Vec<u8> {
+        let mut key = [0u8; 16];
+        if kind == ATTR_XOR_MAPPED_ADDRESS {
+            key[..4].copy_from_slice(&MAGIC_COOKIE.to_be_bytes());
+            key[4..16].copy_from_slice(&transaction_id);
+        }
+
+        let (family, octets) = match ip {
+            IpAddr::V4(ip) => (FAMILY_V4, ip.octets().to_vec()),
+            IpAddr::V6(ip) => (FAMILY_V6, ip.octets().to_vec()),
+        };
+
+        let mut attribute = Vec::new();
+        attribute.extend_from_slice(&kind.to_be_bytes());
+        attribute.extend_from_slice(&(4 + octets.len() as u16).to_be_bytes());
+        attribute.extend_from_slice(&[0, family]);
+        let port_key = u16::from_be_bytes([key[0], key[1]]);
+        attribute.extend_from_slice(&(port ^ port_key).to_be_bytes());
+        for (i, byte) in octets.iter().enumerate() {
+            attribute.push(byte ^ key[i]);
+        }
+
+        attribute
+    }
+
+    fn binding_response(transaction_id: [u8; 12], attributes: &[Vec<u8>]) -> Vec<u8> {
+        let attributes = attributes.concat();
+
+        let mut response = Vec::new();
+        response.extend_from_slice(&BINDING_SUCCESS.to_be_bytes());
+        response.extend_from_slice(&(attributes.len() as u16).to_be_bytes());
+        response.extend_from_slice(&MAGIC_COOKIE.to_be_bytes());
+        response.extend_from_slice(&transaction_id);
+        response.extend_from_slice(&attributes);
+
+        response
+    }
+
+    #[test]
+    fn request() {
+        let request = binding_request([7; 12]);
+
+        assert_eq!(request.len(), 20);
+        assert_eq!(&request[..4], &[0, 1, 0, 0]);
+        assert_eq!(&request[4..8], &[0x21, 0x12, 0xa4, 0x42]);
+        assert_eq!(&request[8..], &[7; 12]);
+    }
+
+    #[test]
+    fn xor_mapped_address_v6() {
+        let ip = IpAddr::from_str("2001:db8::1").unwrap();
+        let response = binding_response(
+            [9; 12],
+            &[address_attribute(
+                ATTR_XOR_MAPPED_ADDRESS,
+                ip,
+                4242,
+                [9; 12],
+            )],
+        );
+
+        assert_eq!(parse_binding_response(&response, [9; 12]).unwrap(), ip);
+    }
+
+    #[test]
+    fn xor_mapped_address_preferred() {
+        let mapped = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        let xor_mapped = IpAddr::V4(Ipv4Addr::new(1, 2, 3, 4));
+        let response = binding_response(
+            [3; 12],
+            &[
+                address_attribute(ATTR_MAPPED_ADDRESS, mapped, 4242, [3; 12]),
+                address_attribute(ATTR_XOR_MAPPED_ADDRESS, xor_mapped, 4242, [3; 12]),
+            ],
+        );
+
+        assert_eq!(
+            parse_binding_response(&response, [3; 12]).unwrap(),
+            xor_mapped
+        );
+    }
+
+    #[test]
+    fn mapped_address_fallback() {
+        let ip = IpAddr::V4(Ipv4Addr::new(1, 2, 3, 4));
+        let response = binding_response(
+            [3; 12],
+            &[address_attribute(ATTR_MAPPED_ADDRESS, ip, 4242, [3; 12])],
+        );
+
+        assert_eq!(parse_binding_response(&response, [3; 12]).unwrap(), ip);
+    }
+
+    #[test]
+    fn error_response() {
+        let mut response = binding_response([3; 12], &[]);
+        // Binding error response
+        response[0..2].copy_from_slice(&0x0111u16.to_be_bytes());
+
+        assert!(parse_binding_response(&response, [3; 12]).is_err());
+        assert!(parse_binding_response(&response[..10], [3; 12]).is_err());
+    }
+
+    #[tokio::test]
+    async fn stun_source() {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let server = socket.local_addr().unwrap().to_string();
+
+        tokio::spawn(async move {
+            let mut buf = [0u8; 1500];
+            let (len, peer) = socket.recv_from(&mut buf).await.unwrap();
+            let request = &buf[..len];
+            assert_eq!(&request[..8], &binding_request([0; 12])[..8]);
+
+            let mut transaction_id = [0u8; 12];
+            transaction_id.copy_from_slice(&request[8..20]);
+
+            let ip = IpAddr::V4(Ipv4Addr::new(1, 2, 3, 4));
+            let response = binding_response(
+                transaction_id,
+                &[address_attribute(
+                    ATTR_XOR_MAPPED_ADDRESS,
+                    ip,
+                    peer.port(),
+                    transaction_id,
+                )],
+            );
+            socket.send_to(&response, peer).await.unwrap();
+        });
+
+        let source = StunSource::new(&[server]).unwrap();
+
+        assert_eq!(
+            source.detect(IpFamily::Ipv4).await.unwrap(),
+            IpAddr::V4(Ipv4Addr::new(1, 2, 3, 4))
+        );
+    }
+
+    #[tokio::test]
+    async fn stun_source_without_server_of_family() {
+        let source = StunSource::new(&[String::from("127.0.0.1")]).unwrap();
+        assert!(source.detect(IpFamily::Ipv6).await.is_err());
+    }
+
+    #[test]
+    fn no_servers() {
+        assert!(StunSource::new(&[]).is_err());
+    }
+}
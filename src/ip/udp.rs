@@ -0,0 +1,54 @@
+use std::{
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
+
+use tokio::net::UdpSocket;
+
+use super::IpSourceError;
+
+/// Initial delay before resending a request, doubled after every attempt
+const INITIAL_TIMEOUT: Duration = Duration::from_millis(250);
+const ATTEMPTS: u32 = 4;
+
+/// UDP socket of the same family as the peer, connected to it
+pub async fn connect(peer: SocketAddr) -> Result<UdpSocket, IpSourceError> {
+    let local: SocketAddr = match peer {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+
+    let socket = UdpSocket::bind(local).await?;
+    socket.connect(peer).await?;
+    Ok(socket)
+}
+
+/// Send the request, resending it with an increasing delay until a valid response arrives
+pub async fn exchange(
+    peer: SocketAddr,
+    request: &[u8],
+    is_response: impl Fn(&[u8]) -> bool,
+) -> Result<Vec<u8>, IpSourceError> {
+    let socket = connect(peer).await?;
+    let mut timeout = INITIAL_TIMEOUT;
+    let mut buf = [0u8; 1500];
+
+    for _ in 0..ATTEMPTS {
+        socket.send(request).await?;
+
+        let deadline = tokio::time::Instant::now() + timeout;
+        while let Ok(res) = tokio::time::timeout_at(deadline, socket.recv(&mut buf)).await {
+            let len = res?;
+            if is_response(&buf[..len]) {
+                return Ok(Vec::from(&buf[..len]));
+            }
+        }
+
+        timeout *= 2;
+    }
+
+    Err(IpSourceError::Io(std::io::Error::new(
+        std::io::ErrorKind::TimedOut,
+        format!("no answer from {}", peer),
+    )))
+}
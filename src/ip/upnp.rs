@@ -0,0 +1,389 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::Mutex,
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use log::debug;
+use tokio::net::UdpSocket;
+
+use super::{parse_ip, parse_socket_addr, IpFamily, IpSource, IpSourceError};
+
+const SEARCH_TARGET: &str = "urn:schemas-upnp-org:device:InternetGatewayDevice:1";
+
+/// Services able to answer `GetExternalIPAddress`, in order of preference
+const WAN_SERVICES: [&str; 3] = [
+    "urn:schemas-upnp-org:service:WANIPConnection:2",
+    "urn:schemas-upnp-org:service:WANIPConnection:1",
+    "urn:schemas-upnp-org:service:WANPPPConnection:1",
+];
+
+const DISCOVERY_TIMEOUT: Duration = Duration::from_secs(3);
+
+pub fn default_ssdp_address() -> String {
+    String::from("239.255.255.250:1900")
+}
+
+/// Control endpoint of the WAN connection service of the gateway
+#[derive(Debug, Clone)]
+struct ControlPoint {
+    url: String,
+    service: String,
+}
+
+/// Asks the router for its WAN address using UPnP IGD. The gateway is found
+/// with SSDP, unless the location of its device description is configured.
+pub struct UpnpSource {
+    ssdp_address: SocketAddr,
+    location: Option<String>,
+    client: reqwest::Client,
+    control_point: Mutex<Option<ControlPoint>>,
+}
+
+impl UpnpSource {
+    pub fn new(ssdp_address: &str, location: Option<&str>) -> Result<Self, IpSourceError> {
+        if let Some(location) = location {
+            if let Err(e) = reqwest::Url::parse(location) {
+                return Err(IpSourceError::Config(format!(
+                    "invalid location {}: {}",
+                    location, e
+                )));
+            }
+        }
+
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(5))
+            .build()?;
+
+        Ok(Self {
+            ssdp_address: parse_socket_addr(ssdp_address, 1900)?,
+            location: location.map(String::from),
+            client,
+            control_point: Mutex::new(None),
+        })
+    }
+
+    /// Send an SSDP M-SEARCH and return the location of the first gateway answering
+    async fn discover(&self) -> Result<String, IpSourceError> {
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+
+        let request = format!(
+            "M-SEARCH * HTTP/1.1\r\nHOST: {}\r\nMAN: \"ssdp:discover\"\r\nMX: 2\r\nST: {}\r\n\r\n",
+            self.ssdp_address, SEARCH_TARGET
+        );
+        socket
+            .send_to(request.as_bytes(), self.ssdp_address)
+            .await?;
+
+        let mut buf = [0u8; 2048];
+        let deadline = tokio::time::Instant::now() + DISCOVERY_TIMEOUT;
+
+        loop {
+            let len = match tokio::time::timeout_at(deadline, socket.recv(&mut buf)).await {
+                Ok(res) => res?,
+                Err(_) => {
+                    return Err(IpSourceError::NotFound(String::from(
+                        "no UPnP gateway answered",
+                    )))
+                }
+            };
+
+            let response = String::from_utf8_lossy(&buf[..len]);
+            if let Some(location) = header_value(&response, "location") {
+                debug!("Found UPnP gateway at {}", location);
+                return Ok(String::from(location));
+            }
+        }
+    }
+
+    /// Fetch the device description and find the WAN connection service
+    async fn control_point(&self) -> Result<ControlPoint, IpSourceError> {
+        if let Some(control_point) = self.control_point.lock().unwrap().clone() {
+            return Ok(control_point);
+        }
+
+        let location = match self.location {
+            Some(ref location) => location.clone(),
+            None => self.discover().await?,
+        };
+
+        let description = self
+            .client
+            .get(&location)
+            .send()
+            .await?
+            .error_for_status()?
+            .text()
+            .await?;
+
+        let (service, control_url) = match find_wan_service(&description) {
+            Some(found) => found,
+            None => {
+                return Err(IpSourceError::NotFound(String::from(
+                    "no WAN connection service in the gateway description",
+                )))
+            }
+        };
+
+        let url = match reqwest::Url::parse(&location).and_then(|base| base.join(control_url)) {
+            Ok(url) => url,
+            Err(e) => {
+                return Err(IpSourceError::NotFound(format!(
+                    "invalid control url {}: {}",
+                    control_url, e
+                )))
+            }
+        };
+
+        let control_point = ControlPoint {
+            url: url.to_string(),
+            service: String::from(service),
+        };
+
+        *self.control_point.lock().unwrap() = Some(control_point.clone());
+
+        Ok(control_point)
+    }
+
+    async fn get_external_ip(&self, control_point: &ControlPoint) -> Result<String, IpSourceError> {
+        let body = format!(
+            concat!(
+                "<?xml version=\"1.0\"?>\r\n",
+                "<s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\" ",
+                "s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\">",
+                "<s:Body><u:GetExternalIPAddress xmlns:u=\"{}\"></u:GetExternalIPAddress></s:Body>",
+                "</s:Envelope>\r\n"
+            ),
+            control_point.service
+        );
+
+        let response = self
+            .client
+            .post(&control_point.url)
+            .header("Content-Type", "text/xml; charset=\"utf-8\"")
+            .header(
+                "SOAPAction",
+                format!("\"{}#GetExternalIPAddress\"", control_point.service),
+            )
+            .body(body)
+            .send()
+            .await?
+            .error_for_status()?
+            .text()
+            .await?;
+
+        match tag_text(&response, "NewExternalIPAddress") {
+            Some(ip) => Ok(String::from(ip)),
+            None => Err(IpSourceError::NotFound(response)),
+        }
+    }
+}
+
+#[async_trait]
+impl IpSource for UpnpSource {
+    fn name(&self) -> String {
+        String::from("upnp")
+    }
+
+    async fn detect(&self, family: IpFamily) -> Result<IpAddr, IpSourceError> {
+        if family != IpFamily::Ipv4 {
+            return Err(IpSourceError::Unsupported(family));
+        }
+
+        let control_point = self.control_point().await?;
+
+        match self.get_external_ip(&control_point).await {
+            Ok(ip) => parse_ip(&ip, family),
+            Err(e) => {
+                // the gateway may have restarted with another control url
+                *self.control_point.lock().unwrap() = None;
+                Err(e)
+            }
+        }
+    }
+}
+
+/// Value of an HTTP style header, the name is case insensitive
+fn header_value<'a>(response: &'a str, name: &str) -> Option<&'a str> {
+    response.lines().find_map(|line| {
+        let (key, value) = line.split_once(':')?;
+        match key.trim().eq_ignore_ascii_case(name) {
+            true => Some(value.trim()),
+            false => None,
+        }
+    })
+}
+
+/// Text between `<tag>` and `</tag>`, ignoring any namespace prefix
+fn tag_text<'a>(xml: &'a str, tag: &str) -> Option<&'a str> {
+    let start = xml.find(&format!("{}>", tag))? + tag.len() + 1;
+    let end = start + xml[start..].find("</")?;
+    Some(xml[start..end].trim())
+}
+
+/// Service type and control url of the preferred WAN connection service
+fn find_wan_service(description: &str) -> Option<(&'static str, &str)> {
+    let services: Vec<(&str, &str)> = description
+        .split("<service>")
+        .skip(1)
+        .filter_map(|block| {
+            Some((
+                tag_text(block, "serviceType")?,
+                tag_text(block, "controlURL")?,
+            ))
+        })
+        .collect();
+
+    WAN_SERVICES.iter().find_map(|wanted| {
+        services
+            .iter()
+            .find(|(service, _)| service == wanted)
+            .map(|(_, control_url)| (*wanted, *control_url))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use httpmock::prelude::*;
+
+    use super::*;
+
+    fn description(control_url: &str) -> String {
+        format!(
+            r#"<?xml version="1.0"?>
+<root xmlns="urn:schemas-upnp-org:device-1-0">
+  <device>
+    <deviceType>urn:schemas-upnp-org:device:InternetGatewayDevice:1</deviceType>
+    <serviceList>
+      <service>
+        <serviceType>urn:schemas-upnp-org:service:Layer3Forwarding:1</serviceType>
+        <controlURL>/ctl/L3F</controlURL>
+      </service>
+    </serviceList>
+    <deviceList>
+      <device>
+        <serviceList>
+          <service>
+            <serviceType>urn:schemas-upnp-org:service:WANIPConnection:1</serviceType>
+            <controlURL>{}</controlURL>
+          </service>
+        </serviceList>
+      </device>
+    </deviceList>
+  </device>
+</root>"#,
+            control_url
+        )
+    }
+
+    const SOAP_RESPONSE: &str = r#"<?xml version="1.0"?>
+<s:Envelope xmlns:s="http://schemas.xmlsoap.org/soap/envelope/">
+  <s:Body>
+    <u:GetExternalIPAddressResponse xmlns:u="urn:schemas-upnp-org:service:WANIPConnection:1">
+      <NewExternalIPAddress>1.2.3.4</NewExternalIPAddress>
+    </u:GetExternalIPAddressResponse>
+  </s:Body>
+</s:Envelope>"#;
+
+    /// Answer the first M-SEARCH received with the given location
+    async fn ssdp_stand_in(socket: UdpSocket, location: String) {
+        let mut buf = [0u8; 2048];
+        let (len, peer) = socket.recv_from(&mut buf).await.unwrap();
+        let request = String::from_utf8_lossy(&buf[..len]);
+        assert!(request.starts_with("M-SEARCH"));
+        assert!(request.contains(SEARCH_TARGET));
+
+        let response = format!(
+            "HTTP/1.1 200 OK\r\nCACHE-CONTROL: max-age=120\r\nST: {}\r\nLocation: {}\r\n\r\n",
+            SEARCH_TARGET, location
+        );
+        socket.send_to(response.as_bytes(), peer).await.unwrap();
+    }
+
+    #[test]
+    fn find_wan_service_in_description() {
+        assert_eq!(
+            find_wan_service(&description("/ctl/IPConn")),
+            Some((
+                "urn:schemas-upnp-org:service:WANIPConnection:1",
+                "/ctl/IPConn"
+            ))
+        );
+        assert_eq!(find_wan_service("<root></root>"), None);
+    }
+
+    #[test]
+    fn header_value_is_case_insensitive() {
+        let response = "HTTP/1.1 200 OK\r\nLOCATION: http://192.168.1.1:5000/desc.xml\r\n\r\n";
+        assert_eq!(
+            header_value(response, "location"),
+            Some("http://192.168.1.1:5000/desc.xml")
+        );
+    }
+
+    #[tokio::test]
+    async fn upnp_source_discovers_gateway() {
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.method(GET).path("/desc.xml");
+            then.status(200).body(description("/ctl/IPConn"));
+        });
+        let soap_mock = server.mock(|when, then| {
+            when.method(POST).path("/ctl/IPConn").header(
+                "SOAPAction",
+                "\"urn:schemas-upnp-org:service:WANIPConnection:1#GetExternalIPAddress\"",
+            );
+            then.status(200).body(SOAP_RESPONSE);
+        });
+
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let ssdp_address = socket.local_addr().unwrap().to_string();
+        tokio::spawn(ssdp_stand_in(socket, server.url("/desc.xml")));
+
+        let source = UpnpSource::new(&ssdp_address, None).unwrap();
+
+        assert_eq!(
+            source.detect(IpFamily::Ipv4).await.unwrap(),
+            IpAddr::V4(Ipv4Addr::new(1, 2, 3, 4))
+        );
+
+        // the control point is cached, no discovery the second time
+        assert_eq!(
+            source.detect(IpFamily::Ipv4).await.unwrap(),
+            IpAddr::V4(Ipv4Addr::new(1, 2, 3, 4))
+        );
+        soap_mock.assert_hits(2);
+    }
+
+    #[tokio::test]
+    async fn upnp_source_with_location() {
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.method(GET).path("/desc.xml");
+            then.status(200)
+                .body(description(&server.url("/ctl/IPConn")));
+        });
+        server.mock(|when, then| {
+            when.method(POST).path("/ctl/IPConn");
+            then.status(200).body(SOAP_RESPONSE);
+        });
+
+        let source =
+            UpnpSource::new(&default_ssdp_address(), Some(&server.url("/desc.xml"))).unwrap();
+
+        assert_eq!(
+            source.detect(IpFamily::Ipv4).await.unwrap(),
+            IpAddr::V4(Ipv4Addr::new(1, 2, 3, 4))
+        );
+    }
+
+    #[tokio::test]
+    async fn upnp_source_ipv6_is_unsupported() {
+        let source = UpnpSource::new(&default_ssdp_address(), None).unwrap();
+        assert!(matches!(
+            source.detect(IpFamily::Ipv6).await,
+            Err(IpSourceError::Unsupported(IpFamily::Ipv6))
+        ));
+    }
+}
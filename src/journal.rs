@@ -0,0 +1,235 @@
+use core::fmt;
+use std::{
+    io::Write,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::cloudflare::models::DNSRecord;
+
+#[derive(Debug)]
+pub enum JournalError {
+    Io(PathBuf, std::io::Error),
+    /// Line number and error of an entry that could not be read
+    Parse(PathBuf, usize, serde_json::Error),
+}
+
+impl fmt::Display for JournalError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JournalError::Io(path, e) => write!(f, "Could not access {}: {}", path.display(), e),
+            JournalError::Parse(path, line, e) => {
+                write!(f, "Could not parse {} line {}: {}", path.display(), line, e)
+            }
+        }
+    }
+}
+
+impl std::error::Error for JournalError {}
+
+/// Records of a zone as they were before a change
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct JournalEntry {
+    pub change_id: String,
+    pub created_on: DateTime<Utc>,
+    pub zone_id: String,
+    /// What the change was about, such as the IP change
+    pub description: String,
+    pub records: Vec<DNSRecord>,
+}
+
+/// Entries of the zones updated concurrently are written one at a time, so
+/// that trimming the file does not lose an entry being appended
+static WRITE: Mutex<()> = Mutex::new(());
+
+/// Append only file of the records snapshots, one JSON entry per line
+#[derive(Debug, Clone, PartialEq)]
+pub struct Journal {
+    path: PathBuf,
+    /// Entries kept when recording, the oldest ones are dropped first
+    max_entries: Option<usize>,
+}
+
+impl Journal {
+    pub fn new(path: &Path) -> Self {
+        Self {
+            path: path.to_path_buf(),
+            max_entries: None,
+        }
+    }
+
+    pub fn with_max_entries(mut self, max_entries: usize) -> Self {
+        self.max_entries = Some(max_entries);
+        self
+    }
+
+    /// Save the records before they change, returns the id of the change
+    pub fn record(
+        &self,
+        zone_id: &str,
+        description: &str,
+        records: &[DNSRecord],
+    ) -> Result<String, JournalError> {
+        let now = Utc::now();
+        let entry = JournalEntry {
+            change_id: format!(
+                "{}-{:04x}",
+                now.format("%Y%m%d%H%M%S"),
+                rand::random::<u16>()
+            ),
+            created_on: now,
+            zone_id: String::from(zone_id),
+            description: String::from(description),
+            records: records.to_vec(),
+        };
+
+        let mut line = serde_json::to_string(&entry)
+            .map_err(|e| JournalError::Parse(self.path.clone(), 0, e))?;
+        line.push('\n');
+
+        let _write = WRITE.lock().unwrap();
+
+        // a single write per entry keeps the lines whole when appending concurrently
+        std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .and_then(|mut file| file.write_all(line.as_bytes()))
+            .map_err(|e| JournalError::Io(self.path.clone(), e))?;
+
+        if let Some(max_entries) = self.max_entries {
+            self.trim(max_entries)?;
+        }
+
+        Ok(entry.change_id)
+    }
+
+    /// Drop the oldest entries beyond `max_entries`, replacing the file at once
+    fn trim(&self, max_entries: usize) -> Result<(), JournalError> {
+        let text = std::fs::read_to_string(&self.path)
+            .map_err(|e| JournalError::Io(self.path.clone(), e))?;
+
+        let lines: Vec<&str> = text.lines().filter(|l| !l.trim().is_empty()).collect();
+        if lines.len() <= max_entries {
+            return Ok(());
+        }
+
+        let mut kept = lines[lines.len() - max_entries..].join("\n");
+        kept.push('\n');
+
+        let mut tmp = self.path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        let tmp = PathBuf::from(tmp);
+
+        std::fs::write(&tmp, kept)
+            .and_then(|_| std::fs::rename(&tmp, &self.path))
+            .map_err(|e| JournalError::Io(self.path.clone(), e))
+    }
+
+    /// Every entry, oldest first, none when the file does not exist yet
+    pub fn entries(&self) -> Result<Vec<JournalEntry>, JournalError> {
+        let text = match std::fs::read_to_string(&self.path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(e) => return Err(JournalError::Io(self.path.clone(), e)),
+        };
+
+        text.lines()
+            .enumerate()
+            .filter(|(_, line)| !line.trim().is_empty())
+            .map(|(i, line)| {
+                serde_json::from_str(line)
+                    .map_err(|e| JournalError::Parse(self.path.clone(), i + 1, e))
+            })
+            .collect()
+    }
+
+    pub fn find(&self, change_id: &str) -> Result<Option<JournalEntry>, JournalError> {
+        Ok(self
+            .entries()?
+            .into_iter()
+            .find(|e| e.change_id == change_id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_journal(name: &str) -> Journal {
+        let path =
+            std::env::temp_dir().join(format!("cfdpip-{}-{}.jsonl", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        Journal::new(&path)
+    }
+
+    fn record(id: &str, content: &str) -> DNSRecord {
+        DNSRecord {
+            id: String::from(id),
+            name: String::from("home.example.com"),
+            content: String::from(content),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn missing_journal_has_no_entries() {
+        let journal = Journal::new(Path::new("/this/file/does/not/exist.jsonl"));
+        assert!(journal.entries().unwrap().is_empty());
+    }
+
+    #[test]
+    fn entries_are_appended() {
+        let journal = temp_journal("journal-append");
+
+        let first = journal
+            .record("1234", "1.2.3.1 -> 1.2.3.2", &[record("a", "1.2.3.1")])
+            .unwrap();
+        let second = journal
+            .record("1234", "1.2.3.2 -> 1.2.3.3", &[record("a", "1.2.3.2")])
+            .unwrap();
+
+        let entries = journal.entries().unwrap();
+        let found = journal.find(&first).unwrap();
+        std::fs::remove_file(&journal.path).unwrap();
+
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[1].change_id, second);
+        assert_eq!(found.unwrap().records[0].content, "1.2.3.1");
+    }
+
+    #[test]
+    fn oldest_entries_are_dropped() {
+        let journal = temp_journal("journal-trim").with_max_entries(2);
+
+        journal.record("1234", "first", &[]).unwrap();
+        journal.record("1234", "second", &[]).unwrap();
+        journal.record("1234", "third", &[]).unwrap();
+
+        let entries = journal.entries().unwrap();
+        std::fs::remove_file(&journal.path).unwrap();
+
+        let descriptions: Vec<&str> = entries.iter().map(|e| e.description.as_str()).collect();
+        assert_eq!(descriptions, vec!["second", "third"]);
+    }
+
+    #[test]
+    fn invalid_line_is_reported() {
+        let journal = temp_journal("journal-invalid");
+        journal.record("1234", "", &[]).unwrap();
+        std::fs::OpenOptions::new()
+            .append(true)
+            .open(&journal.path)
+            .unwrap()
+            .write_all(b"not json\n")
+            .unwrap();
+
+        let result = journal.entries();
+        std::fs::remove_file(&journal.path).unwrap();
+
+        assert!(matches!(result, Err(JournalError::Parse(_, 2, _))));
+    }
+}
@@ -1,7 +1,12 @@
 mod cli;
 mod cloudflare;
+mod config;
+mod ip;
+mod journal;
 mod logger;
 mod mqtt;
+mod retry;
+mod state;
 
 use logger::LOGGER;
 
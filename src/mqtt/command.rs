@@ -0,0 +1,148 @@
+use core::fmt;
+use std::{net::IpAddr, str::FromStr};
+
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use super::UpdateResult;
+
+/// Topic the commands are received on, under the base topic
+pub fn command_topic(base_topic: &str) -> String {
+    format!("{}/cmd", base_topic)
+}
+
+/// Topic the acknowledgements of the commands are published to
+pub fn result_topic(base_topic: &str) -> String {
+    format!("{}/cmd/result", base_topic)
+}
+
+/// Remote control of the monitor, received as text such as `set-ip 1.2.3.4`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Command {
+    /// Check the public IP without waiting for the next check
+    CheckNow,
+    /// Update the managed records not using the current IP, unless paused
+    Reconcile,
+    /// Stop checking the public IP and the records
+    Pause,
+    /// Check again, applying the detected IP
+    Resume,
+    /// Apply an IP instead of the detected one, until the next detected
+    /// change, even while paused
+    SetIp(IpAddr),
+}
+
+#[derive(Debug, PartialEq)]
+pub enum CommandError {
+    Empty,
+    Unknown(String),
+    MissingArgument(&'static str),
+    InvalidIp(String),
+    UnexpectedArgument(String),
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Empty => write!(f, "empty command"),
+            Self::Unknown(name) => write!(f, "unknown command {}", name),
+            Self::MissingArgument(name) => write!(f, "{} requires an IP address", name),
+            Self::InvalidIp(value) => write!(f, "invalid IP address {}", value),
+            Self::UnexpectedArgument(value) => write!(f, "unexpected argument {}", value),
+        }
+    }
+}
+
+impl std::error::Error for CommandError {}
+
+impl FromStr for Command {
+    type Err = CommandError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut words = s.split_whitespace();
+
+        let command = match words.next() {
+            Some("check-now") => Command::CheckNow,
+            Some("reconcile") => Command::Reconcile,
+            Some("pause") => Command::Pause,
+            Some("resume") => Command::Resume,
+            Some("set-ip") => {
+                let value = words
+                    .next()
+                    .ok_or(CommandError::MissingArgument("set-ip"))?;
+                let ip = value
+                    .parse()
+                    .map_err(|_| CommandError::InvalidIp(String::from(value)))?;
+                Command::SetIp(ip)
+            }
+            Some(name) => return Err(CommandError::Unknown(String::from(name))),
+            None => return Err(CommandError::Empty),
+        };
+
+        match words.next() {
+            Some(value) => Err(CommandError::UnexpectedArgument(String::from(value))),
+            None => Ok(command),
+        }
+    }
+}
+
+/// Acknowledgement of a command, `message` tells what was done or why not
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CommandResultMessage {
+    pub command: String,
+    pub result: UpdateResult,
+    pub message: String,
+    pub received_on: DateTime<Utc>,
+}
+
+impl From<CommandResultMessage> for Bytes {
+    fn from(value: CommandResultMessage) -> Bytes {
+        let json = serde_json::to_vec(&value).expect("Failed to serialize CommandResultMessage");
+        Bytes::from(json)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    #[test]
+    fn parse_commands() {
+        assert_eq!("check-now".parse(), Ok(Command::CheckNow));
+        assert_eq!(" reconcile\n".parse(), Ok(Command::Reconcile));
+        assert_eq!("pause".parse(), Ok(Command::Pause));
+        assert_eq!("resume".parse(), Ok(Command::Resume));
+        assert_eq!(
+            "set-ip 1.2.3.4".parse(),
+            Ok(Command::SetIp(IpAddr::V4(Ipv4Addr::new(1, 2, 3, 4))))
+        );
+        assert!(matches!(
+            "set-ip 2001:db8::1".parse(),
+            Ok(Command::SetIp(IpAddr::V6(_)))
+        ));
+    }
+
+    #[test]
+    fn parse_invalid_commands() {
+        assert_eq!("".parse::<Command>(), Err(CommandError::Empty));
+        assert_eq!(
+            "restart".parse::<Command>(),
+            Err(CommandError::Unknown(String::from("restart")))
+        );
+        assert_eq!(
+            "set-ip".parse::<Command>(),
+            Err(CommandError::MissingArgument("set-ip"))
+        );
+        assert_eq!(
+            "set-ip home".parse::<Command>(),
+            Err(CommandError::InvalidIp(String::from("home")))
+        );
+        assert_eq!(
+            "pause now".parse::<Command>(),
+            Err(CommandError::UnexpectedArgument(String::from("now")))
+        );
+    }
+}
@@ -0,0 +1,243 @@
+use serde::{Deserialize, Serialize};
+
+use super::command::command_topic;
+use crate::ip::IpFamily;
+
+/// Device grouping the entities of a cfdpip instance in Home Assistant
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct Device {
+    pub identifiers: Vec<String>,
+    pub name: String,
+    pub model: String,
+    pub sw_version: String,
+}
+
+/// Discovery config of an entity, the fields are the ones of the MQTT
+/// integration of Home Assistant
+///
+/// doc: https://www.home-assistant.io/integrations/mqtt/#mqtt-discovery
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct Discovery {
+    pub name: String,
+    pub unique_id: String,
+    pub object_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub state_topic: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub value_template: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub json_attributes_topic: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub command_topic: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub payload_press: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub device_class: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub unit_of_measurement: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub icon: Option<String>,
+    pub availability_topic: String,
+    pub device: Device,
+}
+
+/// Topics of the state published for the entities, under the base topic
+pub fn ip_topic(base_topic: &str, family: IpFamily) -> String {
+    match family {
+        IpFamily::Ipv4 => format!("{}/state/ipv4", base_topic),
+        IpFamily::Ipv6 => format!("{}/state/ipv6", base_topic),
+    }
+}
+
+pub fn heartbeat_topic(base_topic: &str) -> String {
+    format!("{}/heartbeat", base_topic)
+}
+
+pub fn last_change_topic(base_topic: &str) -> String {
+    format!("{}/state/last_change", base_topic)
+}
+
+pub fn last_update_topic(base_topic: &str) -> String {
+    format!("{}/state/last_update", base_topic)
+}
+
+pub fn record_topic(base_topic: &str, record_id: &str) -> String {
+    format!("{}/state/record/{}", base_topic, record_id)
+}
+
+/// Builds the discovery configs of the entities of a cfdpip instance
+pub struct HomeAssistant {
+    prefix: String,
+    /// MQTT client id, unique per instance
+    id: String,
+    base_topic: String,
+}
+
+impl HomeAssistant {
+    pub fn new(prefix: &str, id: &str, base_topic: &str) -> Self {
+        Self {
+            prefix: String::from(prefix),
+            id: String::from(id),
+            base_topic: String::from(base_topic),
+        }
+    }
+
+    fn entity(&self, object: &str, name: &str) -> Discovery {
+        Discovery {
+            name: String::from(name),
+            unique_id: format!("{}_{}", self.id, object),
+            object_id: format!("{}_{}", self.id, object),
+            state_topic: None,
+            value_template: None,
+            json_attributes_topic: None,
+            command_topic: None,
+            payload_press: None,
+            device_class: None,
+            unit_of_measurement: None,
+            icon: None,
+            availability_topic: format!("{}/status", self.base_topic),
+            device: Device {
+                identifiers: vec![self.id.clone()],
+                name: self.id.clone(),
+                model: String::from("Cloudflare Dynamic Public IP"),
+                sw_version: String::from(env!("CARGO_PKG_VERSION")),
+            },
+        }
+    }
+
+    fn topic(&self, component: &str, object: &str) -> String {
+        format!(
+            "{}/{}/{}/{}/config",
+            self.prefix, component, self.id, object
+        )
+    }
+
+    /// Configs of the entities known on start, with their discovery topic
+    pub fn entities(&self, families: &[IpFamily]) -> Vec<(String, Discovery)> {
+        let mut entities = Vec::new();
+
+        for family in families {
+            let object = match family {
+                IpFamily::Ipv4 => "public_ipv4",
+                IpFamily::Ipv6 => "public_ipv6",
+            };
+            entities.push((
+                self.topic("sensor", object),
+                Discovery {
+                    state_topic: Some(ip_topic(&self.base_topic, *family)),
+                    icon: Some(String::from("mdi:ip-network")),
+                    ..self.entity(object, &format!("Public {}", family))
+                },
+            ));
+        }
+
+        entities.push((
+            self.topic("sensor", "last_change"),
+            Discovery {
+                state_topic: Some(last_change_topic(&self.base_topic)),
+                device_class: Some(String::from("timestamp")),
+                ..self.entity("last_change", "Last IP change")
+            },
+        ));
+
+        let last_update = last_update_topic(&self.base_topic);
+        entities.push((
+            self.topic("sensor", "last_update"),
+            Discovery {
+                state_topic: Some(last_update.clone()),
+                value_template: Some(String::from("{{ value_json.result }}")),
+                json_attributes_topic: Some(last_update),
+                icon: Some(String::from("mdi:cloud-sync")),
+                ..self.entity("last_update", "Last update result")
+            },
+        ));
+
+        entities.push((
+            self.topic("sensor", "cloudflare_budget"),
+            Discovery {
+                state_topic: Some(heartbeat_topic(&self.base_topic)),
+                value_template: Some(String::from("{{ value_json.cloudflare_budget }}")),
+                unit_of_measurement: Some(String::from("requests")),
+                icon: Some(String::from("mdi:speedometer")),
+                ..self.entity("cloudflare_budget", "Cloudflare API budget")
+            },
+        ));
+
+        entities.push((
+            self.topic("button", "check"),
+            Discovery {
+                command_topic: Some(command_topic(&self.base_topic)),
+                payload_press: Some(String::from("check-now")),
+                icon: Some(String::from("mdi:refresh")),
+                ..self.entity("check", "Check public IP")
+            },
+        ));
+
+        entities
+    }
+
+    /// Config of the sensor following a managed record, found at runtime
+    pub fn record(&self, record_id: &str, name: &str) -> (String, Discovery) {
+        let object = format!("record_{}", record_id);
+        let topic = record_topic(&self.base_topic, record_id);
+
+        (
+            self.topic("sensor", &object),
+            Discovery {
+                state_topic: Some(topic.clone()),
+                value_template: Some(String::from("{{ value_json.status }}")),
+                json_attributes_topic: Some(topic),
+                icon: Some(String::from("mdi:dns")),
+                ..self.entity(&object, &format!("Record {}", name))
+            },
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn entities_of_the_instance() {
+        let home_assistant = HomeAssistant::new("homeassistant", "cfdpip", "home/cfdpip");
+
+        let entities = home_assistant.entities(&[IpFamily::Ipv4]);
+        let topics: Vec<&str> = entities.iter().map(|(t, _)| t.as_str()).collect();
+
+        assert_eq!(
+            topics,
+            vec![
+                "homeassistant/sensor/cfdpip/public_ipv4/config",
+                "homeassistant/sensor/cfdpip/last_change/config",
+                "homeassistant/sensor/cfdpip/last_update/config",
+                "homeassistant/sensor/cfdpip/cloudflare_budget/config",
+                "homeassistant/button/cfdpip/check/config",
+            ]
+        );
+        assert_eq!(
+            entities[0].1.state_topic.as_deref(),
+            Some("home/cfdpip/state/ipv4")
+        );
+        assert_eq!(
+            entities[4].1.command_topic.as_deref(),
+            Some("home/cfdpip/cmd")
+        );
+        assert_eq!(entities[0].1.availability_topic, "home/cfdpip/status");
+    }
+
+    #[test]
+    fn record_sensor() {
+        let home_assistant = HomeAssistant::new("homeassistant", "cfdpip", "cfdpip");
+
+        let (topic, discovery) = home_assistant.record("abc", "home.example.com");
+        let json = serde_json::to_value(&discovery).unwrap();
+
+        assert_eq!(topic, "homeassistant/sensor/cfdpip/record_abc/config");
+        assert_eq!(json["unique_id"], "cfdpip_record_abc");
+        assert_eq!(json["state_topic"], "cfdpip/state/record/abc");
+        assert_eq!(json["name"], "Record home.example.com");
+        // unset options are left out
+        assert!(json.get("command_topic").is_none());
+    }
+}
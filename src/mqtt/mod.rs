@@ -1,67 +1,539 @@
-use std::net::Ipv4Addr;
+pub mod command;
+pub mod home_assistant;
+
+use core::fmt;
+use std::{
+    collections::{HashMap, HashSet},
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 
 use bincode::ErrorKind;
 use bytes::Bytes;
-use log::{debug, error, trace};
-use rumqttc::{AsyncClient, ClientError, MqttOptions, QoS};
+use chrono::{DateTime, Utc};
+use log::{debug, error, info, trace, warn};
+use rumqttc::{
+    AsyncClient, ClientError, ConnectReturnCode, ConnectionError, Event, LastWill, MqttOptions,
+    Outgoing, Packet, QoS, Transport,
+};
 use serde::{Deserialize, Serialize};
 use std::convert::TryFrom;
-use tokio::task;
+use tokio::{
+    sync::mpsc,
+    task::{self, JoinHandle},
+};
+
+use self::home_assistant::HomeAssistant;
+use crate::{
+    cloudflare::{drift::DriftField, models::DNSType},
+    config::{MqttConfig, MqttTlsConfig},
+    ip::IpFamily,
+    retry::CircuitState,
+};
+
+/// Wait before connecting again after losing the broker
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// Longest wait for the last messages to be sent when disconnecting
+const DISCONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug)]
+pub enum MqttError {
+    Io(PathBuf, std::io::Error),
+    /// The broker refused the connection, retrying will not change its answer
+    Refused(ConnectReturnCode),
+    Tls(String),
+}
+
+impl fmt::Display for MqttError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MqttError::Io(path, e) => write!(f, "Could not read {}: {}", path.display(), e),
+            MqttError::Refused(ConnectReturnCode::BadUserNamePassword) => write!(
+                f,
+                "MQTT broker rejected the credentials, check mqtt.username and mqtt.password"
+            ),
+            MqttError::Refused(ConnectReturnCode::NotAuthorized) => {
+                write!(f, "MQTT broker refused the connection, not authorized")
+            }
+            MqttError::Refused(code) => {
+                write!(f, "MQTT broker refused the connection: {:?}", code)
+            }
+            MqttError::Tls(e) => write!(f, "MQTT TLS connection failed: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for MqttError {}
+
+impl MqttError {
+    /// Errors that retrying cannot fix, the others are network failures
+    fn from_connection(error: &ConnectionError) -> Option<Self> {
+        match error {
+            ConnectionError::ConnectionRefused(code) => Some(MqttError::Refused(*code)),
+            ConnectionError::Tls(e) => Some(MqttError::Tls(e.to_string())),
+            _ => None,
+        }
+    }
+}
+
+/// Where the messages received on each subscribed topic are forwarded
+type Subscriptions = Arc<Mutex<HashMap<String, mpsc::Sender<Bytes>>>>;
 
 pub struct MqttClient {
     client: AsyncClient,
     base_topic: String,
+    event_loop: Mutex<Option<JoinHandle<()>>>,
+    subscriptions: Subscriptions,
+    /// Set when the Home Assistant discovery is enabled
+    home_assistant: Option<HomeAssistant>,
+    /// Records whose Home Assistant sensor was already published
+    announced_records: Mutex<HashSet<String>>,
 }
 
 impl MqttClient {
-    pub async fn new(host: &str, port: u16, id: &str, base_topic: &str) -> Self {
-        let mut mqttoptions = MqttOptions::new(id, host, port);
+    /// Connect to the broker, failing when it refuses the connection. The
+    /// broker being unreachable is not an error, the connection is retried
+    /// in the background.
+    pub async fn new(config: &MqttConfig) -> Result<Self, MqttError> {
+        let host = config.host.clone().unwrap_or_default();
+
+        let mut mqttoptions = MqttOptions::new(&config.id, host, config.port);
         mqttoptions
             .set_keep_alive(std::time::Duration::from_secs(60))
             .set_clean_session(true);
 
-        let (client, mut eventloop) = AsyncClient::new(mqttoptions.clone(), 10);
-
+        // logged before adding the credentials
         debug!("MQTT options: {:?}", mqttoptions);
 
-        task::spawn(async move {
+        if let Some(ref username) = config.username {
+            mqttoptions.set_credentials(username, config.password.clone().unwrap_or_default());
+        }
+
+        if config.tls.enabled {
+            mqttoptions.set_transport(tls_transport(&config.tls)?);
+        }
+
+        // the broker marks cfdpip offline when the connection is lost
+        let status_topic = format!("{}/status", config.base_topic);
+        mqttoptions.set_last_will(LastWill::new(
+            &status_topic,
+            STATUS_OFFLINE,
+            QoS::AtLeastOnce,
+            true,
+        ));
+
+        let (client, mut eventloop) = AsyncClient::new(mqttoptions, 10);
+
+        loop {
+            match eventloop.poll().await {
+                Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                    info!("Connected to the MQTT broker");
+                    publish_online(&client, &status_topic);
+                    break;
+                }
+                Ok(v) => trace!("MQTT Event = {v:?}"),
+                Err(e) => match MqttError::from_connection(&e) {
+                    Some(e) => return Err(e),
+                    None => {
+                        warn!("Could not connect to the MQTT broker, retrying: {}", e);
+                        break;
+                    }
+                },
+            }
+        }
+
+        let subscriptions: Subscriptions = Arc::new(Mutex::new(HashMap::new()));
+
+        let loop_client = client.clone();
+        let loop_subscriptions = subscriptions.clone();
+        let event_loop = task::spawn(async move {
             trace!("Starting MQTT event loop");
             loop {
                 match eventloop.poll().await {
+                    Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                        info!("Reconnected to the MQTT broker");
+                        // the last will replaced the status while disconnected
+                        publish_online(&loop_client, &status_topic);
+
+                        // the session is clean, the subscriptions are lost
+                        for topic in loop_subscriptions.lock().unwrap().keys() {
+                            if let Err(e) = loop_client.try_subscribe(topic, QoS::AtLeastOnce) {
+                                error!("Failed to subscribe to {}: {}", topic, e);
+                            }
+                        }
+                    }
+                    Ok(Event::Incoming(Packet::Publish(publish))) => {
+                        trace!("MQTT message on {}", publish.topic);
+
+                        let subscriptions = loop_subscriptions.lock().unwrap();
+                        if let Some(sender) = subscriptions.get(&publish.topic) {
+                            if sender.try_send(publish.payload).is_err() {
+                                warn!(
+                                    "Dropping MQTT message on {}, too many pending",
+                                    publish.topic
+                                );
+                            }
+                        }
+                    }
+                    Ok(Event::Outgoing(Outgoing::Disconnect)) => {
+                        trace!("MQTT event loop stopped");
+                        break;
+                    }
                     Ok(v) => {
                         trace!("MQTT Event = {v:?}");
                     }
                     Err(e) => {
+                        if let Some(e) = MqttError::from_connection(&e) {
+                            error!("{}, no longer publishing to MQTT", e);
+                            break;
+                        }
+
                         error!("MQTT Event Error = {e:?}");
+                        tokio::time::sleep(RECONNECT_DELAY).await;
                     }
                 }
             }
         });
 
-        // todo: configure the authentication
+        let home_assistant = match config.home_assistant.enabled {
+            true => Some(HomeAssistant::new(
+                &config.home_assistant.discovery_prefix,
+                &config.id,
+                &config.base_topic,
+            )),
+            false => None,
+        };
 
-        Self {
+        Ok(Self {
             client,
-            base_topic: String::from(base_topic),
+            base_topic: String::from(config.base_topic.as_str()),
+            event_loop: Mutex::new(Some(event_loop)),
+            subscriptions,
+            home_assistant,
+            announced_records: Mutex::new(HashSet::new()),
+        })
+    }
+
+    /// Messages received on `topic`, subscribed to again after reconnecting
+    pub async fn subscribe(&self, topic: &str) -> Result<mpsc::Receiver<Bytes>, ClientError> {
+        debug!("MQTT subscribing to {}", topic);
+
+        let (tx, rx) = mpsc::channel(8);
+        self.subscriptions
+            .lock()
+            .unwrap()
+            .insert(String::from(topic), tx);
+
+        self.client.subscribe(topic, QoS::AtLeastOnce).await?;
+
+        Ok(rx)
+    }
+
+    /// Commands received on `{base_topic}/cmd`
+    pub async fn subscribe_commands(&self) -> Result<mpsc::Receiver<Bytes>, ClientError> {
+        self.subscribe(&command::command_topic(&self.base_topic))
+            .await
+    }
+
+    /// Queued without waiting like the heartbeat, the commands are handled
+    /// by the monitor loop which must not block on the broker
+    pub fn publish_command_result(
+        &self,
+        payload: command::CommandResultMessage,
+    ) -> Result<(), ClientError> {
+        let topic = command::result_topic(&self.base_topic);
+        debug!("MQTT publishing to {}", topic);
+
+        let payload: Bytes = payload.into();
+
+        self.client
+            .try_publish(&topic, QoS::AtLeastOnce, false, payload)
+    }
+
+    /// Publish the Home Assistant discovery configs, when enabled
+    pub fn announce(&self, families: &[IpFamily]) -> Result<(), ClientError> {
+        let home_assistant = match self.home_assistant {
+            Some(ref home_assistant) => home_assistant,
+            None => return Ok(()),
+        };
+
+        for (topic, discovery) in home_assistant.entities(families) {
+            self.publish_discovery(&topic, &discovery)?;
         }
+
+        Ok(())
+    }
+
+    fn publish_discovery(
+        &self,
+        topic: &str,
+        discovery: &home_assistant::Discovery,
+    ) -> Result<(), ClientError> {
+        debug!("MQTT publishing to {}", topic);
+
+        let payload = serde_json::to_vec(discovery).expect("Failed to serialize Discovery");
+
+        self.client
+            .try_publish(topic, QoS::AtLeastOnce, true, payload)
+    }
+
+    pub fn publish_ip(&self, family: IpFamily, ip: IpAddr) -> Result<(), ClientError> {
+        let topic = home_assistant::ip_topic(&self.base_topic, family);
+        debug!("MQTT publishing to {}", topic);
+
+        self.client
+            .try_publish(&topic, QoS::AtLeastOnce, true, ip.to_string())
+    }
+
+    pub fn publish_last_change(&self, changed_on: DateTime<Utc>) -> Result<(), ClientError> {
+        let topic = home_assistant::last_change_topic(&self.base_topic);
+        debug!("MQTT publishing to {}", topic);
+
+        self.client
+            .try_publish(&topic, QoS::AtLeastOnce, true, changed_on.to_rfc3339())
+    }
+
+    pub fn publish_update_result(&self, payload: UpdateResultMessage) -> Result<(), ClientError> {
+        let topic = home_assistant::last_update_topic(&self.base_topic);
+        debug!("MQTT publishing to {}", topic);
+
+        let payload: Bytes = payload.into();
+
+        self.client
+            .try_publish(&topic, QoS::AtLeastOnce, true, payload)
+    }
+
+    /// Status of a managed record, its Home Assistant sensor is published
+    /// the first time the record is seen
+    pub fn publish_record_status(&self, payload: RecordStatusMessage) -> Result<(), ClientError> {
+        if let Some(ref home_assistant) = self.home_assistant {
+            let new = self
+                .announced_records
+                .lock()
+                .unwrap()
+                .insert(payload.record_id.clone());
+
+            if new {
+                let (topic, discovery) = home_assistant.record(&payload.record_id, &payload.name);
+
+                // announced again with the next status when dropped
+                if let Err(e) = self.publish_discovery(&topic, &discovery) {
+                    self.announced_records
+                        .lock()
+                        .unwrap()
+                        .remove(&payload.record_id);
+                    return Err(e);
+                }
+            }
+        }
+
+        let topic = home_assistant::record_topic(&self.base_topic, &payload.record_id);
+        debug!("MQTT publishing to {}", topic);
+
+        let payload: Bytes = payload.into();
+
+        self.client
+            .try_publish(&topic, QoS::AtLeastOnce, true, payload)
+    }
+
+    /// Mark cfdpip offline and close the connection once the pending
+    /// messages are sent, the last will is only for unexpected disconnections
+    pub async fn disconnect(&self) {
+        let topic = format!("{}/status", self.base_topic);
+        debug!("MQTT publishing to {}", topic);
+
+        // queued without waiting, nothing drains a full queue while the
+        // broker is unreachable
+        if let Err(e) = self
+            .client
+            .try_publish(&topic, QoS::AtLeastOnce, true, STATUS_OFFLINE)
+        {
+            error!("Failed to send MQTT message: {}", e);
+        }
+        if let Err(e) = self.client.try_disconnect() {
+            warn!("Failed to disconnect from the MQTT broker: {}", e);
+        }
+
+        let event_loop = self.event_loop.lock().unwrap().take();
+        if let Some(mut event_loop) = event_loop {
+            if tokio::time::timeout(DISCONNECT_TIMEOUT, &mut event_loop)
+                .await
+                .is_err()
+            {
+                warn!("Timed out disconnecting from the MQTT broker");
+                event_loop.abort();
+            }
+        }
+    }
+
+    /// Queued without waiting, a full queue while the broker is unreachable
+    /// drops the heartbeat instead of blocking the caller
+    pub fn publish_heartbeat(&self, payload: HeartbeatMessage) -> Result<(), ClientError> {
+        let topic = home_assistant::heartbeat_topic(&self.base_topic);
+        debug!("MQTT publishing to {}", topic);
+
+        let payload: Bytes = payload.into();
+
+        self.client
+            .try_publish(&topic, QoS::AtMostOnce, false, payload)
     }
 
-    pub async fn publish_ip_change(&self, payload: IpChangeMessage) -> Result<(), ClientError> {
+    pub fn publish_ip_change(&self, payload: IpChangeMessage) -> Result<(), ClientError> {
         let topic = format!("{}/ipchange", self.base_topic);
         debug!("MQTT publishing to {}", topic);
 
         let payload: Bytes = payload.into();
 
         self.client
-            .publish(&topic, QoS::AtLeastOnce, true, payload)
-            .await
+            .try_publish(&topic, QoS::AtLeastOnce, true, payload)
+    }
+
+    pub fn publish_circuit_state(&self, payload: CircuitMessage) -> Result<(), ClientError> {
+        let topic = format!("{}/circuit", self.base_topic);
+        debug!("MQTT publishing to {}", topic);
+
+        let payload: Bytes = payload.into();
+
+        self.client
+            .try_publish(&topic, QoS::AtLeastOnce, true, payload)
+    }
+
+    pub fn publish_drift(&self, payload: DriftMessage) -> Result<(), ClientError> {
+        let topic = format!("{}/drift", self.base_topic);
+        debug!("MQTT publishing to {}", topic);
+
+        let payload: Bytes = payload.into();
+
+        self.client
+            .try_publish(&topic, QoS::AtLeastOnce, false, payload)
+    }
+}
+
+/// Payloads of the retained `{base_topic}/status` topic
+const STATUS_ONLINE: &str = "online";
+const STATUS_OFFLINE: &str = "offline";
+
+/// Queued without waiting, the event loop polling the connection sends it
+fn publish_online(client: &AsyncClient, topic: &str) {
+    debug!("MQTT publishing to {}", topic);
+
+    if let Err(e) = client.try_publish(topic, QoS::AtLeastOnce, true, STATUS_ONLINE) {
+        error!("Failed to send MQTT message: {}", e);
+    }
+}
+
+/// Sign of life of the monitor, sent periodically
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct HeartbeatMessage {
+    /// Seconds since the monitor started
+    pub uptime: u64,
+    pub last_check: Option<DateTime<Utc>>,
+    pub last_error: Option<String>,
+    pub last_error_on: Option<DateTime<Utc>>,
+    /// Cloudflare API requests left in the budget of the token
+    pub cloudflare_budget: Option<u32>,
+}
+
+impl From<HeartbeatMessage> for Bytes {
+    fn from(value: HeartbeatMessage) -> Bytes {
+        let json = serde_json::to_vec(&value).expect("Failed to serialize HeartbeatMessage");
+        Bytes::from(json)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum UpdateResult {
+    Success,
+    Failure,
+}
+
+/// Outcome of the last update of the managed records
+#[derive(Serialize, Deserialize, Debug)]
+pub struct UpdateResultMessage {
+    pub result: UpdateResult,
+    pub error: Option<String>,
+    pub finished_on: DateTime<Utc>,
+}
+
+impl From<UpdateResultMessage> for Bytes {
+    fn from(value: UpdateResultMessage) -> Bytes {
+        let json = serde_json::to_vec(&value).expect("Failed to serialize UpdateResultMessage");
+        Bytes::from(json)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum RecordStatus {
+    /// Uses the current IP with the desired settings
+    InSync,
+    /// Drifted and left as is, in alert mode
+    Drifted,
+    /// Could not be updated
+    Failed,
+}
+
+/// State of a managed record, as last seen
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RecordStatusMessage {
+    pub zone_id: String,
+    pub record_id: String,
+    pub name: String,
+    pub r#type: DNSType,
+    pub content: String,
+    pub status: RecordStatus,
+}
+
+impl From<RecordStatusMessage> for Bytes {
+    fn from(value: RecordStatusMessage) -> Bytes {
+        let json = serde_json::to_vec(&value).expect("Failed to serialize RecordStatusMessage");
+        Bytes::from(json)
+    }
+}
+
+/// A managed record found different from what it should be
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DriftMessage {
+    pub zone_id: String,
+    pub record: String,
+    pub field: DriftField,
+    pub expected: String,
+    pub actual: String,
+    /// Whether the record was updated back, never in alert mode
+    pub fixed: bool,
+}
+
+impl From<DriftMessage> for Bytes {
+    fn from(value: DriftMessage) -> Bytes {
+        let json = serde_json::to_vec(&value).expect("Failed to serialize DriftMessage");
+        Bytes::from(json)
+    }
+}
+
+/// State of the circuit breaker guarding the Cloudflare API
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CircuitMessage {
+    pub state: CircuitState,
+    /// Seconds before the API is called again, when open
+    pub retry_in: Option<u64>,
+}
+
+impl From<CircuitMessage> for Bytes {
+    fn from(value: CircuitMessage) -> Bytes {
+        let json = serde_json::to_vec(&value).expect("Failed to serialize CircuitMessage");
+        Bytes::from(json)
     }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct IpChangeMessage {
-    pub old: Ipv4Addr,
-    pub new: Ipv4Addr,
+    pub family: IpFamily,
+    pub old: IpAddr,
+    pub new: IpAddr,
 }
 
 impl From<&IpChangeMessage> for Vec<u8> {
@@ -90,3 +562,130 @@ impl From<IpChangeMessage> for Bytes {
         Bytes::from(json)
     }
 }
+
+/// TLS using the configured authorities, or the system ones
+fn tls_transport(config: &MqttTlsConfig) -> Result<Transport, MqttError> {
+    let client_auth = match (&config.client_cert_file, &config.client_key_file) {
+        (Some(cert), Some(key)) => Some((read(cert)?, read(key)?)),
+        _ => None,
+    };
+
+    match (&config.ca_file, client_auth) {
+        (None, None) => Ok(Transport::tls_with_default_config()),
+        (Some(ca), client_auth) => Ok(Transport::tls(read(ca)?, client_auth, None)),
+        // the client certificate requires the authorities to be given too
+        (None, Some(_)) => Err(MqttError::Tls(String::from(
+            "a client certificate requires mqtt.tls.ca_file",
+        ))),
+    }
+}
+
+fn read(path: &Path) -> Result<Vec<u8>, MqttError> {
+    std::fs::read(path).map_err(|e| MqttError::Io(path.to_path_buf(), e))
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+        sync::mpsc,
+    };
+
+    use super::*;
+
+    /// Broker answering the first connection with the given CONNACK return
+    /// code, then forwarding what it receives
+    async fn broker(return_code: u8) -> (u16, mpsc::UnboundedReceiver<Vec<u8>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (tx, rx) = mpsc::unbounded_channel();
+
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut buffer = [0; 1024];
+
+            let read = socket.read(&mut buffer).await.unwrap();
+            let _ = tx.send(buffer[..read].to_vec());
+            socket
+                .write_all(&[0x20, 0x02, 0x00, return_code])
+                .await
+                .unwrap();
+
+            while let Ok(read) = socket.read(&mut buffer).await {
+                if read == 0 || tx.send(buffer[..read].to_vec()).is_err() {
+                    break;
+                }
+            }
+        });
+
+        (port, rx)
+    }
+
+    fn config(port: u16) -> MqttConfig {
+        MqttConfig {
+            enabled: true,
+            host: Some(String::from("127.0.0.1")),
+            port,
+            username: Some(String::from("cfdpip")),
+            password: Some(String::from("wrong")),
+            ..Default::default()
+        }
+    }
+
+    fn contains(packet: &[u8], text: &str) -> bool {
+        packet
+            .windows(text.len())
+            .any(|window| window == text.as_bytes())
+    }
+
+    #[tokio::test]
+    async fn rejected_credentials_are_an_error() {
+        let (port, _) = broker(0x04).await;
+
+        let error = MqttClient::new(&config(port)).await.err().unwrap();
+
+        assert!(matches!(
+            error,
+            MqttError::Refused(ConnectReturnCode::BadUserNamePassword)
+        ));
+        assert!(error.to_string().contains("mqtt.username"));
+    }
+
+    #[tokio::test]
+    async fn status_follows_the_connection() {
+        let (port, mut packets) = broker(0x00).await;
+
+        let client = MqttClient::new(&config(port)).await.unwrap();
+
+        let connect = packets.recv().await.unwrap();
+        assert!(contains(&connect, "cfdpip/status"));
+        assert!(contains(&connect, "offline"));
+
+        let online = packets.recv().await.unwrap();
+        assert!(contains(&online, "cfdpip/status"));
+        assert!(contains(&online, "online"));
+
+        client.disconnect().await;
+
+        let mut received = Vec::new();
+        while let Some(packet) = packets.recv().await {
+            received.extend(packet);
+        }
+        assert!(contains(&received, "offline"));
+    }
+
+    #[tokio::test]
+    async fn missing_ca_file_is_an_error() {
+        let mut config = config(1);
+        config.tls = MqttTlsConfig {
+            enabled: true,
+            ca_file: Some(PathBuf::from("/this/file/does/not/exist.pem")),
+            ..Default::default()
+        };
+
+        let error = MqttClient::new(&config).await.err().unwrap();
+
+        assert!(matches!(error, MqttError::Io(_, _)));
+    }
+}
@@ -0,0 +1,266 @@
+use core::fmt;
+use std::{sync::Mutex, time::Duration};
+
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use tokio::time::Instant;
+
+use crate::config::{CircuitBreakerConfig, RetryConfig};
+
+/// When and how long to wait before trying again, with exponential backoff
+/// and full jitter: the delay is picked at random between zero and the
+/// backoff, so that clients failing together do not retry together
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetryPolicy {
+    /// Attempts including the first one, forever when not set
+    pub max_attempts: Option<u32>,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+    pub jitter: bool,
+}
+
+impl RetryPolicy {
+    /// Policy of a single HTTP request, for short network hiccups
+    pub fn requests() -> Self {
+        Self {
+            max_attempts: Some(6),
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(2),
+            jitter: true,
+        }
+    }
+
+    /// Whether another attempt is allowed after `attempts` failed ones
+    pub fn can_retry(&self, attempts: u32) -> bool {
+        match self.max_attempts {
+            Some(max) => attempts < max,
+            None => true,
+        }
+    }
+
+    /// Delay before the next attempt, after `attempts` failed ones
+    pub fn delay(&self, attempts: u32) -> Duration {
+        let exponent = attempts.saturating_sub(1).min(31);
+        let backoff = self
+            .base_delay
+            .saturating_mul(2u32.saturating_pow(exponent))
+            .min(self.max_delay);
+
+        match self.jitter {
+            true => backoff.mul_f64(rand::random::<f64>()),
+            false => backoff,
+        }
+    }
+}
+
+impl From<&RetryConfig> for RetryPolicy {
+    fn from(config: &RetryConfig) -> Self {
+        Self {
+            max_attempts: config.max_attempts,
+            base_delay: Duration::from_secs(config.delay),
+            max_delay: Duration::from_secs(config.max_delay),
+            jitter: config.jitter,
+        }
+    }
+}
+
+/// Statuses worth sending the same request again for
+pub fn is_retryable_status(status: StatusCode) -> bool {
+    status.is_server_error()
+        || status == StatusCode::TOO_MANY_REQUESTS
+        || status == StatusCode::REQUEST_TIMEOUT
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum CircuitState {
+    /// Calls go through
+    Closed,
+    /// Too many failures, calls are refused until the cooldown is over
+    Open,
+    /// Cooldown over, the next call decides whether to close or open again
+    HalfOpen,
+}
+
+impl fmt::Display for CircuitState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CircuitState::Closed => write!(f, "closed"),
+            CircuitState::Open => write!(f, "open"),
+            CircuitState::HalfOpen => write!(f, "half-open"),
+        }
+    }
+}
+
+/// Stops calling a failing service after a number of consecutive failures,
+/// then lets a single call through once the cooldown is over
+pub struct CircuitBreaker {
+    failure_threshold: u32,
+    cooldown: Duration,
+    state: Mutex<BreakerState>,
+}
+
+struct BreakerState {
+    failures: u32,
+    open_until: Option<Instant>,
+}
+
+impl CircuitBreaker {
+    pub fn new(failure_threshold: u32, cooldown: Duration) -> Self {
+        Self {
+            failure_threshold,
+            cooldown,
+            state: Mutex::new(BreakerState {
+                failures: 0,
+                open_until: None,
+            }),
+        }
+    }
+
+    pub fn state(&self) -> CircuitState {
+        let state = self.state.lock().unwrap();
+
+        match state.open_until {
+            None => CircuitState::Closed,
+            Some(until) if until > Instant::now() => CircuitState::Open,
+            Some(_) => CircuitState::HalfOpen,
+        }
+    }
+
+    /// How long calls are still refused, zero when they are allowed
+    pub fn remaining_open(&self) -> Duration {
+        let state = self.state.lock().unwrap();
+
+        match state.open_until {
+            Some(until) => until.saturating_duration_since(Instant::now()),
+            None => Duration::ZERO,
+        }
+    }
+
+    /// Returns the new state when it changed
+    pub fn record_success(&self) -> Option<CircuitState> {
+        let mut state = self.state.lock().unwrap();
+        state.failures = 0;
+
+        state.open_until.take().map(|_| CircuitState::Closed)
+    }
+
+    /// Returns the new state when it changed
+    pub fn record_failure(&self) -> Option<CircuitState> {
+        let mut state = self.state.lock().unwrap();
+        state.failures += 1;
+
+        let half_open = state.open_until.is_some();
+        if half_open || state.failures >= self.failure_threshold {
+            state.open_until = Some(Instant::now() + self.cooldown);
+            // reopening after a failed trial is not a change worth reporting again
+            return match half_open {
+                true => None,
+                false => Some(CircuitState::Open),
+            };
+        }
+
+        None
+    }
+}
+
+impl From<&CircuitBreakerConfig> for CircuitBreaker {
+    fn from(config: &CircuitBreakerConfig) -> Self {
+        CircuitBreaker::new(
+            config.failure_threshold,
+            Duration::from_secs(config.cooldown),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(jitter: bool) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: Some(4),
+            base_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(5),
+            jitter,
+        }
+    }
+
+    #[test]
+    fn exponential_backoff_is_capped() {
+        let policy = policy(false);
+
+        assert_eq!(policy.delay(1), Duration::from_secs(1));
+        assert_eq!(policy.delay(2), Duration::from_secs(2));
+        assert_eq!(policy.delay(3), Duration::from_secs(4));
+        assert_eq!(policy.delay(4), Duration::from_secs(5));
+        assert_eq!(policy.delay(100), Duration::from_secs(5));
+    }
+
+    #[test]
+    fn full_jitter_stays_under_the_backoff() {
+        let jittered = policy(true);
+        let capped = policy(false);
+
+        for attempts in 1..10 {
+            assert!(jittered.delay(attempts) <= capped.delay(attempts));
+        }
+    }
+
+    #[test]
+    fn max_attempts() {
+        assert!(policy(false).can_retry(3));
+        assert!(!policy(false).can_retry(4));
+
+        let forever = RetryPolicy {
+            max_attempts: None,
+            ..policy(false)
+        };
+        assert!(forever.can_retry(u32::MAX));
+    }
+
+    #[test]
+    fn retryable_statuses() {
+        assert!(is_retryable_status(StatusCode::BAD_GATEWAY));
+        assert!(is_retryable_status(StatusCode::SERVICE_UNAVAILABLE));
+        assert!(is_retryable_status(StatusCode::TOO_MANY_REQUESTS));
+        assert!(!is_retryable_status(StatusCode::BAD_REQUEST));
+        assert!(!is_retryable_status(StatusCode::FORBIDDEN));
+        assert!(!is_retryable_status(StatusCode::OK));
+    }
+
+    #[test]
+    fn circuit_opens_after_threshold() {
+        let breaker = CircuitBreaker::new(3, Duration::from_secs(60));
+
+        assert_eq!(breaker.record_failure(), None);
+        assert_eq!(breaker.record_failure(), None);
+        assert_eq!(breaker.record_failure(), Some(CircuitState::Open));
+        assert_eq!(breaker.state(), CircuitState::Open);
+        assert!(breaker.remaining_open() > Duration::from_secs(59));
+    }
+
+    #[test]
+    fn success_resets_the_failures() {
+        let breaker = CircuitBreaker::new(2, Duration::from_secs(60));
+
+        breaker.record_failure();
+        assert_eq!(breaker.record_success(), None);
+        assert_eq!(breaker.record_failure(), None);
+        assert_eq!(breaker.state(), CircuitState::Closed);
+    }
+
+    #[test]
+    fn half_open_after_cooldown() {
+        let breaker = CircuitBreaker::new(1, Duration::ZERO);
+
+        assert_eq!(breaker.record_failure(), Some(CircuitState::Open));
+        assert_eq!(breaker.state(), CircuitState::HalfOpen);
+        assert_eq!(breaker.remaining_open(), Duration::ZERO);
+
+        // a failed trial opens it again silently, a successful one closes it
+        assert_eq!(breaker.record_failure(), None);
+        assert_eq!(breaker.record_success(), Some(CircuitState::Closed));
+        assert_eq!(breaker.state(), CircuitState::Closed);
+    }
+}
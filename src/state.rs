@@ -0,0 +1,286 @@
+use core::fmt;
+use std::{
+    collections::BTreeMap,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use chrono::{DateTime, Utc};
+use log::{debug, error};
+use serde::{Deserialize, Serialize};
+
+use crate::{cloudflare::selector::RecordSelector, ip::IpFamily};
+
+#[derive(Debug)]
+pub enum StateError {
+    Io(PathBuf, std::io::Error),
+    Parse(PathBuf, serde_json::Error),
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StateError::Io(path, e) => write!(f, "Could not access {}: {}", path.display(), e),
+            StateError::Parse(path, e) => write!(f, "Could not parse {}: {}", path.display(), e),
+        }
+    }
+}
+
+impl std::error::Error for StateError {}
+
+/// What was last applied to the DNS records, kept across restarts
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Default)]
+pub struct State {
+    pub applied: Vec<AppliedIp>,
+    /// Ids of the zones configured by name, to look them up only once
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub zone_ids: BTreeMap<String, String>,
+}
+
+/// Last IP the managed records of a zone were updated to
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct AppliedIp {
+    pub zone_id: String,
+    /// Selectors of the managed records at the time, the entry is ignored
+    /// once they change
+    pub records: Vec<RecordSelector>,
+    pub family: IpFamily,
+    pub ip: IpAddr,
+    pub applied_on: DateTime<Utc>,
+}
+
+impl State {
+    /// Empty state when the file does not exist yet
+    pub fn load(path: &Path) -> Result<Self, StateError> {
+        let text = match std::fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(State::default()),
+            Err(e) => return Err(StateError::Io(path.to_path_buf(), e)),
+        };
+
+        serde_json::from_str(&text).map_err(|e| StateError::Parse(path.to_path_buf(), e))
+    }
+
+    /// Write to a temporary file first so that a crash never leaves half a file
+    pub fn save(&self, path: &Path) -> Result<(), StateError> {
+        let json = serde_json::to_string_pretty(self)
+            .map_err(|e| StateError::Parse(path.to_path_buf(), e))?;
+
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        let tmp = PathBuf::from(tmp);
+
+        std::fs::write(&tmp, json)
+            .and_then(|_| std::fs::rename(&tmp, path))
+            .map_err(|e| StateError::Io(path.to_path_buf(), e))
+    }
+
+    pub fn last_applied(
+        &self,
+        zone_id: &str,
+        records: &[RecordSelector],
+        family: IpFamily,
+    ) -> Option<IpAddr> {
+        self.applied
+            .iter()
+            .find(|a| a.zone_id == zone_id && a.records == records && a.family == family)
+            .map(|a| a.ip)
+    }
+
+    pub fn set_applied(&mut self, zone_id: &str, records: &[RecordSelector], ip: IpAddr) {
+        let family = IpFamily::of(&ip);
+        self.applied
+            .retain(|a| !(a.zone_id == zone_id && a.family == family));
+
+        self.applied.push(AppliedIp {
+            zone_id: String::from(zone_id),
+            records: records.to_vec(),
+            family,
+            ip,
+            applied_on: Utc::now(),
+        });
+    }
+}
+
+/// State shared by the updates, saved after every change
+pub struct StateFile {
+    path: PathBuf,
+    state: Mutex<State>,
+    read_only: bool,
+}
+
+impl StateFile {
+    pub fn open(path: &Path) -> Result<Self, StateError> {
+        Ok(Self {
+            path: path.to_path_buf(),
+            state: Mutex::new(State::load(path)?),
+            read_only: false,
+        })
+    }
+
+    /// Keep the changes in memory only, for dry runs
+    pub fn read_only(mut self) -> Self {
+        self.read_only = true;
+        self
+    }
+
+    pub fn last_applied(
+        &self,
+        zone_id: &str,
+        records: &[RecordSelector],
+        family: IpFamily,
+    ) -> Option<IpAddr> {
+        self.state
+            .lock()
+            .unwrap()
+            .last_applied(zone_id, records, family)
+    }
+
+    /// Failing to save is only logged, the records are already updated
+    pub fn record_applied(&self, zone_id: &str, records: &[RecordSelector], ip: IpAddr) {
+        let mut state = self.state.lock().unwrap();
+        state.set_applied(zone_id, records, ip);
+
+        self.save(&state);
+    }
+
+    pub fn zone_id(&self, name: &str) -> Option<String> {
+        self.state
+            .lock()
+            .unwrap()
+            .zone_ids
+            .get(&name.to_lowercase())
+            .cloned()
+    }
+
+    /// Failing to save is only logged, the zone is looked up again next time
+    pub fn record_zone_id(&self, name: &str, zone_id: &str) {
+        let mut state = self.state.lock().unwrap();
+        state
+            .zone_ids
+            .insert(name.to_lowercase(), String::from(zone_id));
+
+        self.save(&state);
+    }
+
+    /// For a zone id Cloudflare no longer knows, the zone is looked up again
+    /// next time
+    pub fn forget_zone_id(&self, name: &str) {
+        let mut state = self.state.lock().unwrap();
+        if state.zone_ids.remove(&name.to_lowercase()).is_some() {
+            self.save(&state);
+        }
+    }
+
+    fn save(&self, state: &State) {
+        if self.read_only {
+            return;
+        }
+
+        match state.save(&self.path) {
+            Ok(_) => debug!("Saved state to {}", self.path.display()),
+            Err(e) => error!("{}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("cfdpip-{}-{}.json", name, std::process::id()))
+    }
+
+    #[test]
+    fn missing_file_is_empty_state() {
+        let state = State::load(Path::new("/this/file/does/not/exist.json")).unwrap();
+        assert_eq!(state, State::default());
+    }
+
+    #[test]
+    fn applied_ip_round_trip() {
+        let path = temp_path("state-round-trip");
+        let selectors = vec![RecordSelector::Name(String::from("home.example.com"))];
+        let ip = IpAddr::V4(Ipv4Addr::new(1, 2, 3, 4));
+
+        let file = StateFile::open(&path).unwrap();
+        file.record_applied("1234", &selectors, ip);
+
+        let loaded = State::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(
+            loaded.last_applied("1234", &selectors, IpFamily::Ipv4),
+            Some(ip)
+        );
+        assert_eq!(
+            loaded.last_applied("1234", &selectors, IpFamily::Ipv6),
+            None
+        );
+        assert_eq!(loaded.last_applied("1234", &[], IpFamily::Ipv4), None);
+    }
+
+    #[test]
+    fn set_applied_replaces_previous_ip() {
+        let mut state = State::default();
+        state.set_applied("1234", &[], IpAddr::V4(Ipv4Addr::new(1, 2, 3, 4)));
+        state.set_applied("1234", &[], IpAddr::V4(Ipv4Addr::new(1, 2, 3, 5)));
+
+        assert_eq!(state.applied.len(), 1);
+        assert_eq!(
+            state.last_applied("1234", &[], IpFamily::Ipv4),
+            Some(IpAddr::V4(Ipv4Addr::new(1, 2, 3, 5)))
+        );
+    }
+
+    #[test]
+    fn invalid_file_is_reported() {
+        let path = temp_path("state-invalid");
+        std::fs::write(&path, "not json").unwrap();
+
+        let result = State::load(&path);
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(matches!(result, Err(StateError::Parse(_, _))));
+    }
+
+    #[test]
+    fn read_only_state_is_not_saved() {
+        let path = temp_path("state-read-only");
+
+        let file = StateFile::open(&path).unwrap().read_only();
+        file.record_applied("1234", &[], IpAddr::V4(Ipv4Addr::new(1, 2, 3, 4)));
+
+        assert!(file.last_applied("1234", &[], IpFamily::Ipv4).is_some());
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn zone_ids_are_saved() {
+        let path = temp_path("state-zone-ids");
+
+        let file = StateFile::open(&path).unwrap();
+        file.record_zone_id("Example.com", "5678");
+
+        let reopened = StateFile::open(&path).unwrap();
+
+        assert_eq!(reopened.zone_id("example.com"), Some(String::from("5678")));
+        assert_eq!(reopened.zone_id("example.org"), None);
+
+        reopened.forget_zone_id("example.com");
+        let reopened = StateFile::open(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(reopened.zone_id("example.com"), None);
+    }
+
+    #[test]
+    fn state_without_zone_ids_loads() {
+        let state: State = serde_json::from_str(r#"{ "applied": [] }"#).unwrap();
+        assert!(state.zone_ids.is_empty());
+    }
+}